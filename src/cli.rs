@@ -0,0 +1,59 @@
+use crate::services::parquet_export::ExportCollection;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+/// Serves the Midgard history API, or runs one of the maintenance commands.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Write a stored collection to a Parquet file and exit
+    ExportParquet {
+        /// Collection to export
+        #[arg(value_enum)]
+        collection: ExportCollection,
+
+        /// Parquet file to write
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Only intervals starting at or after this timestamp
+        #[arg(long)]
+        from: Option<i64>,
+
+        /// Only intervals ending at or before this timestamp
+        #[arg(long)]
+        to: Option<i64>,
+
+        /// Only intervals of this pool, for collections that have one
+        #[arg(long)]
+        pool: Option<String>,
+    },
+
+    /// Create a partner API key, print it and exit
+    CreateApiKey {
+        /// Who the key is for
+        name: String,
+
+        /// Sustained request rate allowed to the key
+        #[arg(long, default_value_t = 600)]
+        requests_per_minute: u32,
+
+        /// Requests the key can make at once after a pause
+        #[arg(long, default_value_t = 100)]
+        burst: u32,
+
+        /// Requests allowed per UTC day; unlimited when omitted
+        #[arg(long)]
+        daily_quota: Option<u64>,
+
+        /// Let the key use the admin routes
+        #[arg(long)]
+        admin: bool,
+    },
+}
@@ -1,15 +1,29 @@
 use crate::models::{
-    depth_price_history::DepthPriceHistory, earnings_history::EarningsHistory,
+    api_key::ApiKey,
+    depth_price_history::DepthPriceHistory,
+    earnings_history::EarningsHistory,
     earnings_history_pools::EarningsHistoryPools,
-    runepool_members_units_history::RunePoolTotalMembersHistory, swaps_history::SwapsHistory,
+    ingestion_checkpoint::{IngestionCheckpoint, Series, SeriesFreshness},
+    ingestion_gap::{GapScanCheckpoint, GapStatus, IngestionGap},
+    quota_usage::QuotaUsage,
+    runepool_members_units_history::RunePoolTotalMembersHistory,
+    scheduler_state::SchedulerState,
+    schema_version::SchemaVersion,
+    swaps_history::SwapsHistory,
 };
+use chrono::Utc;
 use dotenv::dotenv;
+use futures_util::TryStreamExt;
 use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
     error::Error as MongoError,
     options::ClientOptions,
-    options::InsertManyOptions,
-    results::{InsertManyResult, InsertOneResult},
-    Client, Collection,
+    options::{
+        FindOneAndUpdateOptions, FindOptions, IndexOptions, InsertManyOptions, ReplaceOptions,
+        ReturnDocument, UpdateOptions,
+    },
+    results::{InsertManyResult, InsertOneResult, UpdateResult},
+    Client, Collection, IndexModel,
 };
 use std::env;
 use std::sync::Arc;
@@ -22,6 +36,13 @@ pub struct Mongodb {
     pub earnings_history: Collection<EarningsHistory>,
     pub swaps_history: Collection<SwapsHistory>,
     pub runepool_members_history: Collection<RunePoolTotalMembersHistory>,
+    pub ingestion_checkpoints: Collection<IngestionCheckpoint>,
+    pub ingestion_gaps: Collection<IngestionGap>,
+    pub gap_scan_checkpoints: Collection<GapScanCheckpoint>,
+    pub api_keys: Collection<ApiKey>,
+    pub quota_usage: Collection<QuotaUsage>,
+    pub scheduler_state: Collection<SchedulerState>,
+    pub schema_version: Collection<SchemaVersion>,
     pub client: Arc<Client>,
 }
 
@@ -35,6 +56,13 @@ impl Mongodb {
             earnings_history: database.collection("earnings_history"),
             swaps_history: database.collection("swaps_history"),
             runepool_members_history: database.collection("runepool_members_history"),
+            ingestion_checkpoints: database.collection("ingestion_checkpoints"),
+            ingestion_gaps: database.collection("ingestion_gaps"),
+            gap_scan_checkpoints: database.collection("gap_scan_checkpoints"),
+            api_keys: database.collection("api_keys"),
+            quota_usage: database.collection("quota_usage"),
+            scheduler_state: database.collection("scheduler_state"),
+            schema_version: database.collection("schema_version"),
             client,
         }
     }
@@ -50,6 +78,117 @@ impl Mongodb {
         collection.insert_one(document, None).await
     }
 
+    /// Inserts `document`, or overwrites the existing document matching
+    /// `filter`, and returns the `_id` of the stored document. The `_id` of
+    /// `document` itself is ignored so re-ingesting a range keeps existing ids.
+    pub async fn upsert_document<T>(
+        &self,
+        collection: &Collection<T>,
+        filter: Document,
+        document: T,
+    ) -> Result<Option<ObjectId>, MongoError>
+    where
+        T: serde::Serialize,
+    {
+        let mut fields = mongodb::bson::to_document(&document)?;
+        fields.remove("_id");
+
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .projection(doc! { "_id": 1 })
+            .build();
+
+        let stored = collection
+            .clone_with_type::<Document>()
+            .find_one_and_update(filter, doc! { "$set": fields }, options)
+            .await?;
+
+        Ok(stored.and_then(|stored| stored.get_object_id("_id").ok()))
+    }
+
+    /// Collections with a unique index on their natural key, and its keys.
+    pub fn unique_indexes(&self) -> [(Collection<Document>, Document); 10] {
+        [
+            (
+                self.depth_history.clone_with_type::<Document>(),
+                doc! { "pool": 1, "start_time": 1, "end_time": 1 },
+            ),
+            (
+                self.swaps_history.clone_with_type::<Document>(),
+                doc! { "pool": 1, "start_time": 1, "end_time": 1 },
+            ),
+            (
+                self.earnings_history.clone_with_type::<Document>(),
+                doc! { "start_time": 1, "end_time": 1 },
+            ),
+            (
+                self.earnings_history_pools.clone_with_type::<Document>(),
+                doc! { "pool": 1, "start_time": 1, "end_time": 1 },
+            ),
+            (
+                self.runepool_members_history.clone_with_type::<Document>(),
+                doc! { "start_time": 1, "end_time": 1 },
+            ),
+            (
+                self.ingestion_checkpoints.clone_with_type::<Document>(),
+                doc! { "series": 1, "pool": 1, "interval": 1 },
+            ),
+            (
+                self.ingestion_gaps.clone_with_type::<Document>(),
+                doc! { "series": 1, "pool": 1, "interval": 1, "from": 1 },
+            ),
+            (
+                self.gap_scan_checkpoints.clone_with_type::<Document>(),
+                doc! { "series": 1, "pool": 1, "interval": 1 },
+            ),
+            (
+                self.api_keys.clone_with_type::<Document>(),
+                doc! { "key_hash": 1 },
+            ),
+            (
+                self.quota_usage.clone_with_type::<Document>(),
+                doc! { "client": 1, "day": 1 },
+            ),
+        ]
+    }
+
+    /// Creates the unique indexes of `unique_indexes`, which
+    /// `upsert_document` relies on to keep one document per interval, plus
+    /// the secondary indexes the history routes query on. Duplicates left by
+    /// versions without these indexes would make the index build fail; a
+    /// migration removes them, so `run_migrations` has to run first.
+    pub async fn create_indexes(&self) -> Result<(), MongoError> {
+        for (collection, keys) in self.unique_indexes() {
+            let index = IndexModel::builder()
+                .keys(keys)
+                .options(IndexOptions::builder().unique(true).build())
+                .build();
+            collection.create_index(index, None).await?;
+        }
+
+        // Earnings history looks pool rows up by time range across all pools.
+        self.earnings_history_pools
+            .create_index(
+                IndexModel::builder().keys(doc! { "start_time": 1 }).build(),
+                None,
+            )
+            .await?;
+
+        // Quota counters are only needed for the day they count.
+        self.quota_usage
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "expires_at": 1 })
+                    .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+                    .build(),
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn insert_many_documents<T>(
         &self,
         collection: &Collection<T>,
@@ -63,6 +202,323 @@ impl Mongodb {
         collection.insert_many(documents, Some(options)).await
     }
 
+    pub async fn get_checkpoint(
+        &self,
+        series: Series,
+        pool: Option<&str>,
+        interval: &str,
+    ) -> Result<Option<IngestionCheckpoint>, MongoError> {
+        let filter = doc! {
+            "series": series.as_str(),
+            "pool": pool,
+            "interval": interval,
+        };
+
+        self.ingestion_checkpoints.find_one(filter, None).await
+    }
+
+    pub async fn save_checkpoint(
+        &self,
+        series: Series,
+        pool: Option<&str>,
+        interval: &str,
+        last_end_time: i64,
+    ) -> Result<UpdateResult, MongoError> {
+        let filter = doc! {
+            "series": series.as_str(),
+            "pool": pool,
+            "interval": interval,
+        };
+        let update = doc! {
+            "$set": {
+                "last_end_time": last_end_time,
+                "updated_at": Utc::now().timestamp(),
+            }
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        self.ingestion_checkpoints
+            .update_one(filter, update, options)
+            .await
+    }
+
+    /// Every checkpoint, ordered by series, pool and interval.
+    pub async fn list_checkpoints(&self) -> Result<Vec<IngestionCheckpoint>, MongoError> {
+        let options = FindOptions::builder()
+            .sort(doc! { "series": 1, "pool": 1, "interval": 1 })
+            .build();
+        self.ingestion_checkpoints
+            .find(doc! {}, options)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Marks the checkpoint as updated without moving it, for backfills that
+    /// store intervals behind it.
+    pub async fn touch_checkpoint(
+        &self,
+        series: Series,
+        pool: Option<&str>,
+        interval: &str,
+    ) -> Result<UpdateResult, MongoError> {
+        let filter = doc! {
+            "series": series.as_str(),
+            "pool": pool,
+            "interval": interval,
+        };
+        let update = doc! { "$set": { "updated_at": Utc::now().timestamp() } };
+
+        self.ingestion_checkpoints
+            .update_one(filter, update, None)
+            .await
+    }
+
+    /// Newest checkpoint state of `series` across its pools and intervals, or
+    /// `None` before it was first ingested.
+    pub async fn series_freshness(
+        &self,
+        series: Series,
+    ) -> Result<Option<SeriesFreshness>, MongoError> {
+        let pipeline = vec![
+            doc! { "$match": { "series": series.as_str() } },
+            doc! { "$group": {
+                "_id": null,
+                "last_end_time": { "$max": "$last_end_time" },
+                "updated_at": { "$max": "$updated_at" },
+            }},
+        ];
+
+        let mut cursor = self.ingestion_checkpoints.aggregate(pipeline, None).await?;
+        if !cursor.advance().await? {
+            return Ok(None);
+        }
+        let doc = cursor.deserialize_current()?;
+        Ok(Some(SeriesFreshness {
+            last_end_time: doc.get_i64("last_end_time").unwrap_or_default(),
+            updated_at: doc.get_i64("updated_at").unwrap_or_default(),
+        }))
+    }
+
+    pub async fn find_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>, MongoError> {
+        self.api_keys
+            .find_one(doc! { "key_hash": key_hash }, None)
+            .await
+    }
+
+    /// Counts one request of `client` on `day` and returns the requests it
+    /// made that day, this one included.
+    pub async fn increment_quota_usage(
+        &self,
+        client: &str,
+        day: &str,
+        expires_at: DateTime,
+    ) -> Result<u64, MongoError> {
+        let filter = doc! { "client": client, "day": day };
+        let update = doc! {
+            "$inc": { "used": 1_i64 },
+            "$setOnInsert": { "expires_at": expires_at },
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let usage = self
+            .quota_usage
+            .find_one_and_update(filter, update, options)
+            .await?;
+        Ok(usage.map_or(1, |usage| usage.used.max(0) as u64))
+    }
+
+    /// Whether the scheduler was paused from the admin routes, `false` when
+    /// it never was.
+    pub async fn scheduler_paused(&self) -> Result<bool, MongoError> {
+        let state = self
+            .scheduler_state
+            .find_one(doc! { "_id": SchedulerState::ID }, None)
+            .await?;
+        Ok(state.is_some_and(|state| state.paused))
+    }
+
+    pub async fn save_scheduler_paused(&self, paused: bool) -> Result<(), MongoError> {
+        let state = SchedulerState {
+            id: SchedulerState::ID.to_string(),
+            paused,
+            updated_at: Utc::now().timestamp(),
+        };
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.scheduler_state
+            .replace_one(doc! { "_id": SchedulerState::ID }, state, options)
+            .await?;
+        Ok(())
+    }
+
+    /// Untyped handle to the collection holding `series`, for queries that
+    /// work across every series.
+    pub fn series_collection(&self, series: Series) -> Collection<Document> {
+        self.client
+            .database("thorchain")
+            .collection(series.collection_name())
+    }
+
+    /// Deletes the stored intervals of `series` lying within `from`..`to`,
+    /// and returns how many were deleted. `pool` selects the pool of depth
+    /// and swaps history, where `None` is the network-wide swaps series.
+    pub async fn delete_range(
+        &self,
+        series: Series,
+        pool: Option<&str>,
+        from: i64,
+        to: i64,
+    ) -> Result<u64, MongoError> {
+        let mut filter = doc! {
+            "start_time": { "$gte": from },
+            "end_time": { "$lte": to },
+        };
+        if matches!(series, Series::Depth | Series::Swaps) {
+            filter.insert("pool", pool);
+        }
+
+        let deleted = self
+            .series_collection(series)
+            .delete_many(filter.clone(), None)
+            .await?;
+        // Earnings intervals keep their per-pool rows in a second collection.
+        if series == Series::Earnings {
+            self.earnings_history_pools
+                .delete_many(filter, None)
+                .await?;
+        }
+
+        Ok(deleted.deleted_count)
+    }
+
+    /// Seconds of `from`..`to` covered by stored intervals of `series`,
+    /// with `pool` selecting the pool as in `delete_range`.
+    pub async fn stored_seconds(
+        &self,
+        series: Series,
+        pool: Option<&str>,
+        from: i64,
+        to: i64,
+    ) -> Result<i64, MongoError> {
+        let mut filter = doc! {
+            "start_time": { "$gte": from },
+            "end_time": { "$lte": to },
+        };
+        if matches!(series, Series::Depth | Series::Swaps) {
+            filter.insert("pool", pool);
+        }
+
+        let pipeline = vec![
+            doc! { "$match": filter },
+            doc! { "$group": {
+                "_id": null,
+                "seconds": { "$sum": { "$subtract": ["$end_time", "$start_time"] } }
+            }},
+        ];
+        let mut cursor = self
+            .series_collection(series)
+            .aggregate(pipeline, None)
+            .await?;
+
+        Ok(cursor
+            .try_next()
+            .await?
+            .and_then(|total| total.get_i64("seconds").ok())
+            .unwrap_or_default())
+    }
+
+    /// Stored gaps of `series` at `interval`, backfilled ones included.
+    pub async fn list_gaps(
+        &self,
+        series: Series,
+        interval: &str,
+    ) -> Result<Vec<IngestionGap>, MongoError> {
+        self.ingestion_gaps
+            .find(
+                doc! { "series": series.as_str(), "interval": interval },
+                None,
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Drops the gaps of `series` at `interval` an earlier run backfilled,
+    /// so the report keeps unresolved gaps and the last run's backfills.
+    pub async fn clear_backfilled_gaps(
+        &self,
+        series: Series,
+        interval: &str,
+    ) -> Result<(), MongoError> {
+        let status = mongodb::bson::to_bson(&GapStatus::Backfilled)?;
+        self.ingestion_gaps
+            .delete_many(
+                doc! { "series": series.as_str(), "interval": interval, "status": status },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Stores `gap`, replacing the earlier report of the same gap.
+    pub async fn save_gap(&self, gap: &IngestionGap) -> Result<(), MongoError> {
+        let filter = doc! {
+            "series": gap.series.as_str(),
+            "pool": &gap.pool,
+            "interval": &gap.interval,
+            "from": gap.from,
+        };
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.ingestion_gaps
+            .replace_one(filter, gap, options)
+            .await?;
+        Ok(())
+    }
+
+    /// Where gap detection stopped reading `series` at `interval`, per pool.
+    pub async fn gap_scan_checkpoints(
+        &self,
+        series: Series,
+        interval: &str,
+    ) -> Result<Vec<GapScanCheckpoint>, MongoError> {
+        self.gap_scan_checkpoints
+            .find(
+                doc! { "series": series.as_str(), "interval": interval },
+                None,
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    pub async fn save_gap_scan_checkpoint(
+        &self,
+        series: Series,
+        pool: Option<&str>,
+        interval: &str,
+        scanned_until: i64,
+    ) -> Result<UpdateResult, MongoError> {
+        let filter = doc! {
+            "series": series.as_str(),
+            "pool": pool,
+            "interval": interval,
+        };
+        let update = doc! {
+            "$set": {
+                "scanned_until": scanned_until,
+                "updated_at": Utc::now().timestamp(),
+            }
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        self.gap_scan_checkpoints
+            .update_one(filter, update, options)
+            .await
+    }
+
     pub async fn connect_to_mongodb() -> Result<Client, MongoError> {
         dotenv().ok();
 
@@ -0,0 +1,341 @@
+use crate::database::db::Mongodb;
+use crate::models::ingestion_checkpoint::Series;
+use crate::models::{
+    depth_price_history::DepthPriceHistory, earnings_history::EarningsHistory,
+    earnings_history_pools::EarningsHistoryPools,
+    runepool_members_units_history::RunePoolTotalMembersHistory, schema_version::SchemaVersion,
+    swaps_history::SwapsHistory,
+};
+use crate::services::scheduler::INGESTION_INTERVAL;
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use log::{info, warn};
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::error::Error as MongoError;
+use mongodb::options::{AggregateOptions, FindOptions, ReplaceOptions};
+use mongodb::Collection;
+use std::collections::HashSet;
+
+/// Pool the network-wide swaps series was stored under before swaps were
+/// stored per pool.
+const LEGACY_SWAPS_POOL: &str = "BTC.BTC";
+/// Documents updated or deleted per request by a migration.
+const MIGRATION_BATCH: usize = 10_000;
+
+impl Mongodb {
+    /// One-off data migrations, in the order they run. Each runs once per
+    /// database: the number of migrations applied is stored in
+    /// `schema_version`, so later starts skip them.
+    pub const MIGRATIONS: [&'static str; 4] = [
+        "convert legacy amounts to Decimal128",
+        "backfill per-pool earnings",
+        "move legacy network-wide swaps to the null pool",
+        "remove documents duplicating a unique index key",
+    ];
+
+    /// Applies the migrations this database has not been through yet. Run
+    /// before anything reads or writes the series, and before
+    /// `create_indexes`, whose unique indexes some migrations make hold.
+    pub async fn run_migrations(&self) -> Result<(), MongoError> {
+        let applied = self.schema_version().await?;
+        for (index, name) in Self::MIGRATIONS.iter().enumerate().skip(applied as usize) {
+            let version = index as u32 + 1;
+            info!("Running migration {}: {}", version, name);
+            match version {
+                1 => self.convert_legacy_amounts().await?,
+                2 => self.backfill_pool_earnings().await?,
+                3 => self.migrate_legacy_network_swaps().await?,
+                4 => self.remove_duplicate_keys().await?,
+                _ => unreachable!("migration {} has no implementation", version),
+            }
+            self.save_schema_version(version).await?;
+        }
+        Ok(())
+    }
+
+    async fn schema_version(&self) -> Result<u32, MongoError> {
+        let stored = self
+            .schema_version
+            .find_one(doc! { "_id": SchemaVersion::ID }, None)
+            .await?;
+        Ok(stored.map_or(0, |stored| stored.version))
+    }
+
+    async fn save_schema_version(&self, version: u32) -> Result<(), MongoError> {
+        let stored = SchemaVersion {
+            id: SchemaVersion::ID.to_string(),
+            version,
+            migrated_at: Utc::now().timestamp(),
+        };
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.schema_version
+            .replace_one(doc! { "_id": SchemaVersion::ID }, stored, options)
+            .await?;
+        Ok(())
+    }
+
+    /// Converts amounts stored as doubles, integers or strings, as rows
+    /// ingested before amounts were kept as Decimal128 have them, to
+    /// Decimal128, with one update per collection. A value that cannot be
+    /// converted is left as it is and counted in a warning; reading it then
+    /// fails with `ServiceError::InvalidAmount` rather than the start.
+    async fn convert_legacy_amounts(&self) -> Result<(), MongoError> {
+        let database = self.client.database("thorchain");
+        let collections: [(&str, &[&str]); 5] = [
+            ("depth_history", &DepthPriceHistory::AMOUNT_FIELDS),
+            ("earnings_history", &EarningsHistory::AMOUNT_FIELDS),
+            (
+                "earnings_history_pools",
+                &EarningsHistoryPools::AMOUNT_FIELDS,
+            ),
+            ("swaps_history", &SwapsHistory::AMOUNT_FIELDS),
+            (
+                "runepool_members_history",
+                &RunePoolTotalMembersHistory::AMOUNT_FIELDS,
+            ),
+        ];
+
+        for (name, fields) in collections {
+            let collection = database.collection::<Document>(name);
+            let legacy = legacy_amounts_filter(fields);
+
+            let mut set = Document::new();
+            for field in fields {
+                set.insert(*field, to_decimal(field));
+            }
+            let result = collection
+                .update_many(legacy.clone(), vec![doc! { "$set": set }], None)
+                .await?;
+            info!(
+                "Converted legacy amounts in {} documents of {}",
+                result.modified_count, name
+            );
+
+            let unconverted = collection.count_documents(legacy, None).await?;
+            if unconverted > 0 {
+                warn!(
+                    "{} documents of {} keep amounts that are not numbers",
+                    unconverted, name
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets `earnings` on per-pool earnings rows stored before it was kept.
+    /// Midgard reports it as the pool's liquidity fees in RUNE plus its
+    /// rewards, so it is computed the same way.
+    async fn backfill_pool_earnings(&self) -> Result<(), MongoError> {
+        let collection = self.earnings_history_pools.clone_with_type::<Document>();
+        let result = collection
+            .update_many(
+                doc! {
+                    "earnings": { "$exists": false },
+                    "total_liquidity_fees_rune": { "$type": "decimal" },
+                    "rewards": { "$type": "decimal" },
+                },
+                vec![doc! {
+                    "$set": { "earnings": { "$add": ["$total_liquidity_fees_rune", "$rewards"] } }
+                }],
+                None,
+            )
+            .await?;
+        info!(
+            "Backfilled earnings in {} per-pool earnings documents",
+            result.modified_count
+        );
+
+        let missing = collection
+            .count_documents(doc! { "earnings": { "$exists": false } }, None)
+            .await?;
+        if missing > 0 {
+            warn!(
+                "{} per-pool earnings documents have no earnings, since their fees or rewards are not numbers",
+                missing
+            );
+        }
+        Ok(())
+    }
+
+    /// Versions without per-pool swaps stored the network-wide series under
+    /// the pool `BTC.BTC`, which is now a real pool, while the network-wide
+    /// series has a null pool. Those rows are moved to the null pool, or
+    /// removed where the interval is already stored there or stored more
+    /// than once.
+    ///
+    /// Without a `BTC.BTC` swaps checkpoint the pool was never ingested, so
+    /// every `BTC.BTC` row is legacy. Otherwise the newest row of each
+    /// interval is the pool's own and only older duplicates are legacy.
+    async fn migrate_legacy_network_swaps(&self) -> Result<(), MongoError> {
+        let collection = self.swaps_history.clone_with_type::<Document>();
+        let pool_ingested = self
+            .get_checkpoint(Series::Swaps, Some(LEGACY_SWAPS_POOL), INGESTION_INTERVAL)
+            .await?
+            .is_some();
+
+        let network_intervals: HashSet<(i64, i64)> = collection
+            .find(
+                doc! { "pool": Bson::Null },
+                FindOptions::builder()
+                    .projection(doc! { "start_time": 1, "end_time": 1 })
+                    .build(),
+            )
+            .await?
+            .try_collect::<Vec<Document>>()
+            .await?
+            .iter()
+            .filter_map(interval_key)
+            .collect();
+
+        // Every `BTC.BTC` row of each interval, newest first.
+        let pipeline = vec![
+            doc! { "$match": { "pool": LEGACY_SWAPS_POOL } },
+            doc! { "$sort": { "_id": -1 } },
+            doc! { "$group": {
+                "_id": { "start_time": "$start_time", "end_time": "$end_time" },
+                "ids": { "$push": "$_id" },
+            }},
+        ];
+        let options = AggregateOptions::builder().allow_disk_use(true).build();
+        let intervals: Vec<Document> = collection
+            .aggregate(pipeline, options)
+            .await?
+            .try_collect()
+            .await?;
+
+        let mut moved = Vec::new();
+        let mut removed = Vec::new();
+        for interval in &intervals {
+            let Some(key) = interval.get_document("_id").ok().and_then(interval_key) else {
+                continue;
+            };
+            let ids = interval.get_array("ids").map_or(&[][..], |ids| &ids[..]);
+            let legacy = if pool_ingested {
+                ids.get(1..).unwrap_or_default()
+            } else {
+                ids
+            };
+            let Some((newest, older)) = legacy.split_first() else {
+                continue;
+            };
+            if network_intervals.contains(&key) {
+                removed.push(newest.clone());
+            } else {
+                moved.push(newest.clone());
+            }
+            removed.extend(older.iter().cloned());
+        }
+
+        for ids in moved.chunks(MIGRATION_BATCH) {
+            collection
+                .update_many(
+                    doc! { "_id": { "$in": ids } },
+                    doc! { "$set": { "pool": Bson::Null } },
+                    None,
+                )
+                .await?;
+        }
+        for ids in removed.chunks(MIGRATION_BATCH) {
+            collection
+                .delete_many(doc! { "_id": { "$in": ids } }, None)
+                .await?;
+        }
+        info!(
+            "Moved {} legacy network-wide swaps rows to the null pool and removed {} duplicates",
+            moved.len(),
+            removed.len()
+        );
+        Ok(())
+    }
+
+    /// Removes the duplicates versions without unique indexes could store,
+    /// so that `create_indexes` can build them.
+    async fn remove_duplicate_keys(&self) -> Result<(), MongoError> {
+        for (collection, keys) in self.unique_indexes() {
+            let removed = remove_duplicates(&collection, &keys).await?;
+            if removed > 0 {
+                warn!(
+                    "Removed {} duplicate documents from {}",
+                    removed,
+                    collection.name()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Deletes all but the newest document of every group of documents sharing
+/// `keys`, and returns how many were deleted. `ObjectId`s grow with insertion
+/// time, so the document kept is the one written last. Groups only carry
+/// their count and newest `_id`, so the `$group` stays small even on a large
+/// history, and may spill to disk when it is not.
+async fn remove_duplicates(
+    collection: &Collection<Document>,
+    keys: &Document,
+) -> Result<u64, MongoError> {
+    let mut group_id = Document::new();
+    for field in keys.keys() {
+        group_id.insert(field, format!("${}", field));
+    }
+    let pipeline = vec![
+        doc! { "$group": { "_id": group_id, "newest": { "$max": "$_id" }, "count": { "$sum": 1 } } },
+        doc! { "$match": { "count": { "$gt": 1 } } },
+    ];
+    let options = AggregateOptions::builder().allow_disk_use(true).build();
+    let mut groups = collection.aggregate(pipeline, options).await?;
+
+    let mut removed = 0;
+    while let Some(group) = groups.try_next().await? {
+        let (Ok(key), Some(newest)) = (group.get_document("_id"), group.get("newest")) else {
+            continue;
+        };
+        let mut older = key.clone();
+        older.insert("_id", doc! { "$lt": newest });
+        let result = collection.delete_many(older, None).await?;
+        removed += result.deleted_count;
+    }
+    Ok(removed)
+}
+
+/// `(start_time, end_time)` of a stored interval.
+fn interval_key(interval: &Document) -> Option<(i64, i64)> {
+    Some((
+        interval.get_i64("start_time").ok()?,
+        interval.get_i64("end_time").ok()?,
+    ))
+}
+
+/// Documents with any of `fields` stored as something other than Decimal128.
+fn legacy_amounts_filter(fields: &[&str]) -> Document {
+    let legacy: Vec<Document> = fields
+        .iter()
+        .map(|field| doc! { *field: { "$type": ["double", "int", "long", "string"] } })
+        .collect();
+    doc! { "$or": legacy }
+}
+
+/// Expression converting `field` to Decimal128, keeping the value when it
+/// cannot be converted. `$convert` renders a double with 15 significant
+/// digits, which would both round amounts past 1e15 and give whole amounts a
+/// fraction, so doubles are truncated to a 64-bit integer first, which
+/// Midgard's whole base units always fit.
+fn to_decimal(field: &str) -> Document {
+    let path = format!("${}", field);
+    let input = doc! {
+        "$cond": [
+            { "$eq": [{ "$type": &path }, "double"] },
+            { "$convert": { "input": &path, "to": "long", "onError": Bson::Null } },
+            &path
+        ]
+    };
+    doc! {
+        "$convert": {
+            "input": input,
+            "to": "decimal",
+            "onError": &path,
+            "onNull": &path
+        }
+    }
+}
@@ -1,4 +1,5 @@
-use utoipa::OpenApi;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
+use utoipa::{Modify, OpenApi};
 
 #[derive(OpenApi)]
 #[openapi(
@@ -11,25 +12,75 @@ use utoipa::OpenApi;
         crate::routes::depth_history_routes::get_depth_history,
         crate::routes::rune_pool_history_route::get_runepool_history,
         crate::routes::swaps_history_routes::get_swaps_history,
-        crate::routes::earning_history_route::get_earnings_history
+        crate::routes::earning_history_route::get_earnings_history,
+        crate::routes::earning_history_route::get_pool_earnings_history,
+        crate::routes::ingestion_routes::get_ingestion_gaps,
+        crate::routes::export_routes::get_parquet_export,
+        crate::routes::midgard_routes::get_midgard_depth_history,
+        crate::routes::midgard_routes::get_midgard_swaps_history,
+        crate::routes::midgard_routes::get_midgard_earnings_history,
+        crate::routes::midgard_routes::get_midgard_runepool_history,
+        crate::routes::admin_routes::get_series,
+        crate::routes::admin_routes::post_fetch,
+        crate::routes::admin_routes::post_reingest,
+        crate::routes::admin_routes::get_jobs,
+        crate::routes::admin_routes::get_job,
+        crate::routes::admin_routes::get_scheduler,
+        crate::routes::admin_routes::post_scheduler_pause,
+        crate::routes::admin_routes::post_scheduler_resume
     ),
     components(
         schemas(
             crate::routes::queries::HistoryQueryParams,
+            crate::models::amount::Amount,
             crate::models::depth_price_history::DepthPriceHistory,
             crate::models::swaps_history::SwapsHistory,
             crate::models::runepool_members_units_history::RunePoolTotalMembersHistory,
-            crate::models::earnings_history::EarningsHistory
+            crate::models::earnings_history::EarningsHistory,
+            crate::models::ingestion_checkpoint::Series,
+            crate::models::ingestion_checkpoint::IngestionCheckpoint,
+            crate::models::ingestion_gap::GapStatus,
+            crate::models::ingestion_gap::IngestionGap,
+            crate::services::parquet_export::ExportCollection,
+            crate::routes::admin_routes::IngestionRangeRequest,
+            crate::services::ingestion_jobs::IngestionJob,
+            crate::services::ingestion_jobs::JobKind,
+            crate::services::ingestion_jobs::JobStatus,
+            crate::services::scheduler::SchedulerStatus,
+            crate::error::ErrorEnvelope,
+            crate::error::ErrorBody
         )
     ),
     tags(
         (name = "Depth History", description = "Pool depth and price history endpoints"),
         (name = "Rune Pool History", description = "RUNE pool statistics and metrics"),
         (name = "Swaps History", description = "Historical swap data and analytics"),
-        (name = "Earnings History", description = "Historical earnings and rewards data")
+        (name = "Earnings History", description = "Historical earnings and rewards data"),
+        (name = "Ingestion", description = "State of the Midgard ingestion"),
+        (name = "Export", description = "Bulk exports of the stored history"),
+        (name = "Midgard Compatible", description = "Drop-in replacements for Midgard's /v2/history endpoints"),
+        (name = "Admin", description = "Ingestion control; needs an admin API key")
     ),
     servers(
         (url = "http://localhost:8080", description = "Local development server")
-    )
+    ),
+    modifiers(&ApiKeyAddon),
+    security((), ("api_key" = []))
 )]
 pub struct ApiDoc;
+
+/// Documents the optional `X-API-Key` header checked by `enforce_api_key`.
+struct ApiKeyAddon;
+
+impl Modify for ApiKeyAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "X-API-Key",
+                "Partner API key; requests without one get the stricter anonymous limits",
+            ))),
+        );
+    }
+}
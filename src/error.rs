@@ -0,0 +1,274 @@
+use actix_web::error::{JsonPayloadError, PathError, QueryPayloadError};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use log::error;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::num::{ParseFloatError, ParseIntError};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+use crate::middleware::request_id::current_request_id;
+
+/// Errors returned by the HTTP routes. Every variant renders as the same
+/// JSON envelope, so clients can branch on `error.code`.
+#[derive(Debug, Error)]
+pub enum ApiError {
+    /// A query or path parameter has an invalid value.
+    #[error("{message}")]
+    InvalidParameter {
+        param: Cow<'static, str>,
+        message: String,
+    },
+
+    /// The query string could not be parsed at all, e.g. a non-numeric limit.
+    #[error("Invalid query string: {0}")]
+    InvalidQuery(String),
+
+    /// The JSON request body could not be parsed.
+    #[error("Invalid request body: {0}")]
+    InvalidBody(String),
+
+    #[error("{0}")]
+    NotFound(String),
+
+    /// The `X-API-Key` header does not name an enabled key.
+    #[error("{0}")]
+    Unauthorized(String),
+
+    /// The key is valid but not allowed to use the route.
+    #[error("{0}")]
+    Forbidden(String),
+
+    #[error("Rate limit exceeded, retry in {retry_after}s")]
+    RateLimited { retry_after: u64 },
+
+    #[error("Daily request quota exhausted, retry in {retry_after}s")]
+    QuotaExceeded { retry_after: u64 },
+
+    #[error("Database error")]
+    Database(#[from] mongodb::error::Error),
+
+    #[error("Internal server error")]
+    Service(#[from] ServiceError),
+}
+
+impl ApiError {
+    pub fn invalid(param: &'static str, message: impl Into<String>) -> Self {
+        Self::InvalidParameter {
+            param: Cow::Borrowed(param),
+            message: message.into(),
+        }
+    }
+
+    /// Maps a path parameter that failed to parse, e.g. `/api/admin/jobs/abc`,
+    /// to `invalid_parameter` naming the parameter. Every route has at most
+    /// one path parameter, so it is the only one `req` matched.
+    pub fn from_path_error(e: PathError, req: &HttpRequest) -> Self {
+        let mut names = req.match_info().iter().map(|(name, _)| name);
+        let param = match (names.next(), names.next()) {
+            (Some(name), None) => name.to_string(),
+            _ => "path".to_string(),
+        };
+        let message = match e {
+            PathError::Deserialize(e) => format!("Invalid {}: {}", param, e),
+            e => e.to_string(),
+        };
+        Self::InvalidParameter {
+            param: Cow::Owned(param),
+            message,
+        }
+    }
+
+    /// Machine-readable error code of the envelope.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidParameter { .. } => "invalid_parameter",
+            Self::InvalidQuery(_) => "invalid_query",
+            Self::InvalidBody(_) => "invalid_body",
+            Self::NotFound(_) => "not_found",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::Forbidden(_) => "forbidden",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::QuotaExceeded { .. } => "quota_exceeded",
+            Self::Database(_) => "database_error",
+            Self::Service(_) => "internal_error",
+        }
+    }
+
+    /// The offending parameter, when the error is about one.
+    pub fn param(&self) -> Option<&str> {
+        match self {
+            Self::InvalidParameter { param, .. } => Some(param),
+            _ => None,
+        }
+    }
+
+    /// Seconds the client should wait before retrying, sent as `Retry-After`.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Self::RateLimited { retry_after } | Self::QuotaExceeded { retry_after } => {
+                Some(*retry_after)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Body of every error response.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorEnvelope {
+    pub error: ErrorBody,
+    pub status: u16,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorBody {
+    /// invalid_parameter, invalid_query, invalid_body, not_found, unauthorized,
+    /// forbidden, rate_limited, quota_exceeded, database_error or internal_error
+    pub code: &'static str,
+    pub message: String,
+    /// Query or path parameter the error is about
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub param: Option<String>,
+    /// Also sent as the `X-Request-Id` response header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::InvalidParameter { .. } | Self::InvalidQuery(_) | Self::InvalidBody(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::RateLimited { .. } | Self::QuotaExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::Database(_) | Self::Service(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let request_id = current_request_id();
+        // Server errors keep their details in the log, not in the response.
+        match self {
+            Self::Database(e) => error!(
+                "[{}] Database error: {}",
+                request_id_or_dash(&request_id),
+                e
+            ),
+            Self::Service(e) => {
+                error!("[{}] Service error: {}", request_id_or_dash(&request_id), e)
+            }
+            _ => {}
+        }
+
+        let status = self.status_code();
+        let mut res = HttpResponse::build(status);
+        if let Some(retry_after) = self.retry_after() {
+            res.insert_header((RETRY_AFTER, retry_after));
+        }
+        res.json(ErrorEnvelope {
+            error: ErrorBody {
+                code: self.code(),
+                message: self.to_string(),
+                param: self.param().map(str::to_string),
+                request_id,
+            },
+            status: status.as_u16(),
+        })
+    }
+}
+
+fn request_id_or_dash(request_id: &Option<String>) -> &str {
+    request_id.as_deref().unwrap_or("-")
+}
+
+impl From<QueryPayloadError> for ApiError {
+    fn from(e: QueryPayloadError) -> Self {
+        match e {
+            QueryPayloadError::Deserialize(e) => Self::InvalidQuery(e.to_string()),
+            e => Self::InvalidQuery(e.to_string()),
+        }
+    }
+}
+
+impl From<JsonPayloadError> for ApiError {
+    fn from(e: JsonPayloadError) -> Self {
+        Self::InvalidBody(e.to_string())
+    }
+}
+
+/// Errors of the ingestion, gap backfill and export services.
+#[derive(Debug, Error)]
+pub enum ServiceError {
+    /// Every Midgard endpoint failed, or one answered with a non-retryable status.
+    #[error("Midgard request failed: {0}")]
+    Midgard(String),
+
+    #[error("Failed to decode Midgard response: {0}")]
+    Decode(#[from] serde_json::Error),
+
+    /// A Midgard value that should be numeric is not.
+    #[error("Invalid number in Midgard response: {0}")]
+    InvalidNumber(String),
+
+    /// A stored amount that should be a whole number of base units is not.
+    #[error("Stored {field} is not an integer amount: {value}")]
+    InvalidAmount { field: String, value: String },
+
+    #[error("Unknown interval '{0}'")]
+    InvalidInterval(String),
+
+    #[error("{0} history gap has no pool")]
+    MissingPool(&'static str),
+
+    #[error("{series} history fetch failed for pools: {}", pools.join(", "))]
+    PoolsFailed {
+        series: &'static str,
+        pools: Vec<String>,
+    },
+
+    /// Some intervals of a page could not be converted or stored. The page
+    /// is not checkpointed, so the next run fetches it again.
+    #[error("{failed} of {total} {series} intervals could not be stored")]
+    StoreFailed {
+        series: &'static str,
+        failed: usize,
+        total: usize,
+    },
+
+    #[error("Database error: {0}")]
+    Database(#[from] mongodb::error::Error),
+
+    #[error("Failed to serialize document: {0}")]
+    Bson(#[from] mongodb::bson::ser::Error),
+
+    #[error("Export failed: {0}")]
+    Export(String),
+
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+}
+
+impl From<ParseIntError> for ServiceError {
+    fn from(e: ParseIntError) -> Self {
+        Self::InvalidNumber(e.to_string())
+    }
+}
+
+impl From<ParseFloatError> for ServiceError {
+    fn from(e: ParseFloatError) -> Self {
+        Self::InvalidNumber(e.to_string())
+    }
+}
+
+impl From<mongodb::bson::decimal128::ParseError> for ServiceError {
+    fn from(e: mongodb::bson::decimal128::ParseError) -> Self {
+        Self::InvalidNumber(e.to_string())
+    }
+}
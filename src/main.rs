@@ -1,14 +1,18 @@
 #![recursion_limit = "256"]
 use actix_web::{
+    middleware::from_fn,
     web::{self},
     App, HttpResponse, HttpServer, Responder,
 };
+use clap::Parser;
 use mongodb::Client;
 use std::env;
 use tokio::sync::Mutex;
 use utoipa::OpenApi;
+pub mod cli;
 pub mod database;
 pub mod docs;
+pub mod error;
 pub mod middleware;
 pub mod models;
 pub mod routes;
@@ -30,19 +34,88 @@ async fn health_check() -> impl Responder {
     HttpResponse::Ok().body("Server is running!")
 }
 
+async fn midgard_health_check() -> impl Responder {
+    HttpResponse::Ok().json(services::midgard_client::MidgardClient::global().endpoint_statuses())
+}
+
+async fn cache_stats() -> impl Responder {
+    HttpResponse::Ok().json(services::response_cache::ResponseCache::global().stats())
+}
+
+async fn not_found() -> Result<HttpResponse, error::ApiError> {
+    Err(error::ApiError::NotFound("No route matches this path".to_string()))
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "debug");
     env_logger::init();
+    let cli = cli::Cli::parse();
     let mongo_client = database::db::Mongodb::connect_to_mongodb()
         .await
         .expect("Failed to connect to MongoDB");
 
-    // actix_web::rt::spawn(services::scheduler::start_hourly_data_fetch(
-    //     mongo_client.clone(),
-    // ));
-
     let db = database::db::Mongodb::new(mongo_client);
+    // Before anything reads the series: exports and the routes expect every
+    // amount to be a Decimal128.
+    if let Err(e) = db.run_migrations().await {
+        log::error!("Failed to migrate the database: {}", e);
+        return Err(std::io::Error::other(e.to_string()));
+    }
+
+    match cli.command {
+        Some(cli::Command::ExportParquet {
+            collection,
+            output,
+            from,
+            to,
+            pool,
+        }) => {
+            let filter = services::parquet_export::ExportFilter { from, to, pool };
+            let file = std::fs::File::create(&output)?;
+            let rows = services::parquet_export::export_parquet(&db, collection, &filter, file)
+                .await
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            println!("Wrote {} rows to {}", rows, output.display());
+            return Ok(());
+        }
+        Some(cli::Command::CreateApiKey {
+            name,
+            requests_per_minute,
+            burst,
+            daily_quota,
+            admin,
+        }) => {
+            let key = models::api_key::generate_api_key();
+            let api_key = models::api_key::ApiKey {
+                key_hash: models::api_key::hash_api_key(&key),
+                name,
+                requests_per_minute,
+                burst,
+                daily_quota,
+                disabled: false,
+                admin,
+                created_at: chrono::Utc::now().timestamp(),
+            };
+            db.insert_document(&db.api_keys, api_key)
+                .await
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            println!("{}", key);
+            return Ok(());
+        }
+        None => {}
+    }
+
+    // Upserts only keep one document per interval with the unique indexes in
+    // place, so the server does not start without them.
+    if let Err(e) = db.create_indexes().await {
+        log::error!("Failed to create MongoDB indexes: {}", e);
+        return Err(std::io::Error::other(e.to_string()));
+    }
+
+    // Started after the indexes exist, since ingestion upserts on them.
+    actix_web::rt::spawn(services::scheduler::start_hourly_data_fetch(db.clone()));
+    actix_web::rt::spawn(services::rate_limiter::prune_periodically());
     let db_data = web::Data::new(db);
 
     println!(
@@ -53,14 +126,51 @@ async fn main() -> std::io::Result<()> {
 
     HttpServer::new(move || {
         App::new()
+            // Registered first so it runs inside `assign_request_id` and its
+            // 401 and 429 responses carry the request id.
+            .wrap(from_fn(middleware::api_key::enforce_api_key))
+            .wrap(from_fn(middleware::request_id::assign_request_id))
             .app_data(db_data.clone())
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(|e, _req| error::ApiError::from(e).into()),
+            )
+            .app_data(
+                web::PathConfig::default()
+                    .error_handler(|e, req| error::ApiError::from_path_error(e, req).into()),
+            )
+            .app_data(
+                web::JsonConfig::default().error_handler(|e, _req| error::ApiError::from(e).into()),
+            )
             .service(SwaggerUi::new("/docs/{_:.*}").url("/api/openapi.json", api_docs.clone()))
             .route("/health", web::get().to(health_check))
+            .route("/health/midgard", web::get().to(midgard_health_check))
+            .route("/health/cache", web::get().to(cache_stats))
             .service(home_route)
             .service(routes::depth_history_routes::get_depth_history)
             .service(routes::swaps_history_routes::get_swaps_history)
             .service(routes::rune_pool_history_route::get_runepool_history)
             .service(routes::earning_history_route::get_earnings_history)
+            .service(routes::earning_history_route::get_pool_earnings_history)
+            .service(routes::ingestion_routes::get_ingestion_gaps)
+            .service(routes::export_routes::get_parquet_export)
+            .service(routes::midgard_routes::get_midgard_depth_history)
+            .service(routes::midgard_routes::get_midgard_swaps_history)
+            .service(routes::midgard_routes::get_midgard_earnings_history)
+            .service(routes::midgard_routes::get_midgard_runepool_history)
+            .service(
+                web::scope("/api/admin")
+                    .wrap(from_fn(middleware::api_key::require_admin))
+                    .service(routes::admin_routes::get_series)
+                    .service(routes::admin_routes::post_fetch)
+                    .service(routes::admin_routes::post_reingest)
+                    .service(routes::admin_routes::get_jobs)
+                    .service(routes::admin_routes::get_job)
+                    .service(routes::admin_routes::get_scheduler)
+                    .service(routes::admin_routes::post_scheduler_pause)
+                    .service(routes::admin_routes::post_scheduler_resume),
+            )
+            .default_service(web::to(not_found))
     })
     .bind(format!("0.0.0.0:{}", env::var("PORT").unwrap_or("8080".to_string())))?
     .run()
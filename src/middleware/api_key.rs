@@ -0,0 +1,178 @@
+use crate::database::db::Mongodb;
+use crate::error::ApiError;
+use crate::models::api_key::ApiKey;
+use crate::services::api_keys::ApiKeyStore;
+use crate::services::rate_limiter::{RateLimitStatus, RateLimiter, Rejection};
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage};
+use std::net::SocketAddr;
+
+pub const API_KEY_HEADER: HeaderName = HeaderName::from_static("x-api-key");
+
+/// Health checks and the API docs stay open, so load balancers and
+/// first-time visitors never spend a quota.
+fn is_exempt(path: &str) -> bool {
+    path == "/health"
+        || path.starts_with("/health/")
+        || path.starts_with("/docs/")
+        || path == "/api/openapi.json"
+}
+
+fn insert_rate_limit_headers(headers: &mut HeaderMap, status: &RateLimitStatus) {
+    let mut values = vec![
+        ("x-ratelimit-limit", u64::from(status.limit)),
+        ("x-ratelimit-remaining", u64::from(status.remaining)),
+        ("x-ratelimit-reset", status.reset),
+    ];
+    if let Some(quota) = status.quota {
+        values.extend([
+            ("x-ratelimit-quota-limit", quota.limit),
+            ("x-ratelimit-quota-remaining", quota.remaining),
+            ("x-ratelimit-quota-reset", quota.reset),
+        ]);
+    }
+    for (name, value) in values {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+}
+
+/// Rate limiting key of a request without a valid API key: the address the
+/// connection comes from, or the client address reported by the proxy when
+/// `TRUST_PROXY_HEADERS` is set.
+fn client_address(req: &ServiceRequest, trust_proxy_headers: bool) -> String {
+    let address = if trust_proxy_headers {
+        let info = req.connection_info();
+        info.realip_remote_addr().map(|address| {
+            // Proxies and the peer fallback may append a port.
+            address
+                .parse::<SocketAddr>()
+                .map(|address| address.ip().to_string())
+                .unwrap_or_else(|_| address.to_string())
+        })
+    } else {
+        req.peer_addr().map(|address| address.ip().to_string())
+    };
+    format!("ip:{}", address.unwrap_or_default())
+}
+
+fn rejection_error(rejection: Rejection) -> ApiError {
+    match rejection {
+        Rejection::RateLimited { retry_after } => ApiError::RateLimited { retry_after },
+        Rejection::QuotaExceeded { retry_after } => ApiError::QuotaExceeded { retry_after },
+    }
+}
+
+/// Identifies the client by its `X-API-Key`, or by its address when it
+/// sends none, and applies the key's limits or the anonymous tier. Every
+/// limited response carries the client's `X-RateLimit-*` headers; an unknown
+/// or disabled key is rejected with a 401 and an exhausted limit with a 429.
+pub async fn enforce_api_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    if is_exempt(req.path()) {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+
+    let limiter = RateLimiter::global();
+    // Registered on the app like for the handlers, which fail the same way
+    // without it.
+    let Some(db) = req.app_data::<web::Data<Mongodb>>().cloned() else {
+        return Err(ErrorInternalServerError("Mongodb is not configured"));
+    };
+    let address = client_address(&req, limiter.trust_proxy_headers());
+    let key = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .map(|value| value.to_str().unwrap_or_default().to_string());
+
+    let (client, tier) = match key {
+        Some(key) => {
+            let store = ApiKeyStore::global();
+            let cached = store.cached(&key);
+            // Keys that are not known to be valid cost the address one
+            // anonymous request first, so guessing keys is throttled like
+            // anonymous traffic and cannot flood `api_keys` with lookups.
+            let trusted = matches!(&cached, Some(Some(api_key)) if !api_key.disabled);
+            let charged = if trusted {
+                None
+            } else {
+                match limiter.check(&db, &address, limiter.anonymous_tier()).await {
+                    Ok(status) => Some(status),
+                    Err(e) => {
+                        return Ok(req.error_response(ApiError::from(e)).map_into_boxed_body());
+                    }
+                }
+            };
+            if let Some(status) = charged {
+                if let Some(rejection) = status.rejection {
+                    let mut res = req
+                        .error_response(rejection_error(rejection))
+                        .map_into_boxed_body();
+                    insert_rate_limit_headers(res.headers_mut(), &status);
+                    return Ok(res);
+                }
+            }
+
+            let api_key = match cached {
+                Some(api_key) => Ok(api_key),
+                None => store.find(&db, &key).await,
+            };
+            match api_key {
+                Ok(Some(api_key)) if !api_key.disabled => {
+                    let limits = (format!("key:{}", api_key.key_hash), api_key.tier());
+                    // Kept for `require_admin` and handlers that need the caller.
+                    req.extensions_mut().insert(api_key);
+                    limits
+                }
+                Ok(_) => {
+                    let e = ApiError::Unauthorized("Unknown or disabled API key".to_string());
+                    let mut res = req.error_response(e).map_into_boxed_body();
+                    if let Some(status) = charged {
+                        insert_rate_limit_headers(res.headers_mut(), &status);
+                    }
+                    return Ok(res);
+                }
+                Err(e) => {
+                    return Ok(req.error_response(ApiError::from(e)).map_into_boxed_body());
+                }
+            }
+        }
+        None => (address, limiter.anonymous_tier()),
+    };
+
+    let status = match limiter.check(&db, &client, tier).await {
+        Ok(status) => status,
+        Err(e) => return Ok(req.error_response(ApiError::from(e)).map_into_boxed_body()),
+    };
+    let mut res = match status.rejection {
+        Some(rejection) => req
+            .error_response(rejection_error(rejection))
+            .map_into_boxed_body(),
+        None => next.call(req).await?.map_into_boxed_body(),
+    };
+    insert_rate_limit_headers(res.headers_mut(), &status);
+    Ok(res)
+}
+
+/// Only lets requests made with an admin key through. Runs inside
+/// `enforce_api_key`, which has already rejected unknown and disabled keys.
+pub async fn require_admin(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let admin = req
+        .extensions()
+        .get::<ApiKey>()
+        .map(|api_key| api_key.admin);
+    let e = match admin {
+        Some(true) => return Ok(next.call(req).await?.map_into_boxed_body()),
+        Some(false) => ApiError::Forbidden("This API key cannot use the admin routes".to_string()),
+        None => ApiError::Unauthorized("Admin routes need an admin API key".to_string()),
+    };
+    Ok(req.error_response(e).map_into_boxed_body())
+}
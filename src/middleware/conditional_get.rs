@@ -0,0 +1,182 @@
+use crate::database::db::Mongodb;
+use crate::middleware::response_cache::normalized_path_and_query;
+use crate::models::ingestion_checkpoint::{Series, SeriesFreshness};
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{
+    ETag, EntityTag, Header, HttpDate, IfModifiedSince, IfNoneMatch, LastModified,
+    TryIntoHeaderPair, ACCEPT,
+};
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use log::warn;
+use std::collections::hash_map::DefaultHasher;
+use std::future::{ready, Ready};
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Adds `ETag` and `Last-Modified` to history responses and answers
+/// `If-None-Match` / `If-Modified-Since` with a 304 before the handler runs
+/// when the series' ingestion checkpoint shows nothing new was stored.
+/// Attached per route with the series the route reads:
+///
+/// `#[get("/api/history/swaps", wrap = "ConditionalGet::new(Series::Swaps)")]`
+#[derive(Clone, Copy)]
+pub struct ConditionalGet {
+    series: Series,
+}
+
+impl ConditionalGet {
+    pub fn new(series: Series) -> Self {
+        Self { series }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ConditionalGet
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = ConditionalGetMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ConditionalGetMiddleware {
+            service: Rc::new(service),
+            series: self.series,
+        }))
+    }
+}
+
+pub struct ConditionalGetMiddleware<S> {
+    service: Rc<S>,
+    series: Series,
+}
+
+/// Weak tag over the request and the series' checkpoint: it changes when
+/// the query, the negotiated representation or the stored data does. The
+/// query's `from` and `to` are resolved as for the response cache, so a
+/// `/v2` summary without `to` keeps its tag until a new ingestion interval
+/// starts rather than changing with the clock.
+fn entity_tag(req: &ServiceRequest, freshness: SeriesFreshness) -> Option<EntityTag> {
+    let mut hasher = DefaultHasher::new();
+    normalized_path_and_query(req)?.hash(&mut hasher);
+    req.headers()
+        .get(ACCEPT)
+        .map(|value| value.as_bytes())
+        .hash(&mut hasher);
+    freshness.last_end_time.hash(&mut hasher);
+    freshness.updated_at.hash(&mut hasher);
+    Some(EntityTag::new_weak(format!("{:016x}", hasher.finish())))
+}
+
+/// `If-None-Match` takes precedence over `If-Modified-Since`, as in RFC 9110.
+fn not_modified(req: &ServiceRequest, etag: &EntityTag, last_modified: SystemTime) -> bool {
+    if req.headers().contains_key(IfNoneMatch::name()) {
+        return match IfNoneMatch::parse(req.request()) {
+            Ok(IfNoneMatch::Any) => true,
+            Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+            Err(_) => false,
+        };
+    }
+    match IfModifiedSince::parse(req.request()) {
+        Ok(IfModifiedSince(since)) => last_modified <= SystemTime::from(since),
+        Err(_) => false,
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for ConditionalGetMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let series = self.series;
+
+        Box::pin(async move {
+            let db = req.app_data::<web::Data<Mongodb>>().cloned();
+            let freshness = match db {
+                Some(db) => match db.series_freshness(series).await {
+                    Ok(freshness) => freshness,
+                    Err(e) => {
+                        // Conditional requests are an optimisation; serve
+                        // the full response rather than fail.
+                        warn!("Failed to read {} checkpoints: {}", series.as_str(), e);
+                        None
+                    }
+                },
+                None => None,
+            };
+
+            let validators = freshness.and_then(|freshness| {
+                let etag = entity_tag(&req, freshness)?;
+                let last_modified =
+                    UNIX_EPOCH + Duration::from_secs(freshness.updated_at.max(0) as u64);
+                Some((etag, last_modified))
+            });
+            let Some((etag, last_modified)) = validators else {
+                return Ok(service.call(req).await?.map_into_boxed_body());
+            };
+            let headers = [
+                ETag(etag.clone()).try_into_pair(),
+                LastModified(HttpDate::from(last_modified)).try_into_pair(),
+            ];
+
+            if not_modified(&req, &etag, last_modified) {
+                let mut res = HttpResponse::NotModified();
+                for (name, value) in headers.into_iter().flatten() {
+                    res.insert_header((name, value));
+                }
+                return Ok(req.into_response(res.finish()));
+            }
+
+            let mut res = service.call(req).await?;
+            if res.status() == StatusCode::OK {
+                for (name, value) in headers.into_iter().flatten() {
+                    res.headers_mut().insert(name, value);
+                }
+            }
+            Ok(res.map_into_boxed_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::queries::summary_end;
+    use actix_web::test::TestRequest;
+    use chrono::Utc;
+
+    fn tag(uri: &str) -> EntityTag {
+        let freshness = SeriesFreshness {
+            last_end_time: 1_706_745_600,
+            updated_at: 1_706_745_700,
+        };
+        entity_tag(&TestRequest::get().uri(uri).to_srv_request(), freshness).unwrap()
+    }
+
+    #[test]
+    fn summary_tag_uses_the_resolved_end() {
+        let end = summary_end(Utc::now().timestamp());
+        let open = tag("/v2/history/swaps");
+        let closed = tag(&format!("/v2/history/swaps?to={}", end));
+        // Unless the hour turned between the two requests.
+        if summary_end(Utc::now().timestamp()) == end {
+            assert_eq!(open, closed);
+        }
+        assert_ne!(open, tag("/v2/history/swaps?to=1"));
+    }
+}
@@ -1,26 +1,83 @@
-use crate::routes::queries::{validate_count, validate_interval, HistoryQueryParams};
-use actix_web::{dev::ServiceRequest, error::ErrorBadRequest, Error};
+use crate::routes::queries::HistoryQueryParams;
+use crate::utils::rollup::RollupField;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::Error;
+use futures_util::future::LocalBoxFuture;
 use serde_urlencoded;
+use std::future::{ready, Ready};
 
-#[allow(dead_code)]
-pub async fn validate_query_params(req: ServiceRequest) -> Result<ServiceRequest, Error> {
-    let query_str = req.query_string();
-
-    match serde_urlencoded::from_str::<HistoryQueryParams>(query_str) {
-        Ok(query) => {
-            if let Some(ref interval) = query.interval {
-                if !validate_interval(interval) {
-                    return Err(ErrorBadRequest("Invalid interval. Must be one of: 5min, hour, day, week, month, quarter, year"));
-                }
-            }
-
-            if let Some(count) = query.count {
-                if !validate_count(count) {
-                    return Err(ErrorBadRequest("Count must be between 1 and 400"));
-                }
-            }
-            Ok(req)
+/// Validates the query of a history route before its handler runs, with the
+/// same rules on every route (see `HistoryQueryParams::validate`). Attached
+/// per route with the route's rollup fields, which are what it can sort by:
+///
+/// `#[get("/api/history/swaps", wrap = "ValidateHistoryQuery::new(&SWAPS_ROLLUP)")]`
+///
+/// The last `wrap` of a route runs first, so it is listed last: an invalid
+/// query then gets its 400 before a cached response or a 304 is served.
+#[derive(Clone, Copy)]
+pub struct ValidateHistoryQuery {
+    sort_fields: &'static [RollupField],
+}
+
+impl ValidateHistoryQuery {
+    pub fn new(sort_fields: &'static [RollupField]) -> Self {
+        Self { sort_fields }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ValidateHistoryQuery
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ValidateHistoryQueryMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ValidateHistoryQueryMiddleware {
+            service,
+            sort_fields: self.sort_fields,
+        }))
+    }
+}
+
+pub struct ValidateHistoryQueryMiddleware<S> {
+    service: S,
+    sort_fields: &'static [RollupField],
+}
+
+impl<S, B> Service<ServiceRequest> for ValidateHistoryQueryMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // A query that does not parse is left to the handler's extractor,
+        // which reports it as `invalid_query`.
+        let invalid = serde_urlencoded::from_str::<HistoryQueryParams>(req.query_string())
+            .ok()
+            .and_then(|query| {
+                query
+                    .validate(self.sort_fields.iter().map(|field| field.output))
+                    .err()
+            });
+
+        if let Some(e) = invalid {
+            let res = req.error_response(e).map_into_right_body();
+            return Box::pin(async move { Ok(res) });
         }
-        Err(_) => Ok(req),
+
+        let fut = self.service.call(req);
+        Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
     }
 }
@@ -1 +1,6 @@
+pub mod api_key;
+pub mod conditional_get;
+#[allow(clippy::module_inception)]
 pub mod middleware;
+pub mod request_id;
+pub mod response_cache;
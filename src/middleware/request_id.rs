@@ -0,0 +1,49 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::Error;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Id of the request being handled, for error responses and logs. `None`
+/// outside of `assign_request_id`.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// A caller's `X-Request-Id` is reused when it is a short printable token,
+/// so ids can be traced across services.
+fn incoming_request_id(req: &ServiceRequest) -> Option<String> {
+    let id = req.headers().get(REQUEST_ID_HEADER)?.to_str().ok()?;
+    let valid = !id.is_empty()
+        && id.len() <= 128
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c));
+    valid.then(|| id.to_string())
+}
+
+/// Gives every request an id, available to the handler through
+/// `current_request_id` and echoed in the `X-Request-Id` response header.
+pub async fn assign_request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let id = incoming_request_id(&req).unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    let header = HeaderValue::from_str(&id).ok();
+
+    // The inner services are called inside the scope too, since middleware
+    // such as `ValidateHistoryQuery` can respond from `call` itself.
+    let mut res = REQUEST_ID
+        .scope(id, async move { next.call(req).await })
+        .await?;
+    if let Some(header) = header {
+        res.headers_mut().insert(REQUEST_ID_HEADER, header);
+    }
+    Ok(res)
+}
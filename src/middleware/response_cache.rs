@@ -0,0 +1,174 @@
+use crate::models::ingestion_checkpoint::Series;
+use crate::routes::queries::{HistoryQueryParams, MidgardQueryParams};
+use crate::services::response_cache::{CachedResponse, ResponseCache};
+use crate::utils::export::ExportFormat;
+use actix_web::body::{self, BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::{error, Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use serde_urlencoded;
+use std::future::{ready, Ready};
+
+pub const CACHE_STATUS_HEADER: HeaderName = HeaderName::from_static("x-cache");
+
+/// Serves repeated history queries from `ResponseCache::global()`. Attached
+/// per route with the series the route reads, so ingestion of that series
+/// evicts its entries:
+///
+/// `#[get("/api/history/swaps", wrap = "CacheResponse::new(Series::Swaps)")]`
+///
+/// Only successful JSON responses are cached; CSV and NDJSON exports are
+/// streamed and always bypass the cache.
+#[derive(Clone, Copy)]
+pub struct CacheResponse {
+    series: Series,
+}
+
+impl CacheResponse {
+    pub fn new(series: Series) -> Self {
+        Self { series }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for CacheResponse
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = CacheResponseMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CacheResponseMiddleware {
+            service,
+            series: self.series,
+        }))
+    }
+}
+
+pub struct CacheResponseMiddleware<S> {
+    service: S,
+    series: Series,
+}
+
+/// Path plus the query parameters sorted by name, so the same query written
+/// in a different order maps to the same key. `from` and `to` are replaced by
+/// the bounds the handler reads, so relative times such as `-7d` and a
+/// missing `from`, which move with the clock, never reuse an older range.
+/// A `/v2` summary without `to` ends at `summary_end`, so its key only
+/// changes when a new ingestion interval starts. `None` for an unparsable
+/// query.
+pub fn normalized_path_and_query(req: &ServiceRequest) -> Option<String> {
+    let mut params: Vec<(String, String)> = serde_urlencoded::from_str(req.query_string()).ok()?;
+    params.retain(|(name, value)| !value.is_empty() && name != "from" && name != "to");
+    let (from, to) = resolved_range(req)?;
+    params.extend(from.map(|from| ("from".to_string(), from.to_string())));
+    params.extend(to.map(|to| ("to".to_string(), to.to_string())));
+    params.sort();
+    let query = serde_urlencoded::to_string(&params).ok()?;
+    Some(format!("{}?{}", req.path(), query))
+}
+
+/// `from` and `to` as the route's handler resolves them, including the range
+/// of a `/v2` summary.
+fn resolved_range(req: &ServiceRequest) -> Option<(Option<i64>, Option<i64>)> {
+    let query = if req.path().starts_with("/v2/") {
+        serde_urlencoded::from_str::<MidgardQueryParams>(req.query_string())
+            .ok()?
+            .to_history_query()
+            .ok()?
+    } else {
+        serde_urlencoded::from_str::<HistoryQueryParams>(req.query_string()).ok()?
+    };
+    Some(query.time_range(query.interval().ok()?))
+}
+
+/// `None` when the request must not be cached: an unparsable query or an
+/// export format.
+fn cache_key(req: &ServiceRequest) -> Option<String> {
+    let format = req
+        .query_string()
+        .split('&')
+        .find_map(|param| param.strip_prefix("format="));
+    if !matches!(ExportFormat::negotiate(req.request(), format), Ok(None)) {
+        return None;
+    }
+    normalized_path_and_query(req)
+}
+
+fn with_cache_status(mut res: HttpResponse, status: &'static str) -> HttpResponse {
+    res.headers_mut()
+        .insert(CACHE_STATUS_HEADER, HeaderValue::from_static(status));
+    res
+}
+
+impl<S, B> Service<ServiceRequest> for CacheResponseMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let cache = ResponseCache::global();
+        let key = cache_key(&req).filter(|_| cache.enabled());
+        let Some(key) = key else {
+            let fut = self.service.call(req);
+            return Box::pin(async move { Ok(fut.await?.map_into_boxed_body()) });
+        };
+
+        let series = self.series;
+        if let Some(hit) = cache.get(series, &key) {
+            let res = HttpResponse::Ok()
+                .content_type(hit.content_type)
+                .body(hit.body);
+            let res = req.into_response(with_cache_status(res, "HIT"));
+            return Box::pin(async move { Ok(res) });
+        }
+
+        // Read before the handler runs, so a response computed from data
+        // older than a concurrent invalidation is not stored.
+        let generation = cache.generation(series);
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            let content_type = res
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| value.starts_with("application/json"))
+                .map(str::to_string);
+            let Some(content_type) = content_type.filter(|_| res.status() == StatusCode::OK) else {
+                return Ok(res.map_into_boxed_body());
+            };
+
+            let (req, res) = res.into_parts();
+            let (res, body) = res.into_parts();
+            let body = body::to_bytes(body)
+                .await
+                .map_err(|e| error::ErrorInternalServerError(e.into().to_string()))?;
+
+            cache.insert(
+                series,
+                key,
+                generation,
+                CachedResponse {
+                    body: body.clone(),
+                    content_type,
+                },
+            );
+
+            let res = res.set_body(body).map_into_boxed_body();
+            Ok(ServiceResponse::new(req, with_cache_status(res, "MISS")))
+        })
+    }
+}
@@ -0,0 +1,58 @@
+use mongodb::bson::Decimal128;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use utoipa::openapi::{ObjectBuilder, RefOr, Schema, SchemaType};
+use utoipa::ToSchema;
+
+/// A Midgard amount such as a depth, volume, fee or unit count. Midgard sends
+/// these as integer strings in 1e8 base units, which lose precision in an
+/// `f64` past 2^53, so they are stored as BSON Decimal128 instead.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Amount(Decimal128);
+
+impl Amount {
+    pub fn zero() -> Self {
+        Self::from_str("0").unwrap()
+    }
+}
+
+impl Default for Amount {
+    fn default() -> Self {
+        Self::zero()
+    }
+}
+
+impl FromStr for Amount {
+    type Err = mongodb::bson::decimal128::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.trim().parse::<Decimal128>().map(Self)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Debug for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Amount({})", self.0)
+    }
+}
+
+impl<'s> ToSchema<'s> for Amount {
+    fn schema() -> (&'s str, RefOr<Schema>) {
+        (
+            "Amount",
+            ObjectBuilder::new()
+                .schema_type(SchemaType::String)
+                .description(Some("Exact decimal amount in Midgard base units (1e8)"))
+                .example(Some(serde_json::json!("123456789012345678")))
+                .into(),
+        )
+    }
+}
@@ -0,0 +1,56 @@
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Prefix of generated keys, so they are recognisable in configs and logs.
+const KEY_PREFIX: &str = "mk_";
+
+/// A partner API key, sent in the `X-API-Key` header. Only the SHA-256 of
+/// the key is stored.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    pub key_hash: String,
+    pub name: String,
+    /// Sustained rate of the key's token bucket.
+    pub requests_per_minute: u32,
+    /// Bucket size: how many requests can be made at once after a pause.
+    pub burst: u32,
+    /// Requests allowed per UTC day, unlimited when `None`.
+    pub daily_quota: Option<u64>,
+    #[serde(default)]
+    pub disabled: bool,
+    /// Grants access to the `/api/admin` routes.
+    #[serde(default)]
+    pub admin: bool,
+    pub created_at: i64,
+}
+
+/// Limits applied to a client, from its API key or the anonymous tier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitTier {
+    pub requests_per_minute: u32,
+    pub burst: u32,
+    pub daily_quota: Option<u64>,
+}
+
+impl ApiKey {
+    pub fn tier(&self) -> RateLimitTier {
+        RateLimitTier {
+            requests_per_minute: self.requests_per_minute,
+            burst: self.burst,
+            daily_quota: self.daily_quota,
+        }
+    }
+}
+
+/// Hex SHA-256 of `key`, as stored in `key_hash`.
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// A new random key. It is only shown once; the service keeps its hash.
+pub fn generate_api_key() -> String {
+    let mut bytes = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("{}{}", KEY_PREFIX, hex::encode(bytes))
+}
@@ -1,45 +1,76 @@
+use crate::error::ServiceError;
+use crate::models::amount::Amount;
 use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
 use crate::services::fetch_depth_price_history::Interval;
 
-#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
 pub struct DepthPriceHistory {
     pub _id: ObjectId,
     pub pool: String,
     pub start_time: i64,
     pub end_time: i64,
-    pub asset_depth: f64,
-    pub rune_depth: f64,
+    pub asset_depth: Amount,
+    pub rune_depth: Amount,
     pub asset_price: f64,
     pub asset_price_usd: f64,
-    pub liquidity_units: f64,
+    pub liquidity_units: Amount,
     pub members_count: f64,
-    pub synth_units: f64,
-    pub synth_supply: f64,
-    pub units: f64,
+    pub synth_units: Amount,
+    pub synth_supply: Amount,
+    pub units: Amount,
     pub luvi: f64,
 }
 
-impl TryFrom<Interval> for DepthPriceHistory {
-    type Error = Box<dyn std::error::Error>;
+impl DepthPriceHistory {
+    /// Stored fields besides `_id` and `pool`, in declaration order. Depth
+    /// exports use them as columns in this order.
+    pub const FIELDS: [&'static str; 12] = [
+        "start_time",
+        "end_time",
+        "asset_depth",
+        "rune_depth",
+        "asset_price",
+        "asset_price_usd",
+        "liquidity_units",
+        "members_count",
+        "synth_units",
+        "synth_supply",
+        "units",
+        "luvi",
+    ];
 
-    fn try_from(interval: Interval) -> Result<Self, Self::Error> {
+    /// The depth and unit fields, stored as `Amount`. Prices stay `f64`.
+    pub const AMOUNT_FIELDS: [&'static str; 6] = [
+        "asset_depth",
+        "rune_depth",
+        "liquidity_units",
+        "synth_units",
+        "synth_supply",
+        "units",
+    ];
+}
+
+impl TryFrom<(&str, Interval)> for DepthPriceHistory {
+    type Error = ServiceError;
+
+    fn try_from((pool, interval): (&str, Interval)) -> Result<Self, Self::Error> {
         Ok(Self {
             _id: ObjectId::new(),
-            pool: "BTC.BTC".to_string(),
+            pool: pool.to_string(),
             start_time: interval.start_time.trim().parse::<i64>()?,
             end_time: interval.end_time.trim().parse::<i64>()?,
-            asset_depth: interval.asset_depth.trim().parse::<f64>()?,
-            rune_depth: interval.rune_depth.trim().parse::<f64>()?,
+            asset_depth: interval.asset_depth.trim().parse::<Amount>()?,
+            rune_depth: interval.rune_depth.trim().parse::<Amount>()?,
             asset_price: interval.asset_price.trim().parse::<f64>()?,
             asset_price_usd: interval.asset_price_usd.trim().parse::<f64>()?,
-            liquidity_units: interval.liquidity_units.trim().parse::<f64>()?,
+            liquidity_units: interval.liquidity_units.trim().parse::<Amount>()?,
             members_count: interval.members_count.trim().parse::<f64>()?,
-            synth_units: interval.synth_units.trim().parse::<f64>()?,
-            synth_supply: interval.synth_supply.trim().parse::<f64>()?,
-            units: interval.units.trim().parse::<f64>()?,
+            synth_units: interval.synth_units.trim().parse::<Amount>()?,
+            synth_supply: interval.synth_supply.trim().parse::<Amount>()?,
+            units: interval.units.trim().parse::<Amount>()?,
             luvi: interval.luvi.trim().parse::<f64>()?,
         })
     }
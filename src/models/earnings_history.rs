@@ -1,19 +1,45 @@
+use crate::error::ServiceError;
+use crate::models::amount::Amount;
 use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
 use utoipa::ToSchema;
 
-#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
 pub struct EarningsHistory {
     pub _id: ObjectId,
     pub start_time: i64,
     pub end_time: i64,
-    pub block_rewards: f64,
+    pub block_rewards: Amount,
     pub avg_node_count: f64,
-    pub bonding_earnings: f64,
-    pub liquidity_earnings: f64,
-    pub liquidity_fees: f64,
-    pub rune_price_usd: f64,
+    /// Null on intervals predating it, like `rune_price_usd`.
+    pub bonding_earnings: Option<Amount>,
+    pub liquidity_earnings: Amount,
+    pub liquidity_fees: Amount,
+    pub rune_price_usd: Option<f64>,
+}
+
+impl EarningsHistory {
+    /// Stored fields besides `_id`, in declaration order. The per-pool rows
+    /// live in `earnings_history_pools` and are not export columns.
+    pub const FIELDS: [&'static str; 8] = [
+        "start_time",
+        "end_time",
+        "block_rewards",
+        "avg_node_count",
+        "bonding_earnings",
+        "liquidity_earnings",
+        "liquidity_fees",
+        "rune_price_usd",
+    ];
+
+    /// The reward and earnings fields, stored as `Amount`.
+    pub const AMOUNT_FIELDS: [&'static str; 4] = [
+        "block_rewards",
+        "bonding_earnings",
+        "liquidity_earnings",
+        "liquidity_fees",
+    ];
 }
 
 #[derive(Debug, Deserialize)]
@@ -22,26 +48,32 @@ pub struct EarningsHistoryRequest {
     pub end_time: String,
     pub block_rewards: String,
     pub avg_node_count: String,
-    pub bonding_earnings: String,
+    pub bonding_earnings: Option<String>,
     pub liquidity_earnings: String,
     pub liquidity_fees: String,
-    pub rune_price_usd: String,
+    pub rune_price_usd: Option<String>,
 }
 
 impl TryFrom<EarningsHistoryRequest> for EarningsHistory {
-    type Error = Box<dyn std::error::Error>;
+    type Error = ServiceError;
 
     fn try_from(intervals: EarningsHistoryRequest) -> Result<Self, Self::Error> {
         Ok(Self {
             _id: ObjectId::new(),
             start_time: intervals.start_time.trim().parse::<i64>()?,
             end_time: intervals.end_time.trim().parse::<i64>()?,
-            block_rewards: intervals.block_rewards.trim().parse::<f64>()?,
+            block_rewards: intervals.block_rewards.trim().parse::<Amount>()?,
             avg_node_count: intervals.avg_node_count.trim().parse::<f64>()?,
-            bonding_earnings: intervals.bonding_earnings.trim().parse::<f64>()?,
-            liquidity_earnings: intervals.liquidity_earnings.trim().parse::<f64>()?,
-            liquidity_fees: intervals.liquidity_fees.trim().parse::<f64>()?,
-            rune_price_usd: intervals.rune_price_usd.trim().parse::<f64>()?,
+            bonding_earnings: intervals
+                .bonding_earnings
+                .map(|b| b.trim().parse::<Amount>())
+                .transpose()?,
+            liquidity_earnings: intervals.liquidity_earnings.trim().parse::<Amount>()?,
+            liquidity_fees: intervals.liquidity_fees.trim().parse::<Amount>()?,
+            rune_price_usd: intervals
+                .rune_price_usd
+                .map(|p| p.trim().parse::<f64>())
+                .transpose()?,
         })
     }
 }
@@ -1,21 +1,52 @@
+use crate::error::ServiceError;
+use crate::models::amount::Amount;
 use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct EarningsHistoryPools {
     pub _id: ObjectId,
     pub pool: String,
-    pub asset_liquidity_fees: f64,
-    pub rune_liquidity_fees: f64,
-    pub total_liquidity_fees_rune: f64,
-    pub saver_earning: f64,
-    pub rewards: f64,
+    pub asset_liquidity_fees: Amount,
+    pub rune_liquidity_fees: Amount,
+    pub total_liquidity_fees_rune: Amount,
+    pub saver_earning: Amount,
+    pub rewards: Amount,
+    /// Liquidity fees plus rewards, as reported by Midgard. Rows stored
+    /// before it was kept get it from a migration, see
+    /// `Mongodb::backfill_pool_earnings`.
+    pub earnings: Amount,
     pub start_time: i64,
     pub end_time: i64,
     pub earnings_summary_id: ObjectId,
 }
 
+impl EarningsHistoryPools {
+    /// Stored fields besides `_id`, `pool` and the `earnings_summary_id`
+    /// link to the network-wide row, in declaration order.
+    pub const FIELDS: [&'static str; 8] = [
+        "asset_liquidity_fees",
+        "rune_liquidity_fees",
+        "total_liquidity_fees_rune",
+        "saver_earning",
+        "rewards",
+        "earnings",
+        "start_time",
+        "end_time",
+    ];
+
+    /// Every per-pool fee and earnings field is an `Amount`.
+    pub const AMOUNT_FIELDS: [&'static str; 6] = [
+        "asset_liquidity_fees",
+        "rune_liquidity_fees",
+        "total_liquidity_fees_rune",
+        "saver_earning",
+        "rewards",
+        "earnings",
+    ];
+}
+
 #[derive(Debug, Deserialize)]
 pub struct PoolEarningsRequest {
     pub pool: String,
@@ -24,23 +55,25 @@ pub struct PoolEarningsRequest {
     pub total_liquidity_fees_rune: String,
     pub saver_earning: String,
     pub rewards: String,
+    pub earnings: String,
     pub start_time: String,
     pub end_time: String,
     pub earnings_summary_id: ObjectId,
 }
 
 impl TryFrom<PoolEarningsRequest> for EarningsHistoryPools {
-    type Error = Box<dyn std::error::Error>;
+    type Error = ServiceError;
 
     fn try_from(pool: PoolEarningsRequest) -> Result<Self, Self::Error> {
         Ok(Self {
             _id: ObjectId::new(),
-            pool: pool.pool.trim().parse::<String>()?,
-            asset_liquidity_fees: pool.asset_liquidity_fees.trim().parse::<f64>()?,
-            rune_liquidity_fees: pool.rune_liquidity_fees.trim().parse::<f64>()?,
-            total_liquidity_fees_rune: pool.total_liquidity_fees_rune.trim().parse::<f64>()?,
-            saver_earning: pool.saver_earning.trim().parse::<f64>()?,
-            rewards: pool.rewards.trim().parse::<f64>()?,
+            pool: pool.pool.trim().to_string(),
+            asset_liquidity_fees: pool.asset_liquidity_fees.trim().parse::<Amount>()?,
+            rune_liquidity_fees: pool.rune_liquidity_fees.trim().parse::<Amount>()?,
+            total_liquidity_fees_rune: pool.total_liquidity_fees_rune.trim().parse::<Amount>()?,
+            saver_earning: pool.saver_earning.trim().parse::<Amount>()?,
+            rewards: pool.rewards.trim().parse::<Amount>()?,
+            earnings: pool.earnings.trim().parse::<Amount>()?,
             start_time: pool.start_time.trim().parse::<i64>()?,
             end_time: pool.end_time.trim().parse::<i64>()?,
             earnings_summary_id: pool.earnings_summary_id,
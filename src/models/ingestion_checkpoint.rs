@@ -0,0 +1,68 @@
+use crate::utils::{MIDGARD_HISTORY_START, RUNEPOOL_START_TIME};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// A Midgard history series ingested by the service.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Series {
+    Depth,
+    Swaps,
+    Earnings,
+    RunepoolMembers,
+}
+
+impl Series {
+    pub const ALL: [Series; 4] = [
+        Series::Depth,
+        Series::Swaps,
+        Series::Earnings,
+        Series::RunepoolMembers,
+    ];
+
+    /// Collection the series' intervals are stored in.
+    pub fn collection_name(&self) -> &'static str {
+        match self {
+            Series::Depth => "depth_history",
+            Series::Swaps => "swaps_history",
+            Series::Earnings => "earnings_history",
+            Series::RunepoolMembers => "runepool_members_history",
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Series::Depth => "depth",
+            Series::Swaps => "swaps",
+            Series::Earnings => "earnings",
+            Series::RunepoolMembers => "runepool_members",
+        }
+    }
+
+    /// Start of the series' history on Midgard.
+    pub fn history_start(&self) -> i64 {
+        match self {
+            Series::RunepoolMembers => RUNEPOOL_START_TIME,
+            Series::Depth | Series::Swaps | Series::Earnings => MIDGARD_HISTORY_START,
+        }
+    }
+}
+
+/// Ingestion progress for one (series, pool, interval) combination.
+/// `last_end_time` is the end of the last interval known to be complete.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct IngestionCheckpoint {
+    pub series: Series,
+    pub pool: Option<String>,
+    pub interval: String,
+    pub last_end_time: i64,
+    pub updated_at: i64,
+}
+
+/// Latest checkpoint of a series: how far its data reaches and when it was
+/// last stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeriesFreshness {
+    pub last_end_time: i64,
+    pub updated_at: i64,
+}
@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::models::ingestion_checkpoint::Series;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum GapStatus {
+    /// Detected but not backfilled yet.
+    Open,
+    /// Re-fetched from Midgard after it was detected.
+    Backfilled,
+    /// The backfill attempt failed; see `backfill_error`. Retried on later
+    /// runs until it was attempted `IngestionGap::MAX_ATTEMPTS` times.
+    Failed,
+    /// The backfill ran, but Midgard had no intervals for part of the range,
+    /// e.g. during a chain halt; see `backfill_error`. Retried like `Failed`.
+    Unfillable,
+}
+
+/// A run of missing intervals between two stored intervals of a series.
+/// `from` is the end of the interval before the gap and `to` the start of the
+/// interval after it.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct IngestionGap {
+    pub series: Series,
+    pub pool: Option<String>,
+    pub interval: String,
+    pub from: i64,
+    pub to: i64,
+    pub missing_intervals: i64,
+    pub status: GapStatus,
+    pub backfill_error: Option<String>,
+    pub detected_at: i64,
+    /// Backfills tried so far.
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+impl IngestionGap {
+    /// Backfills tried before a gap Midgard cannot fill is left alone.
+    pub const MAX_ATTEMPTS: u32 = 3;
+
+    /// Whether the next gap backfill run should fetch the gap again.
+    pub fn should_retry(&self) -> bool {
+        self.status != GapStatus::Backfilled && self.attempts < Self::MAX_ATTEMPTS
+    }
+}
+
+/// How far gap detection has read the stored intervals of one (series, pool,
+/// interval): `scanned_until` is the end of the newest interval it checked,
+/// so the next scan starts there instead of at the first interval.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GapScanCheckpoint {
+    pub series: Series,
+    pub pool: Option<String>,
+    pub interval: String,
+    pub scanned_until: i64,
+    pub updated_at: i64,
+}
@@ -1,5 +1,121 @@
+pub mod amount;
+pub mod api_key;
 pub mod depth_price_history;
 pub mod earnings_history;
 pub mod earnings_history_pools;
+pub mod ingestion_checkpoint;
+pub mod ingestion_gap;
+pub mod quota_usage;
 pub mod runepool_members_units_history;
+pub mod scheduler_state;
+pub mod schema_version;
 pub mod swaps_history;
+
+#[cfg(test)]
+mod tests {
+    use super::amount::Amount;
+    use super::depth_price_history::DepthPriceHistory;
+    use super::earnings_history::EarningsHistory;
+    use super::earnings_history_pools::EarningsHistoryPools;
+    use super::runepool_members_units_history::RunePoolTotalMembersHistory;
+    use super::swaps_history::SwapsHistory;
+    use crate::error::ServiceError;
+    use crate::services::fetch_runepool_members_units_history::Interval;
+    use mongodb::bson::{to_document, Bson};
+    use serde::Serialize;
+
+    /// Stored keys of `row` in declaration order, besides `_id` and `pool`.
+    fn stored_fields(row: &impl Serialize) -> Vec<String> {
+        to_document(row)
+            .unwrap()
+            .keys()
+            .filter(|key| !matches!(key.as_str(), "_id" | "pool"))
+            .cloned()
+            .collect()
+    }
+
+    /// Stored keys of `row` holding a Decimal128.
+    fn amount_fields(row: &impl Serialize) -> Vec<String> {
+        to_document(row)
+            .unwrap()
+            .into_iter()
+            .filter(|(_, value)| matches!(value, Bson::Decimal128(_)))
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    #[test]
+    fn fields_match_the_stored_documents() {
+        assert_eq!(
+            stored_fields(&DepthPriceHistory::default()),
+            DepthPriceHistory::FIELDS
+        );
+        assert_eq!(
+            stored_fields(&EarningsHistory::default()),
+            EarningsHistory::FIELDS
+        );
+        let mut pool_earnings_fields = stored_fields(&EarningsHistoryPools::default());
+        pool_earnings_fields.retain(|field| field != "earnings_summary_id");
+        assert_eq!(pool_earnings_fields, EarningsHistoryPools::FIELDS);
+        assert_eq!(
+            stored_fields(&SwapsHistory::default()),
+            SwapsHistory::FIELDS
+        );
+        assert_eq!(
+            stored_fields(&RunePoolTotalMembersHistory::default()),
+            RunePoolTotalMembersHistory::FIELDS
+        );
+    }
+
+    #[test]
+    fn amount_fields_match_the_decimal_fields() {
+        assert_eq!(
+            amount_fields(&DepthPriceHistory::default()),
+            DepthPriceHistory::AMOUNT_FIELDS
+        );
+        let earnings = EarningsHistory {
+            bonding_earnings: Some(Amount::zero()),
+            ..Default::default()
+        };
+        assert_eq!(amount_fields(&earnings), EarningsHistory::AMOUNT_FIELDS);
+        assert_eq!(
+            amount_fields(&EarningsHistoryPools::default()),
+            EarningsHistoryPools::AMOUNT_FIELDS
+        );
+        assert_eq!(
+            amount_fields(&SwapsHistory::default()),
+            SwapsHistory::AMOUNT_FIELDS
+        );
+        let runepool = RunePoolTotalMembersHistory {
+            depth: Some(Amount::zero()),
+            ..Default::default()
+        };
+        assert_eq!(
+            amount_fields(&runepool),
+            RunePoolTotalMembersHistory::AMOUNT_FIELDS
+        );
+    }
+
+    fn runepool_interval(depth: Option<&str>) -> Interval {
+        Interval {
+            start_time: "1700000000".to_string(),
+            end_time: "1700003600".to_string(),
+            depth: depth.map(str::to_string),
+            count: "12".to_string(),
+            units: "500".to_string(),
+        }
+    }
+
+    #[test]
+    fn runepool_depth_is_null_only_when_midgard_leaves_it_out() {
+        let missing = RunePoolTotalMembersHistory::try_from(runepool_interval(None)).unwrap();
+        assert!(missing.depth.is_none());
+        let present =
+            RunePoolTotalMembersHistory::try_from(runepool_interval(Some(" 42 "))).unwrap();
+        assert_eq!(present.depth.unwrap().to_string(), "42");
+        assert!(matches!(
+            RunePoolTotalMembersHistory::try_from(runepool_interval(Some("n/a"))),
+            Err(ServiceError::InvalidNumber(_))
+        ));
+    }
+}
@@ -0,0 +1,16 @@
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+/// Requests a client made on one UTC day, counted against its daily quota.
+/// Stored rather than held in memory so quotas survive restarts and are
+/// shared by every instance of the service.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QuotaUsage {
+    /// `key:<hash>` for API keys, `ip:<address>` for anonymous clients.
+    pub client: String,
+    /// UTC date, as `YYYY-MM-DD`.
+    pub day: String,
+    pub used: i64,
+    /// When MongoDB deletes the document, a day after `day` ends.
+    pub expires_at: DateTime,
+}
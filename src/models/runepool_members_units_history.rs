@@ -1,31 +1,45 @@
+use crate::error::ServiceError;
+use crate::models::amount::Amount;
 use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
 use crate::services::fetch_runepool_members_units_history::Interval;
 
-#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
 pub struct RunePoolTotalMembersHistory {
     pub _id: ObjectId,
     // pub pool: String,
     pub start_time: i64,
     pub end_time: i64,
-    pub depth: Option<f64>,
+    pub depth: Option<Amount>,
     pub count: f64,
-    pub units: f64,
+    pub units: Amount,
+}
+
+impl RunePoolTotalMembersHistory {
+    /// Stored fields besides `_id`, in declaration order and export column
+    /// order.
+    pub const FIELDS: [&'static str; 5] = ["start_time", "end_time", "depth", "count", "units"];
+
+    /// `depth` and `units` are `Amount`s; `count` is a member count.
+    pub const AMOUNT_FIELDS: [&'static str; 2] = ["depth", "units"];
 }
 
 impl TryFrom<Interval> for RunePoolTotalMembersHistory {
-    type Error = Box<dyn std::error::Error>;
+    type Error = ServiceError;
 
     fn try_from(interval: Interval) -> Result<Self, Self::Error> {
         Ok(Self {
             _id: ObjectId::new(),
             start_time: interval.start_time.trim().parse::<i64>()?,
             end_time: interval.end_time.trim().parse::<i64>()?,
-            depth: interval.depth.and_then(|d| d.trim().parse::<f64>().ok()),
+            depth: interval
+                .depth
+                .map(|d| d.trim().parse::<Amount>())
+                .transpose()?,
             count: interval.count.trim().parse::<f64>()?,
-            units: interval.units.trim().parse::<f64>()?,
+            units: interval.units.trim().parse::<Amount>()?,
         })
     }
 }
@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+
+/// Scheduler settings kept across restarts. There is a single document, with
+/// `SchedulerState::ID` as its `_id`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SchedulerState {
+    #[serde(rename = "_id")]
+    pub id: String,
+    /// Set by the admin pause route and cleared by the resume route.
+    pub paused: bool,
+    pub updated_at: i64,
+}
+
+impl SchedulerState {
+    pub const ID: &'static str = "scheduler";
+}
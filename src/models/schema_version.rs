@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+/// How many of `Mongodb::MIGRATIONS` the database has been through. There is
+/// a single document, with `SchemaVersion::ID` as its `_id`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SchemaVersion {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub version: u32,
+    pub migrated_at: i64,
+}
+
+impl SchemaVersion {
+    pub const ID: &'static str = "schema";
+}
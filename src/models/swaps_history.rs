@@ -1,13 +1,16 @@
+use crate::error::ServiceError;
+use crate::models::amount::Amount;
 use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
 use crate::services::fetch_swaps_history::Interval;
 
-#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
 pub struct SwapsHistory {
     pub _id: ObjectId,
-    pub pool: String,
+    /// Pool the interval belongs to, or `None` for the network-wide series.
+    pub pool: Option<String>,
     pub start_time: i64,
     pub end_time: i64,
     pub to_asset_count: i64,
@@ -17,27 +20,27 @@ pub struct SwapsHistory {
     pub synth_mint_count: i64,
     pub synth_redeem_count: i64,
     pub total_count: i64,
-    pub to_asset_volume: f64,
-    pub to_rune_volume: f64,
-    pub to_trade_volume: f64,
-    pub from_trade_volume: f64,
-    pub synth_mint_volume: f64,
-    pub synth_redeem_volume: f64,
-    pub total_volume: f64,
-    pub to_asset_volume_usd: f64,
-    pub to_rune_volume_usd: f64,
-    pub to_trade_volume_usd: f64,
-    pub from_trade_volume_usd: f64,
-    pub synth_mint_volume_usd: f64,
-    pub synth_redeem_volume_usd: f64,
-    pub total_volume_usd: f64,
-    pub to_asset_fees: f64,
-    pub to_rune_fees: f64,
-    pub to_trade_fees: f64,
-    pub from_trade_fees: f64,
-    pub synth_mint_fees: f64,
-    pub synth_redeem_fees: f64,
-    pub total_fees: f64,
+    pub to_asset_volume: Amount,
+    pub to_rune_volume: Amount,
+    pub to_trade_volume: Amount,
+    pub from_trade_volume: Amount,
+    pub synth_mint_volume: Amount,
+    pub synth_redeem_volume: Amount,
+    pub total_volume: Amount,
+    pub to_asset_volume_usd: Amount,
+    pub to_rune_volume_usd: Amount,
+    pub to_trade_volume_usd: Amount,
+    pub from_trade_volume_usd: Amount,
+    pub synth_mint_volume_usd: Amount,
+    pub synth_redeem_volume_usd: Amount,
+    pub total_volume_usd: Amount,
+    pub to_asset_fees: Amount,
+    pub to_rune_fees: Amount,
+    pub to_trade_fees: Amount,
+    pub from_trade_fees: Amount,
+    pub synth_mint_fees: Amount,
+    pub synth_redeem_fees: Amount,
+    pub total_fees: Amount,
     pub to_asset_average_slip: f64,
     pub to_rune_average_slip: f64,
     pub to_trade_average_slip: f64,
@@ -48,13 +51,83 @@ pub struct SwapsHistory {
     pub rune_price_usd: f64,
 }
 
-impl TryFrom<Interval> for SwapsHistory {
-    type Error = Box<dyn std::error::Error>;
+impl SwapsHistory {
+    /// Stored fields besides `_id` and `pool`, in declaration order, which is
+    /// the column order of swaps exports.
+    pub const FIELDS: [&'static str; 38] = [
+        "start_time",
+        "end_time",
+        "to_asset_count",
+        "to_rune_count",
+        "to_trade_count",
+        "from_trade_count",
+        "synth_mint_count",
+        "synth_redeem_count",
+        "total_count",
+        "to_asset_volume",
+        "to_rune_volume",
+        "to_trade_volume",
+        "from_trade_volume",
+        "synth_mint_volume",
+        "synth_redeem_volume",
+        "total_volume",
+        "to_asset_volume_usd",
+        "to_rune_volume_usd",
+        "to_trade_volume_usd",
+        "from_trade_volume_usd",
+        "synth_mint_volume_usd",
+        "synth_redeem_volume_usd",
+        "total_volume_usd",
+        "to_asset_fees",
+        "to_rune_fees",
+        "to_trade_fees",
+        "from_trade_fees",
+        "synth_mint_fees",
+        "synth_redeem_fees",
+        "total_fees",
+        "to_asset_average_slip",
+        "to_rune_average_slip",
+        "to_trade_average_slip",
+        "from_trade_average_slip",
+        "synth_mint_average_slip",
+        "synth_redeem_average_slip",
+        "average_slip",
+        "rune_price_usd",
+    ];
 
-    fn try_from(interval: Interval) -> Result<Self, Self::Error> {
+    /// Volumes and fees, stored as `Amount`. Counts and slips are not.
+    pub const AMOUNT_FIELDS: [&'static str; 21] = [
+        "to_asset_volume",
+        "to_rune_volume",
+        "to_trade_volume",
+        "from_trade_volume",
+        "synth_mint_volume",
+        "synth_redeem_volume",
+        "total_volume",
+        "to_asset_volume_usd",
+        "to_rune_volume_usd",
+        "to_trade_volume_usd",
+        "from_trade_volume_usd",
+        "synth_mint_volume_usd",
+        "synth_redeem_volume_usd",
+        "total_volume_usd",
+        "to_asset_fees",
+        "to_rune_fees",
+        "to_trade_fees",
+        "from_trade_fees",
+        "synth_mint_fees",
+        "synth_redeem_fees",
+        "total_fees",
+    ];
+}
+
+impl TryFrom<(Option<&str>, Interval)> for SwapsHistory {
+    type Error = ServiceError;
+
+    fn try_from((pool, interval): (Option<&str>, Interval)) -> Result<Self, Self::Error> {
         Ok(Self {
             _id: ObjectId::new(),
-            pool: "BTC.BTC".to_string(),
+            pool: pool.map(str::to_string),
             start_time: interval.start_time.trim().parse::<i64>()?,
             end_time: interval.end_time.trim().parse::<i64>()?,
             to_asset_count: interval.to_asset_count.trim().parse::<i64>()?,
@@ -64,27 +137,27 @@ impl TryFrom<Interval> for SwapsHistory {
             synth_mint_count: interval.synth_mint_count.trim().parse::<i64>()?,
             synth_redeem_count: interval.synth_redeem_count.trim().parse::<i64>()?,
             total_count: interval.total_count.trim().parse::<i64>()?,
-            to_asset_volume: interval.to_asset_volume.trim().parse::<f64>()?,
-            to_rune_volume: interval.to_rune_volume.trim().parse::<f64>()?,
-            to_trade_volume: interval.to_trade_volume.trim().parse::<f64>()?,
-            from_trade_volume: interval.from_trade_volume.trim().parse::<f64>()?,
-            synth_mint_volume: interval.synth_mint_volume.trim().parse::<f64>()?,
-            synth_redeem_volume: interval.synth_redeem_volume.trim().parse::<f64>()?,
-            total_volume: interval.total_volume.trim().parse::<f64>()?,
-            to_asset_volume_usd: interval.to_asset_volume_usd.trim().parse::<f64>()?,
-            to_rune_volume_usd: interval.to_rune_volume_usd.trim().parse::<f64>()?,
-            to_trade_volume_usd: interval.to_trade_volume_usd.trim().parse::<f64>()?,
-            from_trade_volume_usd: interval.from_trade_volume_usd.trim().parse::<f64>()?,
-            synth_mint_volume_usd: interval.synth_mint_volume_usd.trim().parse::<f64>()?,
-            synth_redeem_volume_usd: interval.synth_redeem_volume_usd.trim().parse::<f64>()?,
-            total_volume_usd: interval.total_volume_usd.trim().parse::<f64>()?,
-            to_asset_fees: interval.to_asset_fees.trim().parse::<f64>()?,
-            to_rune_fees: interval.to_rune_fees.trim().parse::<f64>()?,
-            to_trade_fees: interval.to_trade_fees.trim().parse::<f64>()?,
-            from_trade_fees: interval.from_trade_fees.trim().parse::<f64>()?,
-            synth_mint_fees: interval.synth_mint_fees.trim().parse::<f64>()?,
-            synth_redeem_fees: interval.synth_redeem_fees.trim().parse::<f64>()?,
-            total_fees: interval.total_fees.trim().parse::<f64>()?,
+            to_asset_volume: interval.to_asset_volume.trim().parse::<Amount>()?,
+            to_rune_volume: interval.to_rune_volume.trim().parse::<Amount>()?,
+            to_trade_volume: interval.to_trade_volume.trim().parse::<Amount>()?,
+            from_trade_volume: interval.from_trade_volume.trim().parse::<Amount>()?,
+            synth_mint_volume: interval.synth_mint_volume.trim().parse::<Amount>()?,
+            synth_redeem_volume: interval.synth_redeem_volume.trim().parse::<Amount>()?,
+            total_volume: interval.total_volume.trim().parse::<Amount>()?,
+            to_asset_volume_usd: interval.to_asset_volume_usd.trim().parse::<Amount>()?,
+            to_rune_volume_usd: interval.to_rune_volume_usd.trim().parse::<Amount>()?,
+            to_trade_volume_usd: interval.to_trade_volume_usd.trim().parse::<Amount>()?,
+            from_trade_volume_usd: interval.from_trade_volume_usd.trim().parse::<Amount>()?,
+            synth_mint_volume_usd: interval.synth_mint_volume_usd.trim().parse::<Amount>()?,
+            synth_redeem_volume_usd: interval.synth_redeem_volume_usd.trim().parse::<Amount>()?,
+            total_volume_usd: interval.total_volume_usd.trim().parse::<Amount>()?,
+            to_asset_fees: interval.to_asset_fees.trim().parse::<Amount>()?,
+            to_rune_fees: interval.to_rune_fees.trim().parse::<Amount>()?,
+            to_trade_fees: interval.to_trade_fees.trim().parse::<Amount>()?,
+            from_trade_fees: interval.from_trade_fees.trim().parse::<Amount>()?,
+            synth_mint_fees: interval.synth_mint_fees.trim().parse::<Amount>()?,
+            synth_redeem_fees: interval.synth_redeem_fees.trim().parse::<Amount>()?,
+            total_fees: interval.total_fees.trim().parse::<Amount>()?,
             to_asset_average_slip: interval.to_asset_average_slip.trim().parse::<f64>()?,
             to_rune_average_slip: interval.to_rune_average_slip.trim().parse::<f64>()?,
             to_trade_average_slip: interval.to_trade_average_slip.trim().parse::<f64>()?,
@@ -0,0 +1,226 @@
+use crate::database::db::Mongodb;
+use crate::error::ApiError;
+use crate::models::ingestion_checkpoint::Series;
+use crate::services::ingestion_jobs::{start_job, JobKind, JobRange, JobRegistry};
+use crate::services::scheduler;
+use actix_web::{get, post, web, HttpResponse};
+use chrono::Utc;
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+/// Range to fetch or re-ingest. Intervals are fetched at the ingestion
+/// interval (`hour`).
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct IngestionRangeRequest {
+    pub series: Series,
+
+    /// Pool of the depth history (required) or swaps history (the
+    /// network-wide series when omitted). Earnings and runepool history have
+    /// no pools.
+    pub pool: Option<String>,
+
+    /// Start of the range, Unix seconds
+    pub from: i64,
+
+    /// End of the range, Unix seconds; defaults to now
+    pub to: Option<i64>,
+}
+
+impl IngestionRangeRequest {
+    fn into_range(self) -> Result<JobRange, ApiError> {
+        let to = self.to.unwrap_or_else(|| Utc::now().timestamp());
+        if self.from >= to {
+            return Err(ApiError::invalid("from", "from must be before to"));
+        }
+
+        match (self.series, &self.pool) {
+            (Series::Depth, None) => {
+                return Err(ApiError::invalid("pool", "Depth history needs a pool"))
+            }
+            (Series::Earnings | Series::RunepoolMembers, Some(_)) => {
+                return Err(ApiError::invalid(
+                    "pool",
+                    format!("{} history has no pools", self.series.as_str()),
+                ))
+            }
+            _ => {}
+        }
+
+        Ok(JobRange {
+            series: self.series,
+            pool: self.pool,
+            from: self.from,
+            to,
+        })
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/series",
+    responses(
+        (status = 200, description = "Every series with its collection and ingestion checkpoints", body = Object),
+        (status = 401, description = "No admin API key", body = crate::error::ErrorEnvelope),
+        (status = 403, description = "The API key is not an admin key", body = crate::error::ErrorEnvelope),
+        (status = 500, description = "Internal server error", body = crate::error::ErrorEnvelope)
+    ),
+    security(("api_key" = [])),
+    tag = "Admin"
+)]
+#[get("/series")]
+pub async fn get_series(db: web::Data<Mongodb>) -> Result<HttpResponse, ApiError> {
+    let checkpoints = db.list_checkpoints().await?;
+
+    let series: Vec<serde_json::Value> = Series::ALL
+        .iter()
+        .map(|&series| {
+            serde_json::json!({
+                "series": series,
+                "collection": series.collection_name(),
+                "checkpoints": checkpoints
+                    .iter()
+                    .filter(|checkpoint| checkpoint.series == series)
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "series": series })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/fetch",
+    request_body = IngestionRangeRequest,
+    responses(
+        (status = 202, description = "Fetch job started", body = crate::services::ingestion_jobs::IngestionJob),
+        (status = 400, description = "Invalid range or pool", body = crate::error::ErrorEnvelope),
+        (status = 401, description = "No admin API key", body = crate::error::ErrorEnvelope),
+        (status = 403, description = "The API key is not an admin key", body = crate::error::ErrorEnvelope)
+    ),
+    security(("api_key" = [])),
+    tag = "Admin"
+)]
+#[post("/fetch")]
+pub async fn post_fetch(
+    db: web::Data<Mongodb>,
+    body: web::Json<IngestionRangeRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let range = body.into_inner().into_range()?;
+    let job = start_job(db.get_ref().clone(), JobKind::Fetch, range);
+    Ok(HttpResponse::Accepted().json(job))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/reingest",
+    request_body = IngestionRangeRequest,
+    responses(
+        (status = 202, description = "Re-ingest job started: the range is deleted, then fetched again", body = crate::services::ingestion_jobs::IngestionJob),
+        (status = 400, description = "Invalid range or pool", body = crate::error::ErrorEnvelope),
+        (status = 401, description = "No admin API key", body = crate::error::ErrorEnvelope),
+        (status = 403, description = "The API key is not an admin key", body = crate::error::ErrorEnvelope)
+    ),
+    security(("api_key" = [])),
+    tag = "Admin"
+)]
+#[post("/reingest")]
+pub async fn post_reingest(
+    db: web::Data<Mongodb>,
+    body: web::Json<IngestionRangeRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let range = body.into_inner().into_range()?;
+    let job = start_job(db.get_ref().clone(), JobKind::Reingest, range);
+    Ok(HttpResponse::Accepted().json(job))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/jobs",
+    responses(
+        (status = 200, description = "Running and recent ingestion jobs, newest first", body = Vec<crate::services::ingestion_jobs::IngestionJob>),
+        (status = 401, description = "No admin API key", body = crate::error::ErrorEnvelope),
+        (status = 403, description = "The API key is not an admin key", body = crate::error::ErrorEnvelope)
+    ),
+    security(("api_key" = [])),
+    tag = "Admin"
+)]
+#[get("/jobs")]
+pub async fn get_jobs() -> HttpResponse {
+    HttpResponse::Ok().json(JobRegistry::global().list())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/jobs/{id}",
+    params(("id" = u64, Path, description = "Job id")),
+    responses(
+        (status = 200, description = "The job and its progress", body = crate::services::ingestion_jobs::IngestionJob),
+        (status = 400, description = "The id is not a number", body = crate::error::ErrorEnvelope),
+        (status = 404, description = "No such job", body = crate::error::ErrorEnvelope),
+        (status = 401, description = "No admin API key", body = crate::error::ErrorEnvelope),
+        (status = 403, description = "The API key is not an admin key", body = crate::error::ErrorEnvelope)
+    ),
+    security(("api_key" = [])),
+    tag = "Admin"
+)]
+#[get("/jobs/{id}")]
+pub async fn get_job(id: web::Path<u64>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let job = JobRegistry::global()
+        .get(id)
+        .ok_or_else(|| ApiError::NotFound(format!("No ingestion job {}", id)))?;
+    Ok(HttpResponse::Ok().json(job))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/scheduler",
+    responses(
+        (status = 200, description = "Whether the hourly scheduler is paused or running", body = crate::services::scheduler::SchedulerStatus),
+        (status = 401, description = "No admin API key", body = crate::error::ErrorEnvelope),
+        (status = 403, description = "The API key is not an admin key", body = crate::error::ErrorEnvelope)
+    ),
+    security(("api_key" = [])),
+    tag = "Admin"
+)]
+#[get("/scheduler")]
+pub async fn get_scheduler() -> HttpResponse {
+    HttpResponse::Ok().json(scheduler::status())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/scheduler/pause",
+    responses(
+        (status = 200, description = "Scheduled runs are skipped until resumed, also after a restart; a run in progress finishes", body = crate::services::scheduler::SchedulerStatus),
+        (status = 401, description = "No admin API key", body = crate::error::ErrorEnvelope),
+        (status = 403, description = "The API key is not an admin key", body = crate::error::ErrorEnvelope),
+        (status = 500, description = "Internal server error", body = crate::error::ErrorEnvelope)
+    ),
+    security(("api_key" = [])),
+    tag = "Admin"
+)]
+#[post("/scheduler/pause")]
+pub async fn post_scheduler_pause(db: web::Data<Mongodb>) -> Result<HttpResponse, ApiError> {
+    scheduler::pause(&db).await?;
+    Ok(HttpResponse::Ok().json(scheduler::status()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/scheduler/resume",
+    responses(
+        (status = 200, description = "Scheduled runs resume at the next hour", body = crate::services::scheduler::SchedulerStatus),
+        (status = 401, description = "No admin API key", body = crate::error::ErrorEnvelope),
+        (status = 403, description = "The API key is not an admin key", body = crate::error::ErrorEnvelope),
+        (status = 500, description = "Internal server error", body = crate::error::ErrorEnvelope)
+    ),
+    security(("api_key" = [])),
+    tag = "Admin"
+)]
+#[post("/scheduler/resume")]
+pub async fn post_scheduler_resume(db: web::Data<Mongodb>) -> Result<HttpResponse, ApiError> {
+    scheduler::resume(&db).await?;
+    Ok(HttpResponse::Ok().json(scheduler::status()))
+}
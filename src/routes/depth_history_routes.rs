@@ -1,11 +1,25 @@
 use crate::database::db::Mongodb;
+use crate::error::ApiError;
+use crate::middleware::conditional_get::ConditionalGet;
+use crate::middleware::middleware::ValidateHistoryQuery;
+use crate::middleware::response_cache::CacheResponse;
+use crate::models::depth_price_history::DepthPriceHistory;
+use crate::models::ingestion_checkpoint::Series;
 use crate::routes::queries::HistoryQueryParams;
-use actix_web::{get, web, HttpResponse, Result};
+use actix_web::{get, web, HttpRequest, HttpResponse};
 // use chrono::Utc;
-use crate::utils::{build_match_stage, get_seconds_per_interval, handle_pagination_and_sorting};
+use crate::utils::buckets::{bucket_end_expr, bucket_start_expr};
+use crate::utils::cursor::{
+    cursor_match_stage, page_stages, pagination_document, total_count, PageCursor,
+};
+use crate::utils::export::{export_columns, export_intervals, ExportFormat};
+use crate::utils::rollup::{
+    amount_outputs, group_accumulators, project_fields, summary_pipeline, Rollup, RollupField,
+};
+use crate::utils::time::render_iso_times;
+use crate::utils::{amounts_to_strings_stage, build_match_stage};
 use futures_util::TryStreamExt;
-use log::error;
-use mongodb::bson::{doc, Bson};
+use mongodb::bson::{doc, Document};
 
 #[utoipa::path(
     get,
@@ -16,168 +30,249 @@ use mongodb::bson::{doc, Bson};
     ),
     responses(
         (status = 200, description = "Successfully retrieved depth history", body = Object),
-        (status = 404, description = "No depth history found"),
-        (status = 400, description = "Invalid request parameters"),
-        (status = 500, description = "Internal server error")
+        (status = 304, description = "Nothing new since the If-None-Match or If-Modified-Since validators"),
+        (status = 404, description = "No depth history found", body = crate::error::ErrorEnvelope),
+        (status = 400, description = "Invalid request parameters", body = crate::error::ErrorEnvelope),
+        (status = 500, description = "Internal server error", body = crate::error::ErrorEnvelope)
     ),
     tag = "Depth History"
 )]
-#[get("/api/history/depth/{pool}")]
+#[get(
+    "/api/history/depth/{pool}",
+    wrap = "CacheResponse::new(Series::Depth)",
+    wrap = "ConditionalGet::new(Series::Depth)",
+    wrap = "ValidateHistoryQuery::new(&DEPTH_ROLLUP)"
+)]
 pub async fn get_depth_history(
+    req: HttpRequest,
     path: web::Path<String>,
     db: web::Data<Mongodb>,
     query: web::Query<HistoryQueryParams>,
-) -> Result<HttpResponse> {
+) -> Result<HttpResponse, ApiError> {
     let pool = path.into_inner();
-    let seconds_per_interval =
-        get_seconds_per_interval(query.interval.as_deref().unwrap_or("hour"));
+    let interval = query.interval()?;
     let collection = &db.depth_history;
 
-    let match_stage = build_match_stage(Some(&pool), &query, seconds_per_interval)?;
-    let (_page, skip, limit, sort_field, sort_order) = handle_pagination_and_sorting(&query);
-
-    let pipeline = build_aggregation_pipeline(
-        match_stage.clone(),
-        seconds_per_interval,
-        skip,
-        limit,
-        sort_field,
-        sort_order,
-    );
-
-    let mut cursor = collection.aggregate(pipeline, None).await.map_err(|e| {
-        error!("Database error: {}", e);
-        actix_web::error::ErrorInternalServerError("Failed to fetch depth history")
-    })?;
-
-    let mut intervals = Vec::new();
-    while let Some(doc) = cursor.try_next().await.map_err(|e| {
-        error!("Cursor error: {}", e);
-        actix_web::error::ErrorInternalServerError("Failed to process results")
-    })? {
-        intervals.push(doc);
+    let match_stage = build_match_stage(Some(&pool), &query, interval)?;
+
+    if let Some(format) = ExportFormat::negotiate(&req, query.format.as_deref())? {
+        let fields = query.field_selection(&DEPTH_ROLLUP, &[])?;
+        let mut columns = export_columns(&DepthPriceHistory::FIELDS, &DEPTH_ROLLUP);
+        columns.retain(|column| fields.includes(column));
+        return export_intervals(
+            collection,
+            depth_intervals_pipeline(match_stage, &query, false)?,
+            format,
+            columns,
+            query.iso_times(),
+        )
+        .await;
     }
 
+    let mut intervals = query_depth_intervals(&db, match_stage.clone(), &query).await?;
+
     // Return 404 if no data found
     if intervals.is_empty() {
-        return Ok(HttpResponse::NotFound().json(serde_json::json!({
-            "error": "No depth history found",
-            "status": 404
-        })));
+        return Err(ApiError::NotFound("No depth history found".to_string()));
     }
 
-    let first = intervals.first().unwrap();
-    let last = intervals.last().unwrap();
+    let cursor = PageCursor::from_query(&query)?;
+    let total_count = total_count(collection, &match_stage, interval, cursor).await?;
+    let pagination = pagination_document(
+        &req,
+        &query,
+        collection,
+        &match_stage,
+        cursor,
+        &intervals,
+        total_count,
+    )
+    .await?;
 
-    let total_count = collection
-        .count_documents(match_stage.clone(), None)
-        .await
-        .map_err(|e| {
-            error!("Count error: {}", e);
-            actix_web::error::ErrorInternalServerError("Failed to get total count")
-        })?;
+    let summary = query_depth_summary(&db, match_stage, &query).await?;
+    let mut meta = build_meta_response(summary, pagination);
+    if query.iso_times() {
+        intervals.iter_mut().for_each(render_iso_times);
+        render_iso_times(&mut meta);
+    }
 
     let response = doc! {
         "intervals": &intervals,
-        "meta": build_meta_response(first, last, total_count as i64, query.page.unwrap_or(1), query.limit.unwrap_or(50))
+        "meta": meta
     };
 
     Ok(HttpResponse::Ok().json(response))
 }
 
-fn build_aggregation_pipeline(
+/// Bucketed depth intervals matching `match_stage`, sorted and paginated as
+/// requested by `query`.
+pub async fn query_depth_intervals(
+    db: &Mongodb,
+    match_stage: Document,
+    query: &HistoryQueryParams,
+) -> Result<Vec<Document>, ApiError> {
+    let pipeline = depth_intervals_pipeline(match_stage, query, true)?;
+
+    let cursor = db.depth_history.aggregate(pipeline, None).await?;
+    Ok(cursor.try_collect().await?)
+}
+
+/// Depth fields are all stock metrics, so coarser intervals take the last value.
+pub const DEPTH_ROLLUP: [RollupField; 10] = [
+    RollupField::new("assetDepth", "asset_depth", Rollup::Last),
+    RollupField::new("assetPrice", "asset_price", Rollup::Last),
+    RollupField::new("assetPriceUSD", "asset_price_usd", Rollup::Last),
+    RollupField::new("liquidityUnits", "liquidity_units", Rollup::Last),
+    RollupField::new("luvi", "luvi", Rollup::Last),
+    RollupField::new("membersCount", "members_count", Rollup::Last),
+    RollupField::new("runeDepth", "rune_depth", Rollup::Last),
+    RollupField::new("synthSupply", "synth_supply", Rollup::Last),
+    RollupField::new("synthUnits", "synth_units", Rollup::Last),
+    RollupField::new("units", "units", Rollup::Last),
+];
+
+/// Opening and closing values of the requested range for `meta`, each with
+/// the interval field that selects it. The prices and LUVI only feed
+/// `priceShiftLoss` and `luviIncrease`.
+pub const DEPTH_META: [(&str, RollupField); 14] = [
+    (
+        "assetDepth",
+        RollupField::new("startAssetDepth", "asset_depth", Rollup::First),
+    ),
+    (
+        "assetDepth",
+        RollupField::new("endAssetDepth", "asset_depth", Rollup::Last),
+    ),
+    (
+        "runeDepth",
+        RollupField::new("startRuneDepth", "rune_depth", Rollup::First),
+    ),
+    (
+        "runeDepth",
+        RollupField::new("endRuneDepth", "rune_depth", Rollup::Last),
+    ),
+    (
+        "units",
+        RollupField::new("startLPUnits", "units", Rollup::First),
+    ),
+    (
+        "units",
+        RollupField::new("endLPUnits", "units", Rollup::Last),
+    ),
+    (
+        "membersCount",
+        RollupField::new("startMemberCount", "members_count", Rollup::First),
+    ),
+    (
+        "membersCount",
+        RollupField::new("endMemberCount", "members_count", Rollup::Last),
+    ),
+    (
+        "synthUnits",
+        RollupField::new("startSynthUnits", "synth_units", Rollup::First),
+    ),
+    (
+        "synthUnits",
+        RollupField::new("endSynthUnits", "synth_units", Rollup::Last),
+    ),
+    (
+        "assetPrice",
+        RollupField::new("startAssetPrice", "asset_price", Rollup::First),
+    ),
+    (
+        "assetPrice",
+        RollupField::new("endAssetPrice", "asset_price", Rollup::Last),
+    ),
+    ("luvi", RollupField::new("startLuvi", "luvi", Rollup::First)),
+    ("luvi", RollupField::new("endLuvi", "luvi", Rollup::Last)),
+];
+
+/// `DEPTH_META` over the range matched by `match_stage`, or over the page
+/// window of a cursor request, limited to the selected fields. Empty when
+/// nothing matches.
+pub async fn query_depth_summary(
+    db: &Mongodb,
+    match_stage: Document,
+    query: &HistoryQueryParams,
+) -> Result<Document, ApiError> {
+    let selection = query.field_selection(&DEPTH_ROLLUP, &[])?;
+    let fields: Vec<&RollupField> = DEPTH_META
+        .iter()
+        .filter(|(field, _)| selection.includes(field))
+        .map(|(_, meta)| meta)
+        .collect();
+    let pipeline = summary_pipeline(
+        match_stage,
+        query,
+        &fields,
+        &DepthPriceHistory::AMOUNT_FIELDS,
+    )?;
+
+    let mut cursor = db.depth_history.aggregate(pipeline, None).await?;
+    Ok(cursor.try_next().await?.unwrap_or_default())
+}
+
+/// Depth aggregation for `query`. Exports pass `paginate = false` to get
+/// every interval in the range instead of one page.
+pub fn depth_intervals_pipeline(
     match_stage: mongodb::bson::Document,
-    seconds_per_interval: i64,
-    skip: i64,
-    limit: i64,
-    sort_field: String,
-    sort_order: i32,
-) -> Vec<mongodb::bson::Document> {
-    vec![
-        doc! { "$match": match_stage },
-        doc! {
-            "$group": {
-                "_id": {
-                    "interval_start": {
-                        "$subtract": [
-                            { "$add": ["$end_time", 1] },
-                            { "$mod": [
-                                { "$subtract": ["$end_time", 1] },
-                                seconds_per_interval
-                            ]}
-                        ]
-                    }
-                },
-                "assetDepth": { "$last": "$asset_depth" },
-                "assetPrice": { "$last": "$asset_price" },
-                "assetPriceUSD": { "$last": "$asset_price_usd" },
-                "liquidityUnits": { "$last": "$liquidity_units" },
-                "luvi": { "$last": "$luvi" },
-                "membersCount": { "$last": "$members_count" },
-                "runeDepth": { "$last": "$rune_depth" },
-                "synthSupply": { "$last": "$synth_supply" },
-                "synthUnits": { "$last": "$synth_units" },
-                "units": { "$last": "$units" }
-            }
-        },
-        doc! { "$project": {
-            "_id": 0,
-            "startTime": {
-                "$subtract": [ "$_id.interval_start", { "$mod": [ "$_id.interval_start", seconds_per_interval ] }]
-            },
-            "endTime": {
-                "$add": [
-                    { "$subtract": [ "$_id.interval_start", { "$mod": [ "$_id.interval_start", seconds_per_interval ] }] },
-                    seconds_per_interval
-                ]
-            },
-            "assetDepth": 1,
-            "assetPrice": 1,
-            "assetPriceUSD": 1,
-            "liquidityUnits": 1,
-            "luvi": 1,
-            "membersCount": 1,
-            "runeDepth": 1,
-            "synthSupply": 1,
-            "synthUnits": 1,
-            "units": 1
-        }},
-        doc! { "$sort": { sort_field: sort_order } },
-        doc! { "$skip": skip },
-        doc! { "$limit": limit },
-    ]
+    query: &HistoryQueryParams,
+    paginate: bool,
+) -> Result<Vec<mongodb::bson::Document>, ApiError> {
+    let interval = query.interval()?;
+    let cursor = if paginate {
+        PageCursor::from_query(query)?
+    } else {
+        None
+    };
+    let fields = query.field_selection(&DEPTH_ROLLUP, &[])?;
+
+    let mut group = doc! {
+        "_id": { "interval_start": bucket_start_expr(interval) }
+    };
+    group.extend(group_accumulators(fields.rollup(&DEPTH_ROLLUP)));
+
+    let mut projection = doc! {
+        "_id": 0,
+        "startTime": "$_id.interval_start",
+        "endTime": bucket_end_expr(interval, "$_id.interval_start")
+    };
+    projection.extend(project_fields(fields.rollup(&DEPTH_ROLLUP)));
+
+    let mut pipeline = vec![
+        doc! { "$match": cursor_match_stage(match_stage, query, cursor)? },
+        doc! { "$sort": { "start_time": 1 } },
+        doc! { "$group": group },
+        doc! { "$project": projection },
+    ];
+    pipeline.extend(page_stages(query, cursor, paginate));
+    pipeline.push(amounts_to_strings_stage(&amount_outputs(
+        fields.rollup(&DEPTH_ROLLUP),
+        &DepthPriceHistory::AMOUNT_FIELDS,
+    )));
+    Ok(pipeline)
 }
 
-/// Helper function to build the metadata response
+/// Helper function to build the metadata response from the range's
+/// `DEPTH_META` summary. Entries whose field was left out with `fields` are
+/// left out too.
 fn build_meta_response(
-    first: &mongodb::bson::Document,
-    last: &mongodb::bson::Document,
-    total_count: i64,
-    page: i64,
-    limit: i64,
+    mut meta: mongodb::bson::Document,
+    pagination: mongodb::bson::Document,
 ) -> mongodb::bson::Document {
-    doc! {
-        "startTime": first.get_i64("startTime").unwrap_or_default(),
-        "endTime": last.get_i64("endTime").unwrap_or_default(),
-        "startAssetDepth": first.get_f64("assetDepth").unwrap_or_default().to_string(),
-        "endAssetDepth": last.get_f64("assetDepth").unwrap_or_default().to_string(),
-        "startRuneDepth": first.get_f64("runeDepth").unwrap_or_default().to_string(),
-        "endRuneDepth": last.get_f64("runeDepth").unwrap_or_default().to_string(),
-        "startLPUnits": first.get_f64("units").unwrap_or_default().to_string(),
-        "endLPUnits": last.get_f64("units").unwrap_or_default().to_string(),
-        "startMemberCount": first.get_i32("membersCount").unwrap_or_default(),
-        "endMemberCount": last.get_i32("membersCount").unwrap_or_default(),
-        "startSynthUnits": first.get_f64("synthUnits").unwrap_or_default().to_string(),
-        "endSynthUnits": last.get_f64("synthUnits").unwrap_or_default().to_string(),
-        "priceShiftLoss": first.get_f64("assetPrice").unwrap_or_default() - last.get_f64("assetPrice").unwrap_or_default(),
-        "luviIncrease": last.get_f64("luvi").unwrap_or_default() - first.get_f64("luvi").unwrap_or_default(),
-        "pagination": {
-            "currentPage": Bson::Int64(page),
-            "totalPages": Bson::Int32((total_count as f64 / limit as f64).ceil() as i32),
-            "totalRecords": Bson::Int64(total_count),
-            "limit": Bson::Int64(limit),
-            "sortBy": "startTime",
-            "order": "asc"
-        }
+    let mut change = |start: &str, end: &str| {
+        let start = meta.remove(start)?.as_f64().unwrap_or_default();
+        let end = meta.remove(end)?.as_f64().unwrap_or_default();
+        Some((start, end))
+    };
+    let price = change("startAssetPrice", "endAssetPrice");
+    let luvi = change("startLuvi", "endLuvi");
+    if let Some((start, end)) = price {
+        meta.insert("priceShiftLoss", start - end);
+    }
+    if let Some((start, end)) = luvi {
+        meta.insert("luviIncrease", end - start);
     }
+    meta.insert("pagination", pagination);
+    meta
 }
@@ -1,12 +1,53 @@
 #![allow(unused_imports)]
 use crate::database::db::Mongodb;
+use crate::error::ApiError;
+use crate::middleware::conditional_get::ConditionalGet;
+use crate::middleware::middleware::ValidateHistoryQuery;
+use crate::middleware::response_cache::CacheResponse;
+use crate::models::earnings_history::EarningsHistory;
+use crate::models::earnings_history_pools::EarningsHistoryPools;
+use crate::models::ingestion_checkpoint::Series;
 use crate::routes::queries::HistoryQueryParams;
-use crate::utils::{build_match_stage, get_seconds_per_interval, handle_pagination_and_sorting};
-use actix_web::{get, web, HttpResponse, Result};
-use chrono::Utc;
+use crate::utils::buckets::{bucket_end_expr, bucket_start_expr};
+use crate::utils::cursor::{
+    cursor_match_stage, page_stages, pagination_document, total_count, PageCursor,
+};
+use crate::utils::export::{export_columns, export_intervals, ExportFormat};
+use crate::utils::rollup::{
+    amount_outputs, group_accumulators, project_fields, summary_pipeline, Rollup, RollupField,
+};
+use crate::utils::time::render_iso_times;
+use crate::utils::{amounts_to_strings_stage, build_match_stage};
+use actix_web::{get, web, HttpRequest, HttpResponse};
 use futures_util::TryStreamExt;
 use log::{debug, error};
-use mongodb::bson::doc;
+use mongodb::bson::{doc, Document};
+
+/// Rewards, earnings and fees are flows and are summed over coarser intervals;
+/// the node count is averaged and the RUNE price is a stock.
+pub const EARNINGS_ROLLUP: [RollupField; 6] = [
+    RollupField::new("blockRewards", "block_rewards", Rollup::Sum),
+    RollupField::new("avgNodeCount", "avg_node_count", Rollup::Avg),
+    RollupField::new("bondingEarnings", "bonding_earnings", Rollup::Sum),
+    RollupField::new("liquidityEarnings", "liquidity_earnings", Rollup::Sum),
+    RollupField::new("liquidityFees", "liquidity_fees", Rollup::Sum),
+    RollupField::new("runePriceUSD", "rune_price_usd", Rollup::Last),
+];
+
+/// Per-pool fees, saver earnings, rewards and earnings are all flows and are
+/// summed over coarser intervals.
+pub const POOL_EARNINGS_ROLLUP: [RollupField; 6] = [
+    RollupField::new("assetLiquidityFees", "asset_liquidity_fees", Rollup::Sum),
+    RollupField::new("runeLiquidityFees", "rune_liquidity_fees", Rollup::Sum),
+    RollupField::new(
+        "totalLiquidityFeesRune",
+        "total_liquidity_fees_rune",
+        Rollup::Sum,
+    ),
+    RollupField::new("saverEarning", "saver_earning", Rollup::Sum),
+    RollupField::new("rewards", "rewards", Rollup::Sum),
+    RollupField::new("earnings", "earnings", Rollup::Sum),
+];
 
 #[utoipa::path(
     get,
@@ -14,26 +55,37 @@ use mongodb::bson::doc;
     params(
         ("interval" = Option<String>, Query, description = "Time interval (hour, day, week, etc.)"),
         ("count" = Option<i32>, Query, description = "Number of intervals"),
-        ("from" = Option<i64>, Query, description = "Start timestamp"),
-        ("to" = Option<i64>, Query, description = "End timestamp"),
+        ("from" = Option<String>, Query, description = "Start time: Unix seconds, ISO-8601, a date (2024-06-01), now, or an offset from now (-7d)"),
+        ("to" = Option<String>, Query, description = "End time, in the same forms as from"),
         ("page" = Option<i64>, Query, description = "Page number"),
         ("limit" = Option<i64>, Query, description = "Records per page"),
         ("sort_by" = Option<String>, Query, description = "Field to sort by"),
-        ("order" = Option<String>, Query, description = "Sort order (asc/desc)")
+        ("order" = Option<String>, Query, description = "Sort order (asc/desc)"),
+        ("format" = Option<String>, Query, description = "Response format (json, csv or ndjson)"),
+        ("cursor" = Option<String>, Query, description = "Opaque cursor from nextCursor or prevCursor"),
+        ("fields" = Option<String>, Query, description = "Comma-separated fields to return; startTime and endTime are always included"),
+        ("time_format" = Option<String>, Query, description = "How startTime and endTime are rendered: unix (default) or iso")
     ),
     responses(
         (status = 200, description = "Successfully retrieved earnings history", body = EarningsHistory),
-        (status = 404, description = "No earnings history found"),
-        (status = 400, description = "Invalid request parameters"),
-        (status = 500, description = "Internal server error")
+        (status = 304, description = "Nothing new since the If-None-Match or If-Modified-Since validators"),
+        (status = 404, description = "No earnings history found", body = crate::error::ErrorEnvelope),
+        (status = 400, description = "Invalid request parameters", body = crate::error::ErrorEnvelope),
+        (status = 500, description = "Internal server error", body = crate::error::ErrorEnvelope)
     ),
     tag = "Earnings History"
 )]
-#[get("/api/history/earnings")]
+#[get(
+    "/api/history/earnings",
+    wrap = "CacheResponse::new(Series::Earnings)",
+    wrap = "ConditionalGet::new(Series::Earnings)",
+    wrap = "ValidateHistoryQuery::new(&EARNINGS_ROLLUP)"
+)]
 pub async fn get_earnings_history(
+    req: HttpRequest,
     db: web::Data<Mongodb>,
     query: web::Query<HistoryQueryParams>,
-) -> Result<HttpResponse> {
+) -> Result<HttpResponse, ApiError> {
     // match (&query.interval, query.count) {
     //     (Some(_), None) | (None, Some(_)) => {
     //         return Ok(HttpResponse::BadRequest().json(serde_json::json!({
@@ -44,192 +96,333 @@ pub async fn get_earnings_history(
     //     _ => {}
     // }
 
-    let (page, skip, limit, sort_field, sort_order) = handle_pagination_and_sorting(&query);
+    let match_stage = build_match_stage(None, &query, query.interval()?)?;
+
+    if let Some(format) = ExportFormat::negotiate(&req, query.format.as_deref())? {
+        // The per-pool breakdown follows the model fields as one nested column.
+        let fields = query.field_selection(&EARNINGS_ROLLUP, &["pools"])?;
+        let mut columns = export_columns(&EarningsHistory::FIELDS, &EARNINGS_ROLLUP);
+        columns.push("pools");
+        columns.retain(|column| fields.includes(column));
+        return export_intervals(
+            &db.earnings_history,
+            earnings_intervals_pipeline(match_stage, &query, false)?,
+            format,
+            columns,
+            query.iso_times(),
+        )
+        .await;
+    }
+
+    let cursor = PageCursor::from_query(&query)?;
+    let total_count = total_count(
+        &db.earnings_history,
+        &match_stage,
+        query.interval()?,
+        cursor,
+    )
+    .await?;
+
+    let mut intervals = query_earnings_intervals(&db, match_stage.clone(), &query).await?;
+
+    if intervals.is_empty() {
+        return Err(ApiError::NotFound("No earnings history found".to_string()));
+    }
+
+    let mut meta = query_earnings_summary(&db, match_stage.clone(), &query, false).await?;
+
+    let pagination = pagination_document(
+        &req,
+        &query,
+        &db.earnings_history,
+        &match_stage,
+        cursor,
+        &intervals,
+        total_count,
+    )
+    .await?;
+
+    if query.iso_times() {
+        intervals.iter_mut().for_each(render_iso_times);
+        render_iso_times(&mut meta);
+    }
+
+    let response = doc! {
+        "intervals": &intervals,
+        "meta": meta,
+        "pagination": pagination
+    };
 
-    let seconds_per_interval =
-        get_seconds_per_interval(query.interval.as_deref().unwrap_or("hour"));
-    let earnings_collection = &db.earnings_history;
-    let pools_collection = &db.earnings_history_pools;
+    Ok(HttpResponse::Ok().json(response))
+}
 
-    let mut match_stage = doc! {};
-    // let mut match_stage = build_match_stage(&query, seconds_per_interval);
-    if let Some(from) = query.from {
-        match_stage.insert("start_time", doc! { "$gte": from });
+/// Earnings aggregation for `query`. Exports pass `paginate = false` to get
+/// every interval in the range instead of one page.
+pub fn earnings_intervals_pipeline(
+    match_stage: Document,
+    query: &HistoryQueryParams,
+    paginate: bool,
+) -> Result<Vec<Document>, ApiError> {
+    let interval = query.interval()?;
+    let cursor = if paginate {
+        PageCursor::from_query(query)?
     } else {
-        let current_time = Utc::now().timestamp();
-        let count = query.count.unwrap_or(400) as i64;
-        match_stage.insert(
-            "start_time",
-            doc! {
-                "$gte": current_time - (count * seconds_per_interval)
-            },
-        );
+        None
+    };
+    let fields = query.field_selection(&EARNINGS_ROLLUP, &["pools"])?;
+
+    let mut group = doc! {
+        "_id": { "interval_start": bucket_start_expr(interval) }
+    };
+    group.extend(group_accumulators(fields.rollup(&EARNINGS_ROLLUP)));
+
+    let mut projection = doc! {
+        "_id": 0,
+        "startTime": "$_id.interval_start",
+        "endTime": bucket_end_expr(interval, "$_id.interval_start")
+    };
+    projection.extend(project_fields(fields.rollup(&EARNINGS_ROLLUP)));
+
+    let mut pipeline = vec![
+        doc! { "$match": cursor_match_stage(match_stage.clone(), query, cursor)? },
+        doc! { "$sort": { "start_time": 1 } },
+        doc! { "$group": group },
+        doc! { "$project": projection },
+    ];
+    pipeline.extend(page_stages(query, cursor, paginate));
+    pipeline.push(amounts_to_strings_stage(&amount_outputs(
+        fields.rollup(&EARNINGS_ROLLUP),
+        &EarningsHistory::AMOUNT_FIELDS,
+    )));
+    if fields.includes("pools") {
+        pipeline.push(pools_lookup_stage(match_stage));
     }
-    if let Some(to) = query.to {
-        match_stage.insert("end_time", doc! { "$lte": to });
+    Ok(pipeline)
+}
+
+/// Bucketed earnings intervals matching `match_stage`, sorted and paginated
+/// as requested by `query`, each with its per-pool breakdown in `pools`. The
+/// breakdown is joined in the same aggregation.
+pub async fn query_earnings_intervals(
+    db: &Mongodb,
+    match_stage: Document,
+    query: &HistoryQueryParams,
+) -> Result<Vec<Document>, ApiError> {
+    let pipeline = earnings_intervals_pipeline(match_stage, query, true)?;
+
+    let cursor = db.earnings_history.aggregate(pipeline, None).await?;
+    Ok(cursor.try_collect().await?)
+}
+
+/// The selected earnings fields rolled up over the range matched by
+/// `match_stage`, or over the page window of a cursor request, for `meta`,
+/// with the per-pool breakdown in `pools` when `pools` is set. Empty when
+/// nothing matches.
+pub async fn query_earnings_summary(
+    db: &Mongodb,
+    match_stage: Document,
+    query: &HistoryQueryParams,
+    pools: bool,
+) -> Result<Document, ApiError> {
+    let selection = query.field_selection(&EARNINGS_ROLLUP, &["pools"])?;
+    let fields: Vec<&RollupField> = selection.rollup(&EARNINGS_ROLLUP).collect();
+    let mut pipeline = summary_pipeline(
+        match_stage.clone(),
+        query,
+        &fields,
+        &EarningsHistory::AMOUNT_FIELDS,
+    )?;
+    if pools {
+        pipeline.push(pools_lookup_stage(match_stage));
     }
 
-    let total_count = earnings_collection
-        .count_documents(match_stage.clone(), None)
-        .await
-        .map_err(|e| {
-            error!("Count error: {}", e);
-            actix_web::error::ErrorInternalServerError("Failed to get total count")
-        })?;
-    print!("Total count: {}", total_count);
-
-    let pipeline = vec![
-        doc! { "$match": match_stage },
+    let mut cursor = db.earnings_history.aggregate(pipeline, None).await?;
+    Ok(cursor.try_next().await?.unwrap_or_default())
+}
+
+/// `$lookup` stage that attaches each interval's per-pool breakdown as
+/// `pools`: the pool rows inside the interval (and `match_stage`), rolled up
+/// per pool with `POOL_EARNINGS_ROLLUP`.
+fn pools_lookup_stage(match_stage: Document) -> Document {
+    let mut pool_match = match_stage;
+    pool_match.insert(
+        "$expr",
         doc! {
-            "$group": {
-                "_id": {
-                    "interval_start": {
-                        "$subtract": [
-                            { "$add": ["$end_time", 1] },
-                            { "$mod": [
-                                { "$subtract": ["$end_time", 1] },
-                                seconds_per_interval
-                            ]}
-                        ]
-                    }
-                },
-                "blockRewards": { "$last": "$block_rewards" },
-                "avgNodeCount": { "$last": "$avg_node_count" },
-                "bondingEarnings": { "$last": "$bonding_earnings" },
-                "liquidityEarnings": { "$last": "$liquidity_earnings" },
-                "liquidityFees": { "$last": "$liquidity_fees" },
-                "runePriceUSD": { "$last": "$rune_price_usd" },
-                "earnings_id": { "$last": "$_id" }
-            }
+            "$and": [
+                { "$gte": ["$start_time", "$$start"] },
+                { "$lt": ["$start_time", "$$end"] }
+            ]
         },
-        doc! { "$project": {
-            "_id": 0,
-            "startTime": {
-                "$subtract": [ "$_id.interval_start", { "$mod": [ "$_id.interval_start", seconds_per_interval ] }]
-            },
-            "endTime": {
-                "$add": [
-                    { "$subtract": [ "$_id.interval_start", { "$mod": [ "$_id.interval_start", seconds_per_interval ] }] },
-                    seconds_per_interval
-                ]
-            },
-            "blockRewards": 1,
-            "avgNodeCount": 1,
-            "bondingEarnings": 1,
-            "liquidityEarnings": 1,
-            "liquidityFees": 1,
-            "runePriceUSD": 1,
-            "earnings_id": 1
-        }},
-        doc! { "$sort": { sort_field.clone(): sort_order } },
-        doc! { "$skip": skip },
-        doc! { "$limit": limit },
-    ];
+    );
 
-    let mut cursor = earnings_collection
-        .aggregate(pipeline, None)
-        .await
-        .map_err(|e| {
-            error!("Database error: {}", e);
-            actix_web::error::ErrorInternalServerError("Failed to fetch earnings history")
-        })?;
-
-    let mut intervals = Vec::new();
-    let mut meta = doc! {};
-    let mut count = 0;
-
-    while let Some(mut doc) = cursor.try_next().await.map_err(|e| {
-        error!("Cursor error: {}", e);
-        actix_web::error::ErrorInternalServerError("Failed to process results")
-    })? {
-        for field in [
-            "blockRewards",
-            "avgNodeCount",
-            "bondingEarnings",
-            "liquidityEarnings",
-            "liquidityFees",
-            "runePriceUSD",
-        ]
-        .iter()
-        {
-            if let Some(value) = doc.get(field).and_then(|v| v.as_f64()) {
-                let current_sum = meta.get(field).and_then(|v| v.as_f64()).unwrap_or(0.0);
-                meta.insert(*field, current_sum + value);
-            }
-        }
-        count += 1;
-
-        if let Some(earnings_id) = doc.get("earnings_id") {
-            let pools_filter = doc! {
-                "earnings_summary_id": earnings_id
-            };
-            let mut pools = Vec::new();
-            let mut pools_cursor =
-                pools_collection
-                    .find(pools_filter, None)
-                    .await
-                    .map_err(|e| {
-                        error!("Database error fetching pools: {}", e);
-                        actix_web::error::ErrorInternalServerError("Failed to fetch pools data")
-                    })?;
-
-            while let Some(pool_doc) = pools_cursor.try_next().await.map_err(|e| {
-                error!("Cursor error for pools: {}", e);
-                actix_web::error::ErrorInternalServerError("Failed to process pools results")
-            })? {
-                let pool_data = doc! {
-                    "pool": pool_doc.pool,
-                    "assetLiquidityFees": pool_doc.asset_liquidity_fees,
-                    "runeLiquidityFees": pool_doc.rune_liquidity_fees,
-                    "totalLiquidityFeesRune": pool_doc.total_liquidity_fees_rune,
-                    "saverEarning": pool_doc.saver_earning,
-                    "rewards": pool_doc.rewards
-                };
-                pools.push(pool_data);
-            }
-
-            doc.insert("pools", mongodb::bson::to_bson(&pools).unwrap_or_default());
+    let mut group = doc! { "_id": "$pool" };
+    group.extend(group_accumulators(&POOL_EARNINGS_ROLLUP));
+
+    let mut projection = doc! { "_id": 0, "pool": "$_id" };
+    projection.extend(project_fields(&POOL_EARNINGS_ROLLUP));
+
+    let amount_fields = amount_outputs(&POOL_EARNINGS_ROLLUP, &EarningsHistoryPools::AMOUNT_FIELDS);
+
+    doc! {
+        "$lookup": {
+            "from": "earnings_history_pools",
+            "let": { "start": "$startTime", "end": "$endTime" },
+            "pipeline": [
+                { "$match": pool_match },
+                { "$sort": { "start_time": 1 } },
+                { "$group": group },
+                { "$project": projection },
+                { "$sort": { "pool": 1 } },
+                amounts_to_strings_stage(&amount_fields)
+            ],
+            "as": "pools"
         }
-        doc.remove("earnings_id");
-        intervals.push(doc);
     }
+}
 
-    if intervals.is_empty() {
-        return Ok(HttpResponse::NotFound().json(serde_json::json!({
-            "error": "No earnings history found",
-            "status": 404
-        })));
+#[utoipa::path(
+    get,
+    path = "/api/history/earnings/{pool}",
+    params(
+        ("pool" = String, Path, description = "Pool identifier"),
+        HistoryQueryParams
+    ),
+    responses(
+        (status = 200, description = "Successfully retrieved the pool's earnings history", body = Object),
+        (status = 304, description = "Nothing new since the If-None-Match or If-Modified-Since validators"),
+        (status = 404, description = "No earnings history found for the pool", body = crate::error::ErrorEnvelope),
+        (status = 400, description = "Invalid request parameters", body = crate::error::ErrorEnvelope),
+        (status = 500, description = "Internal server error", body = crate::error::ErrorEnvelope)
+    ),
+    tag = "Earnings History"
+)]
+#[get(
+    "/api/history/earnings/{pool}",
+    wrap = "CacheResponse::new(Series::Earnings)",
+    wrap = "ConditionalGet::new(Series::Earnings)",
+    wrap = "ValidateHistoryQuery::new(&POOL_EARNINGS_ROLLUP)"
+)]
+pub async fn get_pool_earnings_history(
+    req: HttpRequest,
+    path: web::Path<String>,
+    db: web::Data<Mongodb>,
+    query: web::Query<HistoryQueryParams>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = path.into_inner();
+    let interval = query.interval()?;
+    let collection = &db.earnings_history_pools;
+
+    let match_stage = build_match_stage(Some(&pool), &query, interval)?;
+
+    if let Some(format) = ExportFormat::negotiate(&req, query.format.as_deref())? {
+        // The pool rows have no fields besides the rolled-up ones to order
+        // columns by, so they follow the roll-up.
+        let fields = query.field_selection(&POOL_EARNINGS_ROLLUP, &[])?;
+        let mut columns = vec!["startTime", "endTime"];
+        columns.extend(
+            fields
+                .rollup(&POOL_EARNINGS_ROLLUP)
+                .map(|field| field.output),
+        );
+        return export_intervals(
+            collection,
+            pool_earnings_pipeline(match_stage, &query, false)?,
+            format,
+            columns,
+            query.iso_times(),
+        )
+        .await;
     }
 
-    if count > 1 {
-        for field in ["avgNodeCount", "runePriceUSD"].iter() {
-            if let Some(sum) = meta.get(field).and_then(|v| v.as_f64()) {
-                meta.insert(*field, sum / count as f64);
-            }
-        }
+    let cursor = PageCursor::from_query(&query)?;
+    let total_count = total_count(collection, &match_stage, interval, cursor).await?;
+
+    let pipeline = pool_earnings_pipeline(match_stage.clone(), &query, true)?;
+    let results = collection.aggregate(pipeline, None).await?;
+    let mut intervals: Vec<Document> = results.try_collect().await?;
+
+    if intervals.is_empty() {
+        return Err(ApiError::NotFound(
+            "No earnings history found for the pool".to_string(),
+        ));
     }
 
-    let first = intervals.first().unwrap();
-    let last = intervals.last().unwrap();
+    let selection = query.field_selection(&POOL_EARNINGS_ROLLUP, &[])?;
+    let fields: Vec<&RollupField> = selection.rollup(&POOL_EARNINGS_ROLLUP).collect();
+    let pipeline = summary_pipeline(
+        match_stage.clone(),
+        &query,
+        &fields,
+        &EarningsHistoryPools::AMOUNT_FIELDS,
+    )?;
+    let mut summary = collection.aggregate(pipeline, None).await?;
+    let mut meta = summary.try_next().await?.unwrap_or_default();
+    meta.insert("pool", &pool);
 
-    // debug!("First document: {:?}", first);
-    // debug!("Last document: {:?}", last);
+    let pagination = pagination_document(
+        &req,
+        &query,
+        collection,
+        &match_stage,
+        cursor,
+        &intervals,
+        total_count,
+    )
+    .await?;
 
-    meta.insert("startTime", first.get("startTime").unwrap());
-    meta.insert("endTime", last.get("endTime").unwrap());
+    if query.iso_times() {
+        intervals.iter_mut().for_each(render_iso_times);
+        render_iso_times(&mut meta);
+    }
 
     let response = doc! {
         "intervals": &intervals,
         "meta": meta,
-        "pagination": {
-            "currentPage": mongodb::bson::Bson::Int64(page),
-                "totalPages": mongodb::bson::Bson::Int32((total_count as f64 / limit as f64).ceil() as i32),
-                "totalRecords": mongodb::bson::Bson::Int64(total_count as i64),
-                "limit": mongodb::bson::Bson::Int64(limit),
-                "sortBy": sort_field,
-                "order": if sort_order == 1 { "asc" } else { "desc" }
-        }
+        "pagination": pagination
     };
 
     Ok(HttpResponse::Ok().json(response))
 }
+
+/// Earnings aggregation of one pool for `query`. Exports pass
+/// `paginate = false` to get every interval in the range instead of one page.
+fn pool_earnings_pipeline(
+    match_stage: Document,
+    query: &HistoryQueryParams,
+    paginate: bool,
+) -> Result<Vec<Document>, ApiError> {
+    let interval = query.interval()?;
+    let cursor = if paginate {
+        PageCursor::from_query(query)?
+    } else {
+        None
+    };
+    let fields = query.field_selection(&POOL_EARNINGS_ROLLUP, &[])?;
+
+    let mut group = doc! {
+        "_id": { "interval_start": bucket_start_expr(interval) }
+    };
+    group.extend(group_accumulators(fields.rollup(&POOL_EARNINGS_ROLLUP)));
+
+    let mut projection = doc! {
+        "_id": 0,
+        "startTime": "$_id.interval_start",
+        "endTime": bucket_end_expr(interval, "$_id.interval_start")
+    };
+    projection.extend(project_fields(fields.rollup(&POOL_EARNINGS_ROLLUP)));
+
+    let amount_fields = amount_outputs(
+        fields.rollup(&POOL_EARNINGS_ROLLUP),
+        &EarningsHistoryPools::AMOUNT_FIELDS,
+    );
+
+    let mut pipeline = vec![
+        doc! { "$match": cursor_match_stage(match_stage, query, cursor)? },
+        doc! { "$sort": { "start_time": 1 } },
+        doc! { "$group": group },
+        doc! { "$project": projection },
+    ];
+    pipeline.extend(page_stages(query, cursor, paginate));
+    pipeline.push(amounts_to_strings_stage(&amount_fields));
+    Ok(pipeline)
+}
@@ -0,0 +1,145 @@
+use crate::database::db::Mongodb;
+use crate::error::{ApiError, ServiceError};
+use crate::services::parquet_export::{export_parquet, ExportCollection, ExportFilter};
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::web::Bytes;
+use actix_web::{get, web, HttpResponse};
+use futures_util::{stream, Stream};
+use log::warn;
+use serde::Deserialize;
+use std::io::{self, Seek, SeekFrom};
+use std::{env, fs};
+use tokio::io::AsyncReadExt;
+use utoipa::IntoParams;
+
+/// Longest range one Parquet download may cover. The range bounds the rows a
+/// request reads and the temporary file it fills; the `export-parquet`
+/// command has no limit.
+const MAX_PARQUET_SPAN_SECS: i64 = 31 * 86_400;
+
+/// Bytes read from the temporary file per body chunk.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Opens a new temporary file and removes its name right away, so the file
+/// is gone once the handle is dropped, however the request ends.
+fn anonymous_temp_file() -> io::Result<fs::File> {
+    let path = env::temp_dir().join(format!(
+        "midgaurd-export-{:016x}.parquet",
+        rand::random::<u64>()
+    ));
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    if let Err(e) = fs::remove_file(&path) {
+        warn!(
+            "Failed to remove temporary export {}: {}",
+            path.display(),
+            e
+        );
+    }
+    Ok(file)
+}
+
+/// Streams `file` from its start in `CHUNK_SIZE` chunks.
+fn file_body(file: fs::File) -> impl Stream<Item = io::Result<Bytes>> {
+    stream::unfold(tokio::fs::File::from_std(file), |mut file| async move {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        match file.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(read) => {
+                chunk.truncate(read);
+                Some((Ok(Bytes::from(chunk)), file))
+            }
+            Err(e) => Some((Err(e), file)),
+        }
+    })
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ParquetExportParams {
+    /// Only intervals starting at or after this timestamp (required)
+    pub from: Option<i64>,
+
+    /// Only intervals ending at or before this timestamp, at most 31 days
+    /// after `from` (required)
+    pub to: Option<i64>,
+
+    /// Only intervals of this pool, for collections that have one
+    pub pool: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/export/parquet/{collection}",
+    params(
+        ("collection" = ExportCollection, Path, description = "Collection to export"),
+        ParquetExportParams
+    ),
+    responses(
+        (status = 200, description = "Parquet file typed after the collection's model", content_type = "application/vnd.apache.parquet"),
+        (status = 400, description = "Missing from or to, a range over 31 days, or a pool given for a collection without pools", body = crate::error::ErrorEnvelope),
+        (status = 500, description = "Internal server error", body = crate::error::ErrorEnvelope)
+    ),
+    tag = "Export"
+)]
+#[get("/api/export/parquet/{collection}")]
+pub async fn get_parquet_export(
+    db: web::Data<Mongodb>,
+    path: web::Path<ExportCollection>,
+    query: web::Query<ParquetExportParams>,
+) -> Result<HttpResponse, ApiError> {
+    let collection = path.into_inner();
+    let query = query.into_inner();
+
+    if query.pool.is_some() && !collection.has_pool() {
+        return Err(ApiError::invalid(
+            "pool",
+            format!("{} has no pool field", collection.collection_name()),
+        ));
+    }
+
+    let (Some(from), Some(to)) = (query.from, query.to) else {
+        let param = if query.from.is_none() { "from" } else { "to" };
+        return Err(ApiError::invalid(
+            param,
+            "from and to are required for Parquet downloads",
+        ));
+    };
+    if from >= to {
+        return Err(ApiError::invalid("from", "from must be before to"));
+    }
+    let within_limit = matches!(to.checked_sub(from), Some(span) if span <= MAX_PARQUET_SPAN_SECS);
+    if !within_limit {
+        return Err(ApiError::invalid(
+            "to",
+            "A Parquet download covers at most 31 days; use the export-parquet command for longer ranges",
+        ));
+    }
+
+    let filter = ExportFilter {
+        from: Some(from),
+        to: Some(to),
+        pool: query.pool,
+    };
+
+    // Parquet writes its footer last, so the file is written out before any
+    // of it is sent.
+    let export_error = |e: io::Error| ServiceError::Export(e.to_string());
+    let mut file = anonymous_temp_file().map_err(export_error)?;
+    export_parquet(&db, collection, &filter, &mut file).await?;
+    file.seek(SeekFrom::Start(0)).map_err(export_error)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/vnd.apache.parquet")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!(
+                "{}.parquet",
+                collection.collection_name()
+            ))],
+        })
+        .streaming(file_body(file)))
+}
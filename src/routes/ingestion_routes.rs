@@ -0,0 +1,76 @@
+use crate::database::db::Mongodb;
+use crate::error::ApiError;
+use crate::models::ingestion_checkpoint::Series;
+use crate::models::ingestion_gap::{GapStatus, IngestionGap};
+use actix_web::{get, web, HttpResponse};
+use futures_util::TryStreamExt;
+use mongodb::bson::doc;
+use mongodb::options::FindOptions;
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct GapQueryParams {
+    /// Only gaps of this series (depth, swaps, earnings, runepool_members)
+    pub series: Option<Series>,
+
+    /// Only gaps of this pool
+    pub pool: Option<String>,
+
+    /// Only gaps with this status (open, backfilled, failed, unfillable)
+    pub status: Option<GapStatus>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/ingestion/gaps",
+    params(GapQueryParams),
+    responses(
+        (status = 200, description = "Unresolved gaps and the gaps backfilled by the last gap detection run", body = Vec<IngestionGap>),
+        (status = 500, description = "Internal server error", body = crate::error::ErrorEnvelope)
+    ),
+    tag = "Ingestion"
+)]
+#[get("/api/ingestion/gaps")]
+pub async fn get_ingestion_gaps(
+    db: web::Data<Mongodb>,
+    query: web::Query<GapQueryParams>,
+) -> Result<HttpResponse, ApiError> {
+    let mut filter = doc! {};
+    if let Some(series) = query.series {
+        filter.insert("series", series.as_str());
+    }
+    if let Some(ref pool) = query.pool {
+        filter.insert("pool", pool);
+    }
+    if let Some(status) = query.status {
+        filter.insert(
+            "status",
+            mongodb::bson::to_bson(&status).unwrap_or_default(),
+        );
+    }
+
+    let options = FindOptions::builder()
+        .sort(doc! { "series": 1, "pool": 1, "from": 1 })
+        .build();
+
+    let cursor = db.ingestion_gaps.find(filter, options).await?;
+    let gaps: Vec<IngestionGap> = cursor.try_collect().await?;
+
+    let unresolved: Vec<&IngestionGap> = gaps
+        .iter()
+        .filter(|gap| gap.status != GapStatus::Backfilled)
+        .collect();
+    let missing_intervals: i64 = unresolved.iter().map(|gap| gap.missing_intervals).sum();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "gaps": gaps,
+        "meta": {
+            "totalGaps": gaps.len(),
+            "unresolvedGaps": unresolved.len(),
+            "missingIntervals": missing_intervals,
+            "complete": unresolved.is_empty()
+        }
+    })))
+}
@@ -0,0 +1,452 @@
+use crate::database::db::Mongodb;
+use crate::error::{ApiError, ServiceError};
+use crate::middleware::conditional_get::ConditionalGet;
+use crate::middleware::middleware::ValidateHistoryQuery;
+use crate::middleware::response_cache::CacheResponse;
+use crate::models::ingestion_checkpoint::Series;
+use crate::routes::depth_history_routes::{
+    query_depth_intervals, query_depth_summary, DEPTH_ROLLUP,
+};
+use crate::routes::earning_history_route::{
+    query_earnings_intervals, query_earnings_summary, EARNINGS_ROLLUP,
+};
+use crate::routes::queries::{HistoryQueryParams, MidgardQueryParams};
+use crate::routes::rune_pool_history_route::{
+    query_runepool_intervals, query_runepool_summary, RUNEPOOL_ROLLUP,
+};
+use crate::routes::swaps_history_routes::{
+    build_swaps_match_stage, query_swaps_intervals, query_swaps_summary, SWAPS_ROLLUP,
+};
+use crate::utils::rollup::RollupField;
+use crate::utils::{build_match_stage, parse_amount};
+use actix_web::{get, web, HttpResponse};
+use mongodb::bson::{Bson, Document};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Per-pool earnings fields in Midgard's response, besides `pool`.
+const POOL_EARNINGS_FIELDS: [&str; 6] = [
+    "assetLiquidityFees",
+    "earnings",
+    "rewards",
+    "runeLiquidityFees",
+    "saverEarning",
+    "totalLiquidityFeesRune",
+];
+
+/// Formats a float as the shortest decimal that parses back to `value`, never
+/// in exponent notation. Values that are not finite come out as `"0"`.
+fn float_string(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "0".to_string()
+    }
+}
+
+/// Midgard encodes every number as a string.
+fn midgard_string(value: &Bson) -> String {
+    match value {
+        Bson::String(value) => value.clone(),
+        Bson::Int32(value) => value.to_string(),
+        Bson::Int64(value) => value.to_string(),
+        Bson::Double(value) => float_string(*value),
+        _ => "0".to_string(),
+    }
+}
+
+/// An amount rendered by `amounts_to_strings_stage`, or zero when `document`
+/// does not have the field.
+fn amount(document: &Document, field: &str) -> Result<i128, ServiceError> {
+    match document.get_str(field) {
+        Ok(value) => parse_amount(field, value),
+        Err(_) => Ok(0),
+    }
+}
+
+fn number(document: &Document, field: &str) -> f64 {
+    match document.get(field) {
+        Some(Bson::Double(value)) => *value,
+        Some(Bson::Int32(value)) => *value as f64,
+        Some(Bson::Int64(value)) => *value as f64,
+        Some(Bson::String(value)) => value.parse().unwrap_or_default(),
+        _ => 0.0,
+    }
+}
+
+/// Picks `startTime`, `endTime` and the rolled-up `fields` of `document` as
+/// Midgard strings. Keys come out sorted, like Midgard's generated structs.
+fn midgard_fields(document: &Document, fields: &[RollupField]) -> BTreeMap<String, Value> {
+    let mut object = BTreeMap::new();
+    for field in ["startTime", "endTime"]
+        .into_iter()
+        .chain(fields.iter().map(|field| field.output))
+    {
+        let value = document.get(field).unwrap_or(&Bson::Null);
+        object.insert(field.to_string(), Value::String(midgard_string(value)));
+    }
+    object
+}
+
+/// `startTime` and `endTime` of the range `summary` covers, or the requested
+/// range when nothing matched.
+fn range_times(summary: &Document, query: &HistoryQueryParams) -> Document {
+    let mut range = Document::new();
+    let start = summary
+        .get("startTime")
+        .cloned()
+        .unwrap_or(Bson::Int64(query.from.unwrap_or_default()));
+    let end = summary
+        .get("endTime")
+        .cloned()
+        .unwrap_or(Bson::Int64(query.to.unwrap_or_default()));
+    range.insert("startTime", start);
+    range.insert("endTime", end);
+    range
+}
+
+fn midgard_response(
+    intervals: Vec<BTreeMap<String, Value>>,
+    meta: BTreeMap<String, Value>,
+) -> Value {
+    let mut response = BTreeMap::new();
+    response.insert(
+        "intervals",
+        serde_json::to_value(intervals).unwrap_or_default(),
+    );
+    response.insert("meta", serde_json::to_value(meta).unwrap_or_default());
+    serde_json::to_value(response).unwrap_or_default()
+}
+
+/// Midgard's `meta`, which carries the same fields as an interval over the
+/// whole range.
+fn summary_meta(
+    mut summary: Document,
+    fields: &[RollupField],
+    query: &HistoryQueryParams,
+) -> BTreeMap<String, Value> {
+    let range = range_times(&summary, query);
+    summary.extend(range);
+    midgard_fields(&summary, fields)
+}
+
+fn depth_meta(summary: &Document, query: &HistoryQueryParams) -> BTreeMap<String, Value> {
+    // Without a first price or LUVI there is nothing to compare against, so
+    // the range reports no loss and no increase rather than a division by 0.
+    let first_price = number(summary, "startAssetPrice");
+    let price_shift_loss = if first_price > 0.0 {
+        let price_ratio = number(summary, "endAssetPrice") / first_price;
+        2.0 * price_ratio.sqrt() / (1.0 + price_ratio)
+    } else {
+        1.0
+    };
+    let first_luvi = number(summary, "startLuvi");
+    let luvi_increase = if first_luvi > 0.0 {
+        number(summary, "endLuvi") / first_luvi
+    } else {
+        1.0
+    };
+
+    let mut meta = BTreeMap::new();
+    for key in [
+        "startAssetDepth",
+        "endAssetDepth",
+        "startRuneDepth",
+        "endRuneDepth",
+        "startLPUnits",
+        "endLPUnits",
+        "startMemberCount",
+        "endMemberCount",
+        "startSynthUnits",
+        "endSynthUnits",
+    ] {
+        let value = summary.get(key).unwrap_or(&Bson::Null);
+        meta.insert(key.to_string(), Value::String(midgard_string(value)));
+    }
+    for (key, value) in range_times(summary, query) {
+        meta.insert(key, Value::String(midgard_string(&value)));
+    }
+    meta.insert(
+        "priceShiftLoss".to_string(),
+        Value::String(float_string(price_shift_loss)),
+    );
+    meta.insert(
+        "luviIncrease".to_string(),
+        Value::String(float_string(luvi_increase)),
+    );
+    meta
+}
+
+fn runepool_meta(summary: &Document, query: &HistoryQueryParams) -> BTreeMap<String, Value> {
+    let mut meta = BTreeMap::new();
+    for key in ["startCount", "endCount", "startUnits", "endUnits"] {
+        let value = summary.get(key).unwrap_or(&Bson::Null);
+        meta.insert(key.to_string(), Value::String(midgard_string(value)));
+    }
+    for (key, value) in range_times(summary, query) {
+        meta.insert(key, Value::String(midgard_string(&value)));
+    }
+    meta
+}
+
+/// Midgard's per-pool earnings entry.
+fn midgard_pool(pool: &str, totals: &[i128; POOL_EARNINGS_FIELDS.len()]) -> Value {
+    let mut object = BTreeMap::new();
+    object.insert("pool".to_string(), Value::String(pool.to_string()));
+    for (field, total) in POOL_EARNINGS_FIELDS.iter().zip(totals) {
+        object.insert(field.to_string(), Value::String(total.to_string()));
+    }
+    serde_json::to_value(object).unwrap_or_default()
+}
+
+/// Sums the per-pool breakdown of `intervals` per pool, keeping the order in
+/// which pools first appear.
+fn midgard_pools(intervals: &[&Document]) -> Result<Value, ServiceError> {
+    let mut pools: Vec<(String, [i128; POOL_EARNINGS_FIELDS.len()])> = Vec::new();
+    for interval in intervals {
+        let Ok(interval_pools) = interval.get_array("pools") else {
+            continue;
+        };
+        for pool in interval_pools.iter().filter_map(Bson::as_document) {
+            let name = pool.get_str("pool").unwrap_or_default();
+            let index = match pools.iter().position(|(existing, _)| existing == name) {
+                Some(index) => index,
+                None => {
+                    pools.push((name.to_string(), [0; POOL_EARNINGS_FIELDS.len()]));
+                    pools.len() - 1
+                }
+            };
+            for (total, field) in pools[index].1.iter_mut().zip(POOL_EARNINGS_FIELDS) {
+                *total += amount(pool, field)?;
+            }
+        }
+    }
+
+    Ok(Value::Array(
+        pools
+            .iter()
+            .map(|(pool, totals)| midgard_pool(pool, totals))
+            .collect(),
+    ))
+}
+
+/// An earnings interval, or the `meta` summary, in Midgard's layout.
+/// `earnings` is bonding plus liquidity earnings.
+fn midgard_earnings(
+    summary: &Document,
+    pools: &[&Document],
+) -> Result<BTreeMap<String, Value>, ServiceError> {
+    let mut object = midgard_fields(summary, &EARNINGS_ROLLUP);
+    let earnings = amount(summary, "bondingEarnings")? + amount(summary, "liquidityEarnings")?;
+    object.insert("earnings".to_string(), Value::String(earnings.to_string()));
+    object.insert("pools".to_string(), midgard_pools(pools)?);
+    Ok(object)
+}
+
+#[utoipa::path(
+    get,
+    path = "/v2/history/depths/{pool}",
+    params(
+        ("pool" = String, Path, description = "Pool identifier"),
+        MidgardQueryParams
+    ),
+    responses(
+        (status = 200, description = "Depth history in Midgard's response format", body = Object),
+        (status = 500, description = "Internal server error", body = crate::error::ErrorEnvelope)
+    ),
+    tag = "Midgard Compatible"
+)]
+#[get(
+    "/v2/history/depths/{pool}",
+    wrap = "CacheResponse::new(Series::Depth)",
+    wrap = "ConditionalGet::new(Series::Depth)",
+    wrap = "ValidateHistoryQuery::new(&DEPTH_ROLLUP)"
+)]
+pub async fn get_midgard_depth_history(
+    path: web::Path<String>,
+    db: web::Data<Mongodb>,
+    query: web::Query<MidgardQueryParams>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = path.into_inner();
+    let history_query = query.to_history_query()?;
+    let interval = history_query.interval()?;
+
+    let match_stage = build_match_stage(Some(&pool), &history_query, interval)?;
+    // A summary lists no intervals, only the `meta` over the whole range.
+    let intervals = if query.is_summary() {
+        Vec::new()
+    } else {
+        query_depth_intervals(&db, match_stage.clone(), &history_query).await?
+    };
+    let summary = query_depth_summary(&db, match_stage, &history_query).await?;
+
+    let response = midgard_response(
+        intervals
+            .iter()
+            .map(|interval| midgard_fields(interval, &DEPTH_ROLLUP))
+            .collect(),
+        depth_meta(&summary, &history_query),
+    );
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v2/history/swaps",
+    params(MidgardQueryParams),
+    responses(
+        (status = 200, description = "Swaps history in Midgard's response format", body = Object),
+        (status = 500, description = "Internal server error", body = crate::error::ErrorEnvelope)
+    ),
+    tag = "Midgard Compatible"
+)]
+#[get(
+    "/v2/history/swaps",
+    wrap = "CacheResponse::new(Series::Swaps)",
+    wrap = "ConditionalGet::new(Series::Swaps)",
+    wrap = "ValidateHistoryQuery::new(&SWAPS_ROLLUP)"
+)]
+pub async fn get_midgard_swaps_history(
+    db: web::Data<Mongodb>,
+    query: web::Query<MidgardQueryParams>,
+) -> Result<HttpResponse, ApiError> {
+    let history_query = query.to_history_query()?;
+
+    let match_stage = build_swaps_match_stage(&history_query)?;
+    // A summary lists no intervals, only the `meta` over the whole range.
+    let intervals = if query.is_summary() {
+        Vec::new()
+    } else {
+        query_swaps_intervals(&db, match_stage.clone(), &history_query).await?
+    };
+    let summary = query_swaps_summary(&db, match_stage, &history_query).await?;
+
+    let response = midgard_response(
+        intervals
+            .iter()
+            .map(|interval| midgard_fields(interval, &SWAPS_ROLLUP))
+            .collect(),
+        summary_meta(summary, &SWAPS_ROLLUP, &history_query),
+    );
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v2/history/earnings",
+    params(MidgardQueryParams),
+    responses(
+        (status = 200, description = "Earnings history in Midgard's response format", body = Object),
+        (status = 500, description = "Internal server error", body = crate::error::ErrorEnvelope)
+    ),
+    tag = "Midgard Compatible"
+)]
+#[get(
+    "/v2/history/earnings",
+    wrap = "CacheResponse::new(Series::Earnings)",
+    wrap = "ConditionalGet::new(Series::Earnings)",
+    wrap = "ValidateHistoryQuery::new(&EARNINGS_ROLLUP)"
+)]
+pub async fn get_midgard_earnings_history(
+    db: web::Data<Mongodb>,
+    query: web::Query<MidgardQueryParams>,
+) -> Result<HttpResponse, ApiError> {
+    let history_query = query.to_history_query()?;
+
+    let match_stage = build_match_stage(None, &history_query, history_query.interval()?)?;
+    // A summary lists no intervals, only the `meta` over the whole range.
+    let intervals = if query.is_summary() {
+        Vec::new()
+    } else {
+        query_earnings_intervals(&db, match_stage.clone(), &history_query).await?
+    };
+    let mut summary = query_earnings_summary(&db, match_stage, &history_query, true).await?;
+    let range = range_times(&summary, &history_query);
+    summary.extend(range);
+
+    let response = midgard_response(
+        intervals
+            .iter()
+            .map(|interval| midgard_earnings(interval, &[interval]))
+            .collect::<Result<_, _>>()?,
+        midgard_earnings(&summary, &[&summary])?,
+    );
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v2/history/runepool",
+    params(MidgardQueryParams),
+    responses(
+        (status = 200, description = "RUNEPool history in Midgard's response format", body = Object),
+        (status = 500, description = "Internal server error", body = crate::error::ErrorEnvelope)
+    ),
+    tag = "Midgard Compatible"
+)]
+#[get(
+    "/v2/history/runepool",
+    wrap = "CacheResponse::new(Series::RunepoolMembers)",
+    wrap = "ConditionalGet::new(Series::RunepoolMembers)",
+    wrap = "ValidateHistoryQuery::new(&RUNEPOOL_ROLLUP)"
+)]
+pub async fn get_midgard_runepool_history(
+    db: web::Data<Mongodb>,
+    query: web::Query<MidgardQueryParams>,
+) -> Result<HttpResponse, ApiError> {
+    let history_query = query.to_history_query()?;
+
+    let match_stage = build_match_stage(None, &history_query, history_query.interval()?)?;
+    // A summary lists no intervals, only the `meta` over the whole range.
+    let intervals = if query.is_summary() {
+        Vec::new()
+    } else {
+        query_runepool_intervals(&db, match_stage.clone(), &history_query).await?
+    };
+    let summary = query_runepool_summary(&db, match_stage, &history_query).await?;
+
+    // Midgard's RUNEPool intervals only carry the member count and units.
+    let response = midgard_response(
+        intervals
+            .iter()
+            .map(|interval| midgard_fields(interval, &RUNEPOOL_ROLLUP[..2]))
+            .collect(),
+        runepool_meta(&summary, &history_query),
+    );
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    #[test]
+    fn float_string_is_plain_decimal() {
+        assert_eq!(float_string(0.5), "0.5");
+        assert_eq!(float_string(1.0), "1");
+        assert_eq!(float_string(1e21), "1000000000000000000000");
+        assert_eq!(float_string(1e-7), "0.0000001");
+        assert_eq!(float_string(f64::NAN), "0");
+    }
+
+    #[test]
+    fn depth_meta_guards_a_zero_first_value() {
+        let summary = doc! {
+            "startTime": 0_i64,
+            "endTime": 7200_i64,
+            "startAssetPrice": 0.0,
+            "endAssetPrice": 2.0,
+            "startLuvi": 0.0,
+            "endLuvi": 1.5,
+        };
+        let meta = depth_meta(&summary, &HistoryQueryParams::default());
+        assert_eq!(meta["priceShiftLoss"], Value::String("1".to_string()));
+        assert_eq!(meta["luviIncrease"], Value::String("1".to_string()));
+        assert_eq!(meta["endTime"], Value::String("7200".to_string()));
+    }
+}
@@ -1,5 +1,9 @@
+pub mod admin_routes;
 pub mod depth_history_routes;
 pub mod earning_history_route;
+pub mod export_routes;
+pub mod ingestion_routes;
+pub mod midgard_routes;
 pub mod queries;
 pub mod rune_pool_history_route;
 pub mod swaps_history_routes;
@@ -1,7 +1,13 @@
+use crate::error::ApiError;
+use crate::services::scheduler::INGESTION_INTERVAL;
+use crate::utils::buckets::{bucket_start, default_from};
+use crate::utils::rollup::RollupField;
+use crate::utils::time::deserialize_time;
+use chrono::Utc;
 use serde::Deserialize;
 use utoipa::{IntoParams, ToSchema};
 
-#[derive(Debug, Deserialize, IntoParams, ToSchema)]
+#[derive(Debug, Default, Deserialize, IntoParams, ToSchema)]
 #[into_params(parameter_in = Query)]
 pub struct HistoryQueryParams {
     /// Time interval for data grouping (5min, hour, day, week, month, quarter, year)
@@ -11,37 +17,422 @@ pub struct HistoryQueryParams {
     #[param(minimum = 1, maximum = 400)]
     pub count: Option<i32>,
 
-    /// Start timestamp
+    /// Pool identifier (e.g. BTC.BTC); omit for the network-wide series
+    pub pool: Option<String>,
+
+    /// Start time: Unix seconds, ISO-8601, a date (2024-06-01), now, or an offset from now (-7d)
+    #[serde(default, deserialize_with = "deserialize_time")]
+    #[param(value_type = Option<String>, example = "-7d")]
+    #[schema(value_type = Option<String>, example = "-7d")]
     pub from: Option<i64>,
 
-    /// End timestamp
+    /// End time, in the same forms as `from`
+    #[serde(default, deserialize_with = "deserialize_time")]
+    #[param(value_type = Option<String>, example = "now")]
+    #[schema(value_type = Option<String>, example = "now")]
     pub to: Option<i64>,
 
-    /// Page number for pagination
-    #[param(minimum = 1)]
+    /// Page number for pagination (1-1000000); ignored when `cursor` is given
+    #[param(minimum = 1, maximum = 1000000)]
     pub page: Option<i64>,
 
     /// Records per page
     #[param(minimum = 1, maximum = 400)]
     pub limit: Option<i64>,
 
+    /// Opaque cursor from a previous response's `nextCursor` or `prevCursor`
+    pub cursor: Option<String>,
+
+    /// Comma-separated interval fields to return, e.g. totalVolumeUSD,totalFees; all by default
+    pub fields: Option<String>,
+
     /// Field to sort by
     pub sort_by: Option<String>,
 
     /// Sort order (asc or desc)
     #[param(value_type = String, example = "asc")]
     pub order: Option<String>,
+
+    /// Response format (json, csv or ndjson); defaults to the `Accept` header, then json
+    pub format: Option<String>,
+
+    /// How startTime and endTime are rendered: unix (seconds, the default) or iso
+    #[param(value_type = Option<String>, example = "iso")]
+    pub time_format: Option<String>,
+}
+
+impl HistoryQueryParams {
+    /// The requested interval, `hour` when none is given.
+    pub fn interval(&self) -> Result<&str, ApiError> {
+        let interval = self.interval.as_deref().unwrap_or("hour");
+        if !validate_interval(interval) {
+            return Err(ApiError::invalid(
+                "interval",
+                format!(
+                    "Invalid interval '{}', expected one of: {}",
+                    interval,
+                    INTERVALS.join(", ")
+                ),
+            ));
+        }
+        Ok(interval)
+    }
+
+    /// Bounds of the `start_time` and `end_time` the query reads, `None`
+    /// where the range is open. Relative times were resolved when the query
+    /// was parsed; a missing `from` covers the last `count` intervals before
+    /// `to`, or now, except for cursor pages, which span the whole history.
+    pub fn time_range(&self, interval: &str) -> (Option<i64>, Option<i64>) {
+        let from = match self.from {
+            Some(from) => Some(from),
+            None if self.cursor.is_none() => {
+                let count = self.count.unwrap_or(400) as i64;
+                Some(default_from(interval, count, self.to))
+            }
+            None => None,
+        };
+        (from, self.to)
+    }
+
+    /// Whether `startTime` and `endTime` are rendered as ISO-8601 strings.
+    pub fn iso_times(&self) -> bool {
+        self.time_format.as_deref() == Some("iso")
+    }
+
+    /// Checks the parameters every history route shares. `sort_fields` are
+    /// the interval fields of the route, which can be sorted by besides
+    /// `startTime` and `endTime`.
+    pub fn validate<'a>(
+        &self,
+        sort_fields: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), ApiError> {
+        self.interval()?;
+        if let Some(count) = self.count {
+            if !validate_count(count) {
+                return Err(ApiError::invalid(
+                    "count",
+                    "count must be between 1 and 400",
+                ));
+            }
+        }
+        if let Some(limit) = self.limit {
+            if !(1..=400).contains(&limit) {
+                return Err(ApiError::invalid(
+                    "limit",
+                    "limit must be between 1 and 400",
+                ));
+            }
+        }
+        if let Some(page) = self.page {
+            if !(1..=MAX_PAGE).contains(&page) {
+                return Err(ApiError::invalid(
+                    "page",
+                    format!("page must be between 1 and {}", MAX_PAGE),
+                ));
+            }
+        }
+        if let (Some(from), Some(to)) = (self.from, self.to) {
+            if from > to {
+                return Err(ApiError::invalid("from", "from must not be after to"));
+            }
+        }
+        if let Some(order) = self.order.as_deref() {
+            if !matches!(order, "asc" | "desc") {
+                return Err(ApiError::invalid("order", "order must be asc or desc"));
+            }
+        }
+        if let Some(time_format) = self.time_format.as_deref() {
+            if !matches!(time_format, "unix" | "iso") {
+                return Err(ApiError::invalid(
+                    "time_format",
+                    "time_format must be unix or iso",
+                ));
+            }
+        }
+        if let Some(sort_by) = self.sort_by.as_deref() {
+            let sortable: Vec<&str> = ["startTime", "endTime"]
+                .into_iter()
+                .chain(sort_fields)
+                .collect();
+            if !sortable.contains(&sort_by) {
+                return Err(ApiError::invalid(
+                    "sort_by",
+                    format!(
+                        "Unknown sort field '{}', expected one of: {}",
+                        sort_by,
+                        sortable.join(", ")
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Interval fields requested with `fields`, checked against the route's
+    /// `rollup` fields and any `extra` ones it returns. The sort field is
+    /// always kept so the sort still applies.
+    pub fn field_selection(
+        &self,
+        rollup: &[RollupField],
+        extra: &[&str],
+    ) -> Result<FieldSelection, ApiError> {
+        let Some(fields) = self.fields.as_deref() else {
+            return Ok(FieldSelection(None));
+        };
+
+        let known: Vec<&str> = rollup
+            .iter()
+            .map(|field| field.output)
+            .chain(extra.iter().copied())
+            .collect();
+        let mut selected = Vec::new();
+        for field in fields.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            if !known.contains(&field) {
+                return Err(ApiError::invalid(
+                    "fields",
+                    format!(
+                        "Unknown field '{}', expected one of: {}",
+                        field,
+                        known.join(", ")
+                    ),
+                ));
+            }
+            selected.push(field.to_string());
+        }
+        if let Some(sort_by) = self.sort_by.as_deref() {
+            if known.contains(&sort_by) {
+                selected.push(sort_by.to_string());
+            }
+        }
+        Ok(FieldSelection(Some(selected)))
+    }
 }
 
-#[allow(unused)]
+/// Interval fields a history request returns; `None` means all of them.
+#[derive(Debug)]
+pub struct FieldSelection(Option<Vec<String>>);
+
+impl FieldSelection {
+    /// Whether `field` is returned. `startTime` and `endTime` always are.
+    pub fn includes(&self, field: &str) -> bool {
+        match &self.0 {
+            None => true,
+            Some(selected) => {
+                matches!(field, "startTime" | "endTime") || selected.iter().any(|s| s == field)
+            }
+        }
+    }
+
+    /// The returned fields among `fields`.
+    pub fn retain<'a>(&self, fields: &[&'a str]) -> Vec<&'a str> {
+        fields
+            .iter()
+            .copied()
+            .filter(|field| self.includes(field))
+            .collect()
+    }
+
+    /// The returned fields among `rollup`.
+    pub fn rollup<'a>(
+        &'a self,
+        rollup: &'a [RollupField],
+    ) -> impl Iterator<Item = &'a RollupField> + 'a {
+        rollup.iter().filter(|field| self.includes(field.output))
+    }
+}
+
+/// Query parameters of Midgard's own `/v2/history/*` endpoints.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MidgardQueryParams {
+    /// Time interval for data grouping (5min, hour, day, week, month, quarter, year).
+    /// Without it, only `meta` is returned, summarizing the whole range.
+    pub interval: Option<String>,
+
+    /// Number of intervals to return (1-400), ignored without `interval`
+    #[param(minimum = 1, maximum = 400)]
+    pub count: Option<i32>,
+
+    /// Pool identifier (e.g. BTC.BTC), for endpoints that take it as a query parameter
+    pub pool: Option<String>,
+
+    /// Start timestamp
+    pub from: Option<i64>,
+
+    /// End timestamp
+    pub to: Option<i64>,
+}
+
+impl MidgardQueryParams {
+    /// Like Midgard, a request without `interval` gets a single summary of
+    /// the range in `meta` and no intervals.
+    pub fn is_summary(&self) -> bool {
+        self.interval.is_none()
+    }
+
+    /// The equivalent query for our own routes: a single ascending page
+    /// holding every requested interval. A summary rolls up the stored
+    /// intervals of its range, which ends at `summary_end` unless `to` is
+    /// given and spans `MAX_SUMMARY_SPAN_SECS` unless `from` is given; longer
+    /// ranges are rejected so a request cannot read a whole series.
+    pub fn to_history_query(&self) -> Result<HistoryQueryParams, ApiError> {
+        let (interval, from, to) = if self.is_summary() {
+            let to = self
+                .to
+                .unwrap_or_else(|| summary_end(Utc::now().timestamp()));
+            let from = match self.from {
+                Some(from) => from,
+                None => to
+                    .checked_sub(MAX_SUMMARY_SPAN_SECS)
+                    .ok_or_else(|| ApiError::invalid("to", "to is out of range"))?,
+            };
+            let within_limit =
+                matches!(to.checked_sub(from), Some(span) if span <= MAX_SUMMARY_SPAN_SECS);
+            if !within_limit {
+                return Err(ApiError::invalid(
+                    "from",
+                    "A summary covers at most 366 days; pass an interval for longer ranges",
+                ));
+            }
+            (Some(INGESTION_INTERVAL.to_string()), Some(from), Some(to))
+        } else {
+            (self.interval.clone(), self.from, self.to)
+        };
+        Ok(HistoryQueryParams {
+            interval,
+            count: self.count,
+            pool: self.pool.clone(),
+            from,
+            to,
+            page: Some(1),
+            limit: Some(self.count.unwrap_or(400) as i64),
+            cursor: None,
+            fields: None,
+            sort_by: None,
+            order: None,
+            format: None,
+            time_format: None,
+        })
+    }
+}
+
+/// Default end of a `/v2` summary requested at `now`: the start of the
+/// current ingestion interval. Stored intervals end on its boundaries, so the
+/// range reads the same intervals as one ending at `now`, but stays the same
+/// until the next interval starts and can be cached and tagged meanwhile.
+pub fn summary_end(now: i64) -> i64 {
+    bucket_start(INGESTION_INTERVAL, now).unwrap_or(now)
+}
+
+/// Longest range a `/v2` summary may cover. Summaries roll up every stored
+/// interval of the range on each request.
+pub const MAX_SUMMARY_SPAN_SECS: i64 = 366 * 86_400;
+
+/// Highest `page` accepted. Far past the end of any series, and low enough
+/// that the `$skip` computed from it cannot overflow.
+pub const MAX_PAGE: i64 = 1_000_000;
+
+/// Interval names accepted by the history routes.
+pub const INTERVALS: [&str; 7] = ["5min", "hour", "day", "week", "month", "quarter", "year"];
+
 pub fn validate_interval(interval: &str) -> bool {
-    matches!(
-        interval,
-        "5min" | "hour" | "day" | "week" | "month" | "quarter" | "year"
-    )
+    INTERVALS.contains(&interval)
 }
 
-#[allow(unused)]
 pub fn validate_count(count: i32) -> bool {
     count > 0 && count <= 400
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::handle_pagination_and_sorting;
+
+    fn page_query(page: i64) -> HistoryQueryParams {
+        HistoryQueryParams {
+            page: Some(page),
+            limit: Some(400),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn time_range_resolves_the_default_from() {
+        let query = HistoryQueryParams {
+            count: Some(2),
+            to: Some(1_706_745_600),
+            ..Default::default()
+        };
+        assert_eq!(
+            query.time_range("day"),
+            (Some(1_706_745_600 - 2 * 86_400), Some(1_706_745_600))
+        );
+
+        let query = HistoryQueryParams {
+            cursor: Some("next".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(query.time_range("day"), (None, None));
+    }
+
+    fn summary_query(from: Option<i64>, to: Option<i64>) -> MidgardQueryParams {
+        MidgardQueryParams {
+            interval: None,
+            count: None,
+            pool: None,
+            from,
+            to,
+        }
+    }
+
+    #[test]
+    fn summary_span_rejects_overflowing_bounds() {
+        let error = summary_query(None, Some(i64::MIN))
+            .to_history_query()
+            .unwrap_err();
+        assert_eq!(error.param(), Some("to"));
+
+        let error = summary_query(Some(i64::MIN), Some(1))
+            .to_history_query()
+            .unwrap_err();
+        assert_eq!(error.param(), Some("from"));
+
+        let query = summary_query(Some(0), Some(MAX_SUMMARY_SPAN_SECS))
+            .to_history_query()
+            .unwrap();
+        assert_eq!(
+            (query.from, query.to),
+            (Some(0), Some(MAX_SUMMARY_SPAN_SECS))
+        );
+    }
+
+    #[test]
+    fn summary_end_holds_within_an_ingestion_interval() {
+        // 2024-02-01T00:00:00Z
+        let hour = 1_706_745_600;
+        assert_eq!(summary_end(hour), hour);
+        assert_eq!(summary_end(hour + 1), hour);
+        assert_eq!(summary_end(hour + 3_599), hour);
+        assert_eq!(summary_end(hour + 3_600), hour + 3_600);
+    }
+
+    #[test]
+    fn rejects_pages_out_of_range() {
+        for page in [0, -1, MAX_PAGE + 1, i64::MAX] {
+            let error = page_query(page).validate([]).unwrap_err();
+            assert_eq!(error.param(), Some("page"));
+        }
+        assert!(page_query(1).validate([]).is_ok());
+        assert!(page_query(MAX_PAGE).validate([]).is_ok());
+    }
+
+    #[test]
+    fn skip_saturates_instead_of_overflowing() {
+        let (_, skip, ..) = handle_pagination_and_sorting(&page_query(MAX_PAGE));
+        assert_eq!(skip, (MAX_PAGE - 1) * 400);
+        let (_, skip, ..) = handle_pagination_and_sorting(&page_query(i64::MAX));
+        assert_eq!(skip, i64::MAX);
+        let (_, skip, ..) = handle_pagination_and_sorting(&page_query(i64::MIN));
+        assert_eq!(skip, 0);
+    }
+}
@@ -1,11 +1,47 @@
 use crate::database::db::Mongodb;
+use crate::error::ApiError;
+use crate::middleware::conditional_get::ConditionalGet;
+use crate::middleware::middleware::ValidateHistoryQuery;
+use crate::middleware::response_cache::CacheResponse;
+use crate::models::ingestion_checkpoint::Series;
+use crate::models::runepool_members_units_history::RunePoolTotalMembersHistory;
 use crate::routes::queries::HistoryQueryParams;
-use crate::utils::get_seconds_per_interval;
-use actix_web::{get, web, HttpResponse, Result};
-use chrono::Utc;
+use crate::utils::buckets::{bucket_end_expr, bucket_start_expr};
+use crate::utils::cursor::{
+    cursor_match_stage, page_stages, pagination_document, total_count, PageCursor,
+};
+use crate::utils::export::{export_columns, export_intervals, ExportFormat};
+use crate::utils::rollup::{
+    amount_outputs, group_accumulators, project_fields, summary_pipeline, Rollup, RollupField,
+};
+use crate::utils::time::render_iso_times;
+use crate::utils::{amounts_to_strings_stage, build_match_stage};
+use actix_web::{get, web, HttpRequest, HttpResponse};
 use futures_util::TryStreamExt;
-use log::error;
-use mongodb::bson::doc;
+use mongodb::bson::{doc, Document};
+
+/// Member count, units and depth are stock metrics, so coarser intervals take
+/// the last value.
+pub const RUNEPOOL_ROLLUP: [RollupField; 3] = [
+    RollupField::new("count", "count", Rollup::Last),
+    RollupField::new("units", "units", Rollup::Last),
+    RollupField::new("depth", "depth", Rollup::Last),
+];
+
+/// Opening and closing values of the requested range for `meta`, each with
+/// the interval field that selects it.
+pub const RUNEPOOL_META: [(&str, RollupField); 4] = [
+    (
+        "count",
+        RollupField::new("startCount", "count", Rollup::First),
+    ),
+    ("count", RollupField::new("endCount", "count", Rollup::Last)),
+    (
+        "units",
+        RollupField::new("startUnits", "units", Rollup::First),
+    ),
+    ("units", RollupField::new("endUnits", "units", Rollup::Last)),
+];
 
 #[utoipa::path(
     get,
@@ -13,186 +49,180 @@ use mongodb::bson::doc;
     params(
         ("interval" = Option<String>, Query, description = "Time interval (hour, day, week, etc.)"),
         ("count" = Option<i32>, Query, description = "Number of intervals"),
-        ("from" = Option<i64>, Query, description = "Start timestamp"),
-        ("to" = Option<i64>, Query, description = "End timestamp"),
+        ("from" = Option<String>, Query, description = "Start time: Unix seconds, ISO-8601, a date (2024-06-01), now, or an offset from now (-7d)"),
+        ("to" = Option<String>, Query, description = "End time, in the same forms as from"),
         ("page" = Option<i64>, Query, description = "Page number"),
         ("limit" = Option<i64>, Query, description = "Records per page"),
         ("sort_by" = Option<String>, Query, description = "Field to sort by"),
-        ("order" = Option<String>, Query, description = "Sort order (asc/desc)")
+        ("order" = Option<String>, Query, description = "Sort order (asc/desc)"),
+        ("format" = Option<String>, Query, description = "Response format (json, csv or ndjson)"),
+        ("cursor" = Option<String>, Query, description = "Opaque cursor from nextCursor or prevCursor"),
+        ("fields" = Option<String>, Query, description = "Comma-separated fields to return; startTime and endTime are always included"),
+        ("time_format" = Option<String>, Query, description = "How startTime and endTime are rendered: unix (default) or iso")
     ),
     responses(
         (status = 200, description = "Successfully retrieved runepool history", body = RunepoolMembersUnitsHistory),
-        (status = 404, description = "No runepool history found"),
-        (status = 400, description = "Invalid request parameters")
+        (status = 304, description = "Nothing new since the If-None-Match or If-Modified-Since validators"),
+        (status = 404, description = "No runepool history found", body = crate::error::ErrorEnvelope),
+        (status = 400, description = "Invalid request parameters", body = crate::error::ErrorEnvelope)
     ),
     tag = "Rune Pool History"
 )]
-#[get("/api/history/runepool")]
+#[get(
+    "/api/history/runepool",
+    wrap = "CacheResponse::new(Series::RunepoolMembers)",
+    wrap = "ConditionalGet::new(Series::RunepoolMembers)",
+    wrap = "ValidateHistoryQuery::new(&RUNEPOOL_ROLLUP)"
+)]
 pub async fn get_runepool_history(
+    req: HttpRequest,
     db: web::Data<Mongodb>,
     query: web::Query<HistoryQueryParams>,
-) -> Result<HttpResponse> {
-    match (&query.interval, query.count) {
-        (Some(_), None) | (None, Some(_)) => {
-            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                "error": "Both interval and count must be provided together",
-                "status": 400
-            })));
-        }
-        _ => {}
-    }
-    let seconds_per_interval =
-        get_seconds_per_interval(query.interval.as_deref().unwrap_or("hour"));
+) -> Result<HttpResponse, ApiError> {
+    let interval = query.interval()?;
     let collection = &db.runepool_members_history;
 
-    let mut match_stage = doc! {};
+    let match_stage = build_match_stage(None, &query, query.interval()?)?;
 
-    if let Some(from) = query.from {
-        match_stage.insert("start_time", doc! { "$gte": from });
-    } else {
-        let current_time = Utc::now().timestamp();
-        let count = query.count.unwrap_or(400) as i64;
-        match_stage.insert(
-            "start_time",
-            doc! { "$gte": current_time - (count * seconds_per_interval) },
-        );
-    }
-    if let Some(to) = query.to {
-        match_stage.insert("end_time", doc! { "$lte": to });
+    if let Some(format) = ExportFormat::negotiate(&req, query.format.as_deref())? {
+        let fields = query.field_selection(&RUNEPOOL_ROLLUP, &[])?;
+        let mut columns = export_columns(&RunePoolTotalMembersHistory::FIELDS, &RUNEPOOL_ROLLUP);
+        columns.retain(|column| fields.includes(column));
+        return export_intervals(
+            collection,
+            runepool_intervals_pipeline(match_stage, &query, false)?,
+            format,
+            columns,
+            query.iso_times(),
+        )
+        .await;
     }
 
-    let interval_pipeline = vec![
-        doc! { "$match": match_stage.clone() },
-        doc! {
-            "$group": {
-                "_id": {
-                    "$subtract": [
-                        "$end_time",
-                        { "$mod": ["$end_time", seconds_per_interval] }
-                    ]
-                }
-            }
-        },
-        doc! { "$group": {
-            "_id": null,
-            "count": { "$sum": 1 }
-        }},
-    ];
+    let cursor = PageCursor::from_query(&query)?;
+    let total_count = total_count(collection, &match_stage, interval, cursor).await?;
 
-    let total_intervals = match collection.aggregate(interval_pipeline, None).await {
-        Ok(mut cursor) => {
-            if let Ok(Some(doc)) = cursor.try_next().await.map_err(|e| {
-                error!("Cursor error: {}", e);
-            }) {
-                doc.get_i32("count").unwrap_or(0) as i64
-            } else {
-                0
-            }
-        }
-        Err(e) => {
-            error!("Count error: {}", e);
-            return Err(actix_web::error::ErrorInternalServerError(
-                "Failed to get total count",
-            ));
-        }
-    };
+    let mut intervals = query_runepool_intervals(&db, match_stage.clone(), &query).await?;
 
-    #[allow(unused_variables)]
-    let total_docs = collection
-        .count_documents(match_stage.clone(), None)
-        .await
-        .map_err(|e| {
-            error!("Count error: {}", e);
-            actix_web::error::ErrorInternalServerError("Failed to get total count")
-        })?;
-
-    let page = query.page.unwrap_or(1);
-    let limit = query.limit.unwrap_or(50).min(400);
-    let skip = (page - 1) * limit;
-
-    let sort_field = query.sort_by.as_deref().unwrap_or("startTime");
-    let sort_order = match query.order.as_deref().unwrap_or("asc") {
-        "desc" => -1,
-        _ => 1,
-    };
+    if intervals.is_empty() {
+        return Err(ApiError::NotFound(
+            "No runepool history found for the specified criteria".to_string(),
+        ));
+    }
 
-    let pipeline = vec![
-        doc! { "$match": match_stage },
-        doc! {
-            "$group": {
-                "_id": {
-                    "interval_start": {
-                        "$subtract": [
-                            "$end_time",
-                            { "$mod": ["$end_time", seconds_per_interval] }
-                        ]
-                    }
-                },
-                "count": { "$last": "$count" },
-                "units": { "$last": "$units" },
-                "depth": { "$last": "$depth" }
-            }
-        },
-        doc! { "$project": {
-            "_id": 0,
-            "startTime": "$_id.interval_start",
-            "endTime": { "$add": ["$_id.interval_start", seconds_per_interval] },
-            "count": 1,
-            "units": 1,
-            "depth": 1
-        }},
-        doc! { "$sort": { sort_field: sort_order } },
-        doc! { "$skip": skip },
-        doc! { "$limit": limit },
-    ];
+    let pagination = pagination_document(
+        &req,
+        &query,
+        collection,
+        &match_stage,
+        cursor,
+        &intervals,
+        total_count,
+    )
+    .await?;
 
-    let mut cursor = collection.aggregate(pipeline, None).await.map_err(|e| {
-        error!("Database error: {}", e);
-        actix_web::error::ErrorInternalServerError("Failed to fetch runepool history")
-    })?;
-
-    let mut intervals = Vec::new();
-    while let Some(doc) = cursor.try_next().await.map_err(|e| {
-        error!("Cursor error: {}", e);
-        actix_web::error::ErrorInternalServerError("Failed to process results")
-    })? {
-        intervals.push(doc);
+    // Fields left out with `fields` are left out of the meta too.
+    let mut meta = query_runepool_summary(&db, match_stage, &query).await?;
+    for key in ["startCount", "endCount"] {
+        if let Ok(count) = meta.get_f64(key) {
+            meta.insert(key, count.to_string());
+        }
     }
 
-    if intervals.is_empty() {
-        return Ok(HttpResponse::NotFound().json(serde_json::json!({
-            "error": "No runepool history found for the specified criteria",
-            "status": 404,
-            "meta": {
-                "totalRecords": total_intervals,
-                "currentPage": page,
-                "limit": limit
-            }
-        })));
+    if query.iso_times() {
+        intervals.iter_mut().for_each(render_iso_times);
+        render_iso_times(&mut meta);
     }
 
-    let first = intervals.first().unwrap();
-    let last = intervals.last().unwrap();
-
     let response = doc! {
         "intervals": &intervals,
-        "meta": {
-            "startTime": first.get_i64("startTime").unwrap_or_default(),
-            "endTime": last.get_i64("endTime").unwrap_or_default(),
-            "startCount": first.get_f64("count").unwrap_or_default().to_string(),
-            "endCount": last.get_f64("count").unwrap_or_default().to_string(),
-            "startUnits": first.get_f64("units").unwrap_or_default().to_string(),
-            "endUnits": last.get_f64("units").unwrap_or_default().to_string()
-        },
-        "pagination": {
-            "currentPage": mongodb::bson::Bson::Int64(page),
-            "totalPages": mongodb::bson::Bson::Int64((total_intervals as f64 / limit as f64).ceil() as i64),
-            "totalRecords": mongodb::bson::Bson::Int64(total_intervals as i64),
-            "limit": mongodb::bson::Bson::Int64(limit),
-            "sortBy": sort_field,
-            "order": if sort_order == 1 { "asc" } else { "desc" }
-        }
+        "meta": meta,
+        "pagination": pagination
     };
 
     Ok(HttpResponse::Ok().json(response))
 }
+
+/// RUNEPool aggregation for `query`. Exports pass `paginate = false` to get
+/// every interval in the range instead of one page.
+pub fn runepool_intervals_pipeline(
+    match_stage: Document,
+    query: &HistoryQueryParams,
+    paginate: bool,
+) -> Result<Vec<Document>, ApiError> {
+    let interval = query.interval()?;
+    let cursor = if paginate {
+        PageCursor::from_query(query)?
+    } else {
+        None
+    };
+    let fields = query.field_selection(&RUNEPOOL_ROLLUP, &[])?;
+
+    let mut group = doc! {
+        "_id": { "interval_start": bucket_start_expr(interval) }
+    };
+    group.extend(group_accumulators(fields.rollup(&RUNEPOOL_ROLLUP)));
+
+    let mut projection = doc! {
+        "_id": 0,
+        "startTime": "$_id.interval_start",
+        "endTime": bucket_end_expr(interval, "$_id.interval_start")
+    };
+    projection.extend(project_fields(fields.rollup(&RUNEPOOL_ROLLUP)));
+
+    let mut pipeline = vec![
+        doc! { "$match": cursor_match_stage(match_stage, query, cursor)? },
+        doc! { "$sort": { "start_time": 1 } },
+        doc! { "$group": group },
+        doc! { "$project": projection },
+    ];
+    pipeline.extend(page_stages(query, cursor, paginate));
+    pipeline.push(amounts_to_strings_stage(&amount_outputs(
+        fields.rollup(&RUNEPOOL_ROLLUP),
+        &RunePoolTotalMembersHistory::AMOUNT_FIELDS,
+    )));
+    Ok(pipeline)
+}
+
+/// Bucketed RUNEPool intervals matching `match_stage`, sorted and paginated
+/// as requested by `query`.
+pub async fn query_runepool_intervals(
+    db: &Mongodb,
+    match_stage: Document,
+    query: &HistoryQueryParams,
+) -> Result<Vec<Document>, ApiError> {
+    let pipeline = runepool_intervals_pipeline(match_stage, query, true)?;
+
+    let cursor = db
+        .runepool_members_history
+        .aggregate(pipeline, None)
+        .await?;
+    Ok(cursor.try_collect().await?)
+}
+
+/// `RUNEPOOL_META` over the range matched by `match_stage`, or over the page
+/// window of a cursor request, limited to the selected fields. Empty when
+/// nothing matches.
+pub async fn query_runepool_summary(
+    db: &Mongodb,
+    match_stage: Document,
+    query: &HistoryQueryParams,
+) -> Result<Document, ApiError> {
+    let selection = query.field_selection(&RUNEPOOL_ROLLUP, &[])?;
+    let fields: Vec<&RollupField> = RUNEPOOL_META
+        .iter()
+        .filter(|(field, _)| selection.includes(field))
+        .map(|(_, meta)| meta)
+        .collect();
+    let pipeline = summary_pipeline(
+        match_stage,
+        query,
+        &fields,
+        &RunePoolTotalMembersHistory::AMOUNT_FIELDS,
+    )?;
+
+    let mut cursor = db
+        .runepool_members_history
+        .aggregate(pipeline, None)
+        .await?;
+    Ok(cursor.try_next().await?.unwrap_or_default())
+}
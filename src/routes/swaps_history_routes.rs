@@ -1,11 +1,97 @@
 use crate::database::db::Mongodb;
+use crate::error::ApiError;
+use crate::middleware::conditional_get::ConditionalGet;
+use crate::middleware::middleware::ValidateHistoryQuery;
+use crate::middleware::response_cache::CacheResponse;
+use crate::models::ingestion_checkpoint::Series;
+use crate::models::swaps_history::SwapsHistory;
 use crate::routes::queries::HistoryQueryParams;
-use crate::utils::get_seconds_per_interval;
-use actix_web::{get, web, HttpResponse, Result};
-use chrono::Utc;
+use crate::utils::buckets::{bucket_end_expr, bucket_start_expr};
+use crate::utils::cursor::{
+    cursor_match_stage, page_stages, pagination_document, total_count, PageCursor,
+};
+use crate::utils::export::{export_columns, export_intervals, ExportFormat};
+use crate::utils::rollup::{
+    amount_outputs, group_accumulators, project_fields, summary_pipeline, Rollup, RollupField,
+};
+use crate::utils::time::render_iso_times;
+use crate::utils::{amounts_to_strings_stage, build_match_stage};
+use actix_web::{get, web, HttpRequest, HttpResponse};
 use futures_util::TryStreamExt;
-use log::{debug, error};
-use mongodb::bson::doc;
+use mongodb::bson::{doc, Document};
+
+/// Counts, volumes and fees are flows and are summed over coarser intervals;
+/// slips are weighted by the matching volume and the RUNE price is a stock.
+pub const SWAPS_ROLLUP: [RollupField; 36] = [
+    RollupField::new("toAssetCount", "to_asset_count", Rollup::Sum),
+    RollupField::new("toRuneCount", "to_rune_count", Rollup::Sum),
+    RollupField::new("toTradeCount", "to_trade_count", Rollup::Sum),
+    RollupField::new("fromTradeCount", "from_trade_count", Rollup::Sum),
+    RollupField::new("synthMintCount", "synth_mint_count", Rollup::Sum),
+    RollupField::new("synthRedeemCount", "synth_redeem_count", Rollup::Sum),
+    RollupField::new("totalCount", "total_count", Rollup::Sum),
+    RollupField::new("toAssetVolume", "to_asset_volume", Rollup::Sum),
+    RollupField::new("toRuneVolume", "to_rune_volume", Rollup::Sum),
+    RollupField::new("toTradeVolume", "to_trade_volume", Rollup::Sum),
+    RollupField::new("fromTradeVolume", "from_trade_volume", Rollup::Sum),
+    RollupField::new("synthMintVolume", "synth_mint_volume", Rollup::Sum),
+    RollupField::new("synthRedeemVolume", "synth_redeem_volume", Rollup::Sum),
+    RollupField::new("totalVolume", "total_volume", Rollup::Sum),
+    RollupField::new("toAssetVolumeUSD", "to_asset_volume_usd", Rollup::Sum),
+    RollupField::new("toRuneVolumeUSD", "to_rune_volume_usd", Rollup::Sum),
+    RollupField::new("toTradeVolumeUSD", "to_trade_volume_usd", Rollup::Sum),
+    RollupField::new("fromTradeVolumeUSD", "from_trade_volume_usd", Rollup::Sum),
+    RollupField::new("synthMintVolumeUSD", "synth_mint_volume_usd", Rollup::Sum),
+    RollupField::new(
+        "synthRedeemVolumeUSD",
+        "synth_redeem_volume_usd",
+        Rollup::Sum,
+    ),
+    RollupField::new("totalVolumeUSD", "total_volume_usd", Rollup::Sum),
+    RollupField::new("toAssetFees", "to_asset_fees", Rollup::Sum),
+    RollupField::new("toRuneFees", "to_rune_fees", Rollup::Sum),
+    RollupField::new("toTradeFees", "to_trade_fees", Rollup::Sum),
+    RollupField::new("fromTradeFees", "from_trade_fees", Rollup::Sum),
+    RollupField::new("synthMintFees", "synth_mint_fees", Rollup::Sum),
+    RollupField::new("synthRedeemFees", "synth_redeem_fees", Rollup::Sum),
+    RollupField::new("totalFees", "total_fees", Rollup::Sum),
+    RollupField::new(
+        "toAssetAverageSlip",
+        "to_asset_average_slip",
+        Rollup::WeightedAvg("to_asset_volume"),
+    ),
+    RollupField::new(
+        "toRuneAverageSlip",
+        "to_rune_average_slip",
+        Rollup::WeightedAvg("to_rune_volume"),
+    ),
+    RollupField::new(
+        "toTradeAverageSlip",
+        "to_trade_average_slip",
+        Rollup::WeightedAvg("to_trade_volume"),
+    ),
+    RollupField::new(
+        "fromTradeAverageSlip",
+        "from_trade_average_slip",
+        Rollup::WeightedAvg("from_trade_volume"),
+    ),
+    RollupField::new(
+        "synthMintAverageSlip",
+        "synth_mint_average_slip",
+        Rollup::WeightedAvg("synth_mint_volume"),
+    ),
+    RollupField::new(
+        "synthRedeemAverageSlip",
+        "synth_redeem_average_slip",
+        Rollup::WeightedAvg("synth_redeem_volume"),
+    ),
+    RollupField::new(
+        "averageSlip",
+        "average_slip",
+        Rollup::WeightedAvg("total_volume"),
+    ),
+    RollupField::new("runePriceUSD", "rune_price_usd", Rollup::Last),
+];
 
 #[utoipa::path(
     get,
@@ -13,254 +99,166 @@ use mongodb::bson::doc;
     params(
         ("interval" = Option<String>, Query, description = "Time interval (hour, day, week, etc.)"),
         ("count" = Option<i32>, Query, description = "Number of intervals"),
-        ("from" = Option<i64>, Query, description = "Start timestamp"),
-        ("to" = Option<i64>, Query, description = "End timestamp"),
+        ("pool" = Option<String>, Query, description = "Pool identifier; omit for the network-wide series"),
+        ("from" = Option<String>, Query, description = "Start time: Unix seconds, ISO-8601, a date (2024-06-01), now, or an offset from now (-7d)"),
+        ("to" = Option<String>, Query, description = "End time, in the same forms as from"),
         ("page" = Option<i64>, Query, description = "Page number"),
         ("limit" = Option<i64>, Query, description = "Records per page"),
         ("sort_by" = Option<String>, Query, description = "Field to sort by"),
-        ("order" = Option<String>, Query, description = "Sort order (asc/desc)")
+        ("order" = Option<String>, Query, description = "Sort order (asc/desc)"),
+        ("format" = Option<String>, Query, description = "Response format (json, csv or ndjson)"),
+        ("cursor" = Option<String>, Query, description = "Opaque cursor from nextCursor or prevCursor"),
+        ("fields" = Option<String>, Query, description = "Comma-separated fields to return; startTime and endTime are always included"),
+        ("time_format" = Option<String>, Query, description = "How startTime and endTime are rendered: unix (default) or iso")
     ),
     responses(
         (status = 200, description = "Successfully retrieved swaps history", body = SwapsHistory),
-        (status = 404, description = "No swaps history found"),
-        (status = 400, description = "Invalid request parameters"),
-        (status = 500, description = "Internal server error")
+        (status = 304, description = "Nothing new since the If-None-Match or If-Modified-Since validators"),
+        (status = 404, description = "No swaps history found", body = crate::error::ErrorEnvelope),
+        (status = 400, description = "Invalid request parameters", body = crate::error::ErrorEnvelope),
+        (status = 500, description = "Internal server error", body = crate::error::ErrorEnvelope)
     ),
     tag = "Swaps History"
 )]
-#[get("/api/history/swaps")]
+#[get(
+    "/api/history/swaps",
+    wrap = "CacheResponse::new(Series::Swaps)",
+    wrap = "ConditionalGet::new(Series::Swaps)",
+    wrap = "ValidateHistoryQuery::new(&SWAPS_ROLLUP)"
+)]
 pub async fn get_swaps_history(
+    req: HttpRequest,
     db: web::Data<Mongodb>,
     query: web::Query<HistoryQueryParams>,
-) -> Result<HttpResponse> {
-    match (&query.interval, query.count) {
-        (Some(_), None) | (None, Some(_)) => {
-            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                "error": "Both interval and count must be provided together",
-                "status": 400
-            })));
-        }
-        _ => {}
-    }
-    let page = query.page.unwrap_or(1);
-    let limit = query.limit.unwrap_or(50).min(400);
-    let skip = (page - 1) * limit;
-    let sort_field = query.sort_by.as_deref().unwrap_or("startTime");
-    let sort_order = match query.order.as_deref().unwrap_or("asc") {
-        "desc" => -1,
-        _ => 1,
-    };
-    let seconds_per_interval =
-        get_seconds_per_interval(query.interval.as_deref().unwrap_or("hour"));
+) -> Result<HttpResponse, ApiError> {
     let collection = &db.swaps_history;
 
-    let mut match_stage = doc! {};
-    if let Some(from) = query.from {
-        match_stage.insert("start_time", doc! { "$gte": from });
-    } else {
-        let current_time = Utc::now().timestamp();
-        let count = query.count.unwrap_or(400) as i64;
-        match_stage.insert(
-            "start_time",
-            doc! {
-                "$gte": current_time - (count * seconds_per_interval)
-            },
-        );
-    }
-    // 35
-    if let Some(to) = query.to {
-        match_stage.insert("end_time", doc! { "$lte": to });
-    }
-
-    let total_count = collection
-        .count_documents(match_stage.clone(), None)
-        .await
-        .map_err(|e| {
-            error!("Count error: {}", e);
-            actix_web::error::ErrorInternalServerError("Failed to get total count")
-        })?;
+    let match_stage = build_swaps_match_stage(&query)?;
 
-    let pipeline = vec![
-        doc! { "$match": match_stage },
-        doc! {
-            "$group": {
-                "_id": {
-                    "interval_start": {
-                        "$subtract": [
-                            { "$add": ["$end_time", 1] },
-                            { "$mod": [
-                                { "$subtract": ["$end_time", 1] },
-                                seconds_per_interval
-                            ]}
-                        ]
-                    }
-                },
-                "toAssetCount": { "$last": "$to_asset_count" },
-                "toRuneCount": { "$last": "$to_rune_count" },
-                "toTradeCount": { "$last": "$to_trade_count" },
-                "fromTradeCount": { "$last": "$from_trade_count" },
-                "synthMintCount": { "$last": "$synth_mint_count" },
-                "synthRedeemCount": { "$last": "$synth_redeem_count" },
-                "totalCount": { "$last": "$total_count" },
-                "toAssetVolume": { "$last": "$to_asset_volume" },
-                "toRuneVolume": { "$last": "$to_rune_volume" },
-                "toTradeVolume": { "$last": "$to_trade_volume" },
-                "fromTradeVolume": { "$last": "$from_trade_volume" },
-                "synthMintVolume": { "$last": "$synth_mint_volume" },
-                "synthRedeemVolume": { "$last": "$synth_redeem_volume" },
-                "totalVolume": { "$last": "$total_volume" },
-                "toAssetVolumeUSD": { "$last": "$to_asset_volume_usd" },
-                "toRuneVolumeUSD": { "$last": "$to_rune_volume_usd" },
-                "toTradeVolumeUSD": { "$last": "$to_trade_volume_usd" },
-                "fromTradeVolumeUSD": { "$last": "$from_trade_volume_usd" },
-                "synthMintVolumeUSD": { "$last": "$synth_mint_volume_usd" },
-                "synthRedeemVolumeUSD": { "$last": "$synth_redeem_volume_usd" },
-                "totalVolumeUSD": { "$last": "$total_volume_usd" },
-                "toAssetFees": { "$last": "$to_asset_fees" },
-                "toRuneFees": { "$last": "$to_rune_fees" },
-                "toTradeFees": { "$last": "$to_trade_fees" },
-                "fromTradeFees": { "$last": "$from_trade_fees" },
-                "synthMintFees": { "$last": "$synth_mint_fees" },
-                "synthRedeemFees": { "$last": "$synth_redeem_fees" },
-                "totalFees": { "$last": "$total_fees" },
-                "toAssetAverageSlip": { "$last": "$to_asset_average_slip" },
-                "toRuneAverageSlip": { "$last": "$to_rune_average_slip" },
-                "toTradeAverageSlip": { "$last": "$to_trade_average_slip" },
-                "fromTradeAverageSlip": { "$last": "$from_trade_average_slip" },
-                "synthMintAverageSlip": { "$last": "$synth_mint_average_slip" },
-                "synthRedeemAverageSlip": { "$last": "$synth_redeem_average_slip" },
-                "averageSlip": { "$last": "$average_slip" },
-                "runePriceUSD": { "$last": "$rune_price_usd" },
-            }
-        },
-        doc! { "$project": {
-            "_id": 0,
-            "startTime": {
-                "$subtract": [ "$_id.interval_start", { "$mod": [ "$_id.interval_start", seconds_per_interval ] }]
-            },
-            "endTime": {
-                "$add": [
-                    { "$subtract": [ "$_id.interval_start", { "$mod": [ "$_id.interval_start", seconds_per_interval ] }] },
-                    seconds_per_interval
-                ]
-            },
-            "toAssetCount": 1,
-            "toRuneCount": 1,
-            "toTradeCount": 1,
-            "fromTradeCount": 1,
-            "synthMintCount": 1,
-            "synthRedeemCount": 1,
-            "totalCount": 1,
-            "toAssetVolume": 1,
-            "toRuneVolume": 1,
-            "toTradeVolume": 1,
-            "fromTradeVolume": 1,
-            "synthMintVolume": 1,
-            "synthRedeemVolume": 1,
-            "totalVolume": 1,
-            "toAssetVolumeUSD": 1,
-            "toRuneVolumeUSD": 1,
-            "toTradeVolumeUSD": 1,
-            "fromTradeVolumeUSD": 1,
-            "synthMintVolumeUSD": 1,
-            "synthRedeemVolumeUSD": 1,
-            "totalVolumeUSD": 1,
-            "toAssetFees": 1,
-            "toRuneFees": 1,
-            "toTradeFees": 1,
-            "fromTradeFees": 1,
-            "synthMintFees": 1,
-            "synthRedeemFees": 1,
-            "totalFees": 1,
-            "toAssetAverageSlip": 1,
-            "toRuneAverageSlip": 1,
-            "toTradeAverageSlip": 1,
-            "fromTradeAverageSlip": 1,
-            "synthMintAverageSlip": 1,
-            "synthRedeemAverageSlip": 1,
-            "averageSlip": 1,
-            "runePriceUSD": 1,
-        }},
-        doc! { "$sort": { sort_field: sort_order } },
-        doc! { "$skip": skip },
-        doc! { "$limit": limit },
-    ];
+    if let Some(format) = ExportFormat::negotiate(&req, query.format.as_deref())? {
+        let fields = query.field_selection(&SWAPS_ROLLUP, &[])?;
+        let mut columns = export_columns(&SwapsHistory::FIELDS, &SWAPS_ROLLUP);
+        columns.retain(|column| fields.includes(column));
+        return export_intervals(
+            collection,
+            swaps_intervals_pipeline(match_stage, &query, false)?,
+            format,
+            columns,
+            query.iso_times(),
+        )
+        .await;
+    }
 
-    let mut cursor = collection.aggregate(pipeline, None).await.map_err(|e| {
-        error!("Database error: {}", e);
-        actix_web::error::ErrorInternalServerError("Failed to fetch depth history")
-    })?;
+    let cursor = PageCursor::from_query(&query)?;
+    let total_count = total_count(collection, &match_stage, query.interval()?, cursor).await?;
 
-    let mut intervals = Vec::new();
-    while let Some(doc) = cursor.try_next().await.map_err(|e| {
-        error!("Cursor error: {}", e);
-        actix_web::error::ErrorInternalServerError("Failed to process results")
-    })? {
-        intervals.push(doc);
-    }
+    let mut intervals = query_swaps_intervals(&db, match_stage.clone(), &query).await?;
 
     if intervals.is_empty() {
-        return Ok(HttpResponse::NotFound().json(serde_json::json!({
-            "error": "No depth history found",
-            "status": 404
-        })));
+        return Err(ApiError::NotFound("No swaps history found".to_string()));
     }
 
-    let first = intervals.first().unwrap();
-    let last = intervals.last().unwrap();
+    let pagination = pagination_document(
+        &req,
+        &query,
+        collection,
+        &match_stage,
+        cursor,
+        &intervals,
+        total_count,
+    )
+    .await?;
 
-    // debug!("First document: {:?}", first);
-    debug!("Last document: {:?}", last);
+    let mut meta = query_swaps_summary(&db, match_stage, &query).await?;
+    meta.insert("pagination", pagination);
+
+    if query.iso_times() {
+        intervals.iter_mut().for_each(render_iso_times);
+        render_iso_times(&mut meta);
+    }
 
     let response = doc! {
         "intervals": &intervals,
-        "meta": {
-            "toAssetCount": first.get_i64("toAssetCount").unwrap(),
-            "toRuneCount": first.get_i64("toRuneCount").unwrap(),
-            "toTradeCount": first.get_i64("toTradeCount").unwrap(),
-            "fromTradeCount": first.get_i64("fromTradeCount").unwrap(),
-            "synthMintCount": first.get_i64("synthMintCount").unwrap(),
-            "synthRedeemCount": first.get_i64("synthRedeemCount").unwrap(),
-            "totalCount": first.get_i64("totalCount").unwrap(),
-            "toAssetVolume": first.get_f64("toAssetVolume").unwrap(),
-            "toRuneVolume": first.get_f64("toRuneVolume").unwrap(),
-            "toTradeVolume": first.get_f64("toTradeVolume").unwrap(),
-            "fromTradeVolume": first.get_f64("fromTradeVolume").unwrap(),
-            "synthMintVolume": first.get_f64("synthMintVolume").unwrap(),
-            "synthRedeemVolume": first.get_f64("synthRedeemVolume").unwrap(),
-            "totalVolume": first.get_f64("totalVolume").unwrap(),
-            "toAssetVolumeUSD": first.get_f64("toAssetVolumeUSD").unwrap(),
-            "toRuneVolumeUSD": first.get_f64("toRuneVolumeUSD").unwrap(),
-            "toTradeVolumeUSD": first.get_f64("toTradeVolumeUSD").unwrap(),
-            "fromTradeVolumeUSD": first.get_f64("fromTradeVolumeUSD").unwrap(),
-            "synthMintVolumeUSD": first.get_f64("synthMintVolumeUSD").unwrap(),
-            "synthRedeemVolumeUSD": first.get_f64("synthRedeemVolumeUSD").unwrap(),
-            "totalVolumeUSD": first.get_f64("totalVolumeUSD").unwrap(),
-            "toAssetFees": first.get_f64("toAssetFees").unwrap(),
-            "toRuneFees": first.get_f64("toRuneFees").unwrap(),
-            "toTradeFees": first.get_f64("toTradeFees").unwrap(),
-            "fromTradeFees": first.get_f64("fromTradeFees").unwrap(),
-            "synthMintFees": first.get_f64("synthMintFees").unwrap(),
-            "synthRedeemFees": first.get_f64("synthRedeemFees").unwrap(),
-            "totalFees": first.get_f64("totalFees").unwrap(),
-            "toAssetAverageSlip": first.get_f64("toAssetAverageSlip").unwrap(),
-            "toRuneAverageSlip": first.get_f64("toRuneAverageSlip").unwrap(),
-            "toTradeAverageSlip": first.get_f64("toTradeAverageSlip").unwrap(),
-            "fromTradeAverageSlip": first.get_f64("fromTradeAverageSlip").unwrap(),
-            "synthMintAverageSlip": first.get_f64("synthMintAverageSlip").unwrap(),
-            "synthRedeemAverageSlip": first.get_f64("synthRedeemAverageSlip").unwrap(),
-            "averageSlip": first.get_f64("averageSlip").unwrap(),
-            "runePriceUSD": first.get_f64("runePriceUSD").unwrap(),
-            "pagination": {
-            "currentPage": mongodb::bson::Bson::Int64(page),
-                "totalPages": mongodb::bson::Bson::Int32((total_count as f64 / limit as f64).ceil() as i32),
-                "totalRecords": mongodb::bson::Bson::Int64(total_count as i64),
-                "limit": mongodb::bson::Bson::Int64(limit),
-                "sortBy": sort_field,
-                "order": if sort_order == 1 { "asc" } else { "desc" }
-        }
-        }
+        "meta": meta
     };
 
     Ok(HttpResponse::Ok().json(response))
 }
+
+/// Match stage for the swaps series selected by `query`: one pool, or the
+/// network-wide series (stored with a null pool) when no pool is given.
+pub fn build_swaps_match_stage(query: &HistoryQueryParams) -> Result<Document, ApiError> {
+    let mut match_stage = build_match_stage(None, query, query.interval()?)?;
+    match_stage.insert("pool", query.pool.as_deref());
+    Ok(match_stage)
+}
+
+/// Swaps aggregation for `query`. Exports pass `paginate = false` to get
+/// every interval in the range instead of one page.
+pub fn swaps_intervals_pipeline(
+    match_stage: Document,
+    query: &HistoryQueryParams,
+    paginate: bool,
+) -> Result<Vec<Document>, ApiError> {
+    let interval = query.interval()?;
+    let cursor = if paginate {
+        PageCursor::from_query(query)?
+    } else {
+        None
+    };
+    let fields = query.field_selection(&SWAPS_ROLLUP, &[])?;
+
+    let mut group = doc! {
+        "_id": { "interval_start": bucket_start_expr(interval) }
+    };
+    group.extend(group_accumulators(fields.rollup(&SWAPS_ROLLUP)));
+
+    let mut projection = doc! {
+        "_id": 0,
+        "startTime": "$_id.interval_start",
+        "endTime": bucket_end_expr(interval, "$_id.interval_start")
+    };
+    projection.extend(project_fields(fields.rollup(&SWAPS_ROLLUP)));
+
+    let mut pipeline = vec![
+        doc! { "$match": cursor_match_stage(match_stage, query, cursor)? },
+        doc! { "$sort": { "start_time": 1 } },
+        doc! { "$group": group },
+        doc! { "$project": projection },
+    ];
+    pipeline.extend(page_stages(query, cursor, paginate));
+    pipeline.push(amounts_to_strings_stage(&amount_outputs(
+        fields.rollup(&SWAPS_ROLLUP),
+        &SwapsHistory::AMOUNT_FIELDS,
+    )));
+    Ok(pipeline)
+}
+
+/// Bucketed swaps intervals matching `match_stage`, sorted and paginated as
+/// requested by `query`.
+pub async fn query_swaps_intervals(
+    db: &Mongodb,
+    match_stage: Document,
+    query: &HistoryQueryParams,
+) -> Result<Vec<Document>, ApiError> {
+    let pipeline = swaps_intervals_pipeline(match_stage, query, true)?;
+
+    let cursor = db.swaps_history.aggregate(pipeline, None).await?;
+    Ok(cursor.try_collect().await?)
+}
+
+/// The selected swaps fields rolled up over the range matched by
+/// `match_stage`, or over the page window of a cursor request, for `meta`.
+/// Empty when nothing matches.
+pub async fn query_swaps_summary(
+    db: &Mongodb,
+    match_stage: Document,
+    query: &HistoryQueryParams,
+) -> Result<Document, ApiError> {
+    let fields = query.field_selection(&SWAPS_ROLLUP, &[])?;
+    let fields: Vec<&RollupField> = fields.rollup(&SWAPS_ROLLUP).collect();
+    let pipeline = summary_pipeline(match_stage, query, &fields, &SwapsHistory::AMOUNT_FIELDS)?;
+
+    let mut cursor = db.swaps_history.aggregate(pipeline, None).await?;
+    Ok(cursor.try_next().await?.unwrap_or_default())
+}
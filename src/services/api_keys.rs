@@ -0,0 +1,80 @@
+use crate::database::db::Mongodb;
+use crate::models::api_key::{hash_api_key, ApiKey};
+use mongodb::error::Error as MongoError;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How long a key read from `api_keys` is trusted before it is read again,
+/// so disabling a key or changing its limits takes effect within this delay.
+const KEY_CACHE_TTL: Duration = Duration::from_secs(60);
+/// How long a key missing from `api_keys` is remembered as unknown, so a
+/// client retrying a bad key does not cost a lookup per request.
+const UNKNOWN_KEY_CACHE_TTL: Duration = Duration::from_secs(10);
+/// Unknown keys remembered at most; past it, new unknown keys are looked up
+/// every time until the remembered ones expire.
+const MAX_UNKNOWN_KEYS: usize = 10_000;
+
+static API_KEY_STORE: OnceLock<ApiKeyStore> = OnceLock::new();
+
+/// Looks API keys up in the `api_keys` collection, keeping found keys in
+/// memory for `KEY_CACHE_TTL` and unknown ones for `UNKNOWN_KEY_CACHE_TTL`
+/// so a request does not cost a lookup each time.
+#[derive(Debug, Default)]
+pub struct ApiKeyStore {
+    known: Mutex<HashMap<String, (ApiKey, Instant)>>,
+    unknown: Mutex<HashMap<String, Instant>>,
+}
+
+impl ApiKeyStore {
+    pub fn global() -> &'static ApiKeyStore {
+        API_KEY_STORE.get_or_init(Self::default)
+    }
+
+    /// The remembered lookup of `key`: `Some(None)` for a key known not to
+    /// exist, and `None` when it has to be looked up with `find`.
+    pub fn cached(&self, key: &str) -> Option<Option<ApiKey>> {
+        let key_hash = hash_api_key(key);
+        if let Some((api_key, read_at)) = self.known.lock().unwrap().get(&key_hash) {
+            if read_at.elapsed() < KEY_CACHE_TTL {
+                return Some(Some(api_key.clone()));
+            }
+        }
+        if let Some(read_at) = self.unknown.lock().unwrap().get(&key_hash) {
+            if read_at.elapsed() < UNKNOWN_KEY_CACHE_TTL {
+                return Some(None);
+            }
+        }
+        None
+    }
+
+    /// The stored key matching `key`, disabled or not.
+    pub async fn find(&self, db: &Mongodb, key: &str) -> Result<Option<ApiKey>, MongoError> {
+        if let Some(api_key) = self.cached(key) {
+            return Ok(api_key);
+        }
+
+        let key_hash = hash_api_key(key);
+        let api_key = db.find_api_key(&key_hash).await?;
+        match &api_key {
+            Some(api_key) => {
+                self.unknown.lock().unwrap().remove(&key_hash);
+                self.known
+                    .lock()
+                    .unwrap()
+                    .insert(key_hash, (api_key.clone(), Instant::now()));
+            }
+            None => {
+                self.known.lock().unwrap().remove(&key_hash);
+                let mut unknown = self.unknown.lock().unwrap();
+                if unknown.len() >= MAX_UNKNOWN_KEYS {
+                    unknown.retain(|_, read_at| read_at.elapsed() < UNKNOWN_KEY_CACHE_TTL);
+                }
+                if unknown.len() < MAX_UNKNOWN_KEYS {
+                    unknown.insert(key_hash, Instant::now());
+                }
+            }
+        }
+        Ok(api_key)
+    }
+}
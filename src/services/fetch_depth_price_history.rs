@@ -1,9 +1,22 @@
 use crate::database::db::Mongodb;
+use crate::error::ServiceError;
 use crate::models::depth_price_history::DepthPriceHistory;
-use chrono::{DateTime, Utc};
+use crate::models::ingestion_checkpoint::Series;
+use crate::services::fetch_pools::fetch_active_pools;
+use crate::services::series_fetcher::{fetch_series, MidgardSeries, Page};
+use mongodb::bson::doc;
 use mongodb::Client as MongoClient;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use serde::{Deserialize, Serialize};
 
+/// Bytes of a pool id kept as they are in a URL path segment: the unreserved
+/// characters. Anything else, `/` in particular, is percent-encoded.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Meta {
     #[serde(rename = "startTime")]
@@ -71,22 +84,32 @@ pub struct PriceHistory {
 pub async fn store_to_db(
     client: &MongoClient,
     intervals: Vec<Interval>,
-    _pool: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+    pool: &str,
+) -> Result<(), ServiceError> {
     let db = Mongodb::new(client.clone());
     let depth_collection = &db.depth_history;
     let mut success_count = 0;
     let mut error_count = 0;
 
     for interval in intervals {
-        match DepthPriceHistory::try_from(interval) {
-            Ok(depth_history) => match db.insert_document(depth_collection, depth_history).await {
-                Ok(_) => success_count += 1,
-                Err(e) => {
-                    error_count += 1;
-                    eprintln!("Error inserting document: {}", e);
+        match DepthPriceHistory::try_from((pool, interval)) {
+            Ok(depth_history) => {
+                let filter = doc! {
+                    "pool": &depth_history.pool,
+                    "start_time": depth_history.start_time,
+                    "end_time": depth_history.end_time,
+                };
+                match db
+                    .upsert_document(depth_collection, filter, depth_history)
+                    .await
+                {
+                    Ok(_) => success_count += 1,
+                    Err(e) => {
+                        error_count += 1;
+                        eprintln!("Error upserting document: {}", e);
+                    }
                 }
-            },
+            }
             Err(e) => {
                 error_count += 1;
                 eprintln!("Error converting interval: {}", e);
@@ -95,70 +118,111 @@ pub async fn store_to_db(
     }
 
     println!(
-        "Batch complete: {} documents inserted successfully, {} failed",
+        "Batch complete: {} documents upserted successfully, {} failed",
         success_count, error_count
     );
+    if error_count > 0 {
+        return Err(ServiceError::StoreFailed {
+            series: Series::Depth.as_str(),
+            failed: error_count,
+            total: success_count + error_count,
+        });
+    }
     Ok(())
 }
 
-pub async fn fetch_depth_price_history(
-    pool: &str,
-    interval: &str,
-    start_time: i64,
-    mongo_client: &MongoClient,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut current_time = start_time;
+/// Depth and price history of a single pool.
+pub struct DepthSeries<'a> {
+    pub pool: &'a str,
+}
 
-    loop {
-        let url = format!(
-            "https://midgard.ninerealms.com/v2/history/depths/{}?interval={}&from={}&count=400",
-            pool, interval, current_time
-        );
+impl MidgardSeries for DepthSeries<'_> {
+    type Response = PriceHistory;
 
-        println!("Fetching URL: {}", url);
+    fn series(&self) -> Series {
+        Series::Depth
+    }
 
-        let response = reqwest::get(&url).await?;
-        println!("Response status: {}", response.status());
+    fn pool(&self) -> Option<&str> {
+        Some(self.pool)
+    }
 
-        if !response.status().is_success() {
-            return Err(format!("Request failed with status: {}", response.status()).into());
-        }
+    fn endpoint(&self) -> String {
+        format!(
+            "/v2/history/depths/{}",
+            utf8_percent_encode(self.pool, PATH_SEGMENT)
+        )
+    }
 
-        let text = response.text().await?;
+    fn page(response: &PriceHistory) -> Result<Page, ServiceError> {
+        Page::parse(
+            response.intervals.len(),
+            &response.meta.end_time,
+            response
+                .intervals
+                .last()
+                .map(|last| last.start_time.as_str()),
+        )
+    }
 
-        match serde_json::from_str::<PriceHistory>(&text) {
-            Ok(price_history) => {
-                if price_history.intervals.is_empty() {
-                    println!("No more intervals to process");
-                    break;
-                }
+    async fn store(
+        &self,
+        mongo_client: &MongoClient,
+        response: PriceHistory,
+    ) -> Result<(), ServiceError> {
+        store_to_db(mongo_client, response.intervals, self.pool).await
+    }
+}
 
-                println!(
-                    "Number of intervals to process: {}",
-                    price_history.intervals.len()
-                );
+pub async fn fetch_depth_price_history(
+    pool: &str,
+    interval: &str,
+    start_time: i64,
+    mongo_client: &MongoClient,
+) -> Result<(), ServiceError> {
+    fetch_series(&DepthSeries { pool }, interval, start_time, mongo_client).await
+}
 
-                let end_time = price_history.meta.end_time.parse::<i64>()?;
+/// Discovers the current pool list from Midgard and fetches depth history for
+/// each pool in turn. A failing pool is logged and skipped so the remaining
+/// pools are still ingested.
+pub async fn fetch_depth_price_history_for_all_pools(
+    interval: &str,
+    start_time: i64,
+    mongo_client: &MongoClient,
+) -> Result<(), ServiceError> {
+    let pools = fetch_active_pools().await?;
+    let mut failed_pools = Vec::new();
+
+    for pool in &pools {
+        if let Err(e) = fetch_depth_price_history(pool, interval, start_time, mongo_client).await {
+            eprintln!("Error fetching depth history for {}: {}", pool, e);
+            failed_pools.push(pool.clone());
+        }
+    }
 
-                store_to_db(mongo_client, price_history.intervals, pool).await?;
+    if !failed_pools.is_empty() {
+        return Err(ServiceError::PoolsFailed {
+            series: Series::Depth.as_str(),
+            pools: failed_pools,
+        });
+    }
 
-                let current_utc: DateTime<Utc> = Utc::now();
-                let current_timestamp = current_utc.timestamp();
+    Ok(())
+}
 
-                if end_time >= current_timestamp {
-                    break;
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                current_time = end_time;
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-            }
-            Err(e) => {
-                println!("Failed to parse JSON: {}", e);
-                println!("Response text: {}", text);
-                return Err(Box::new(e));
-            }
-        }
+    #[test]
+    fn endpoint_encodes_the_pool() {
+        let endpoint = |pool| DepthSeries { pool }.endpoint();
+        assert_eq!(
+            endpoint("ETH.USDC-0XA0B86991C6218B36C1D19D4A2E9EB0CE3606EB48"),
+            "/v2/history/depths/ETH.USDC-0XA0B86991C6218B36C1D19D4A2E9EB0CE3606EB48"
+        );
+        assert_eq!(endpoint("BTC/BTC"), "/v2/history/depths/BTC%2FBTC");
+        assert_eq!(endpoint("BTC~BTC"), "/v2/history/depths/BTC~BTC");
     }
-
-    Ok(())
 }
@@ -0,0 +1,36 @@
+use crate::error::ServiceError;
+use crate::services::midgard_client::MidgardClient;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolDetail {
+    pub asset: String,
+    pub status: String,
+}
+
+/// Fetches the pool list from Midgard and returns the asset names of every
+/// pool that is not suspended.
+pub async fn fetch_active_pools() -> Result<Vec<String>, ServiceError> {
+    let path = "/v2/pools";
+
+    let text = MidgardClient::global().get_text(path).await?;
+
+    match serde_json::from_str::<Vec<PoolDetail>>(&text) {
+        Ok(pools) => {
+            let active_pools: Vec<String> = pools
+                .into_iter()
+                .filter(|pool| pool.status != "suspended")
+                .map(|pool| pool.asset)
+                .collect();
+
+            println!("Discovered {} active pools", active_pools.len());
+            Ok(active_pools)
+        }
+        Err(e) => {
+            println!("Failed to parse JSON: {}", e);
+            println!("Response text: {}", text);
+            Err(e.into())
+        }
+    }
+}
@@ -1,5 +1,8 @@
 use crate::database::db::Mongodb;
-use chrono::{DateTime, Utc};
+use crate::error::ServiceError;
+use crate::models::ingestion_checkpoint::Series;
+use crate::services::series_fetcher::{fetch_series, MidgardSeries, Page};
+use mongodb::bson::doc;
 use mongodb::Client as MongoClient;
 use serde::{Deserialize, Serialize};
 
@@ -45,7 +48,7 @@ pub struct ApiResponse {
 pub async fn store_to_db(
     client: &MongoClient,
     intervals: Vec<Interval>,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), ServiceError> {
     let db = Mongodb::new(client.clone());
     let depth_collection = &db.runepool_members_history;
     let mut success_count = 0;
@@ -53,13 +56,22 @@ pub async fn store_to_db(
 
     for interval in intervals {
         match RunePoolTotalMembersHistory::try_from(interval) {
-            Ok(depth_history) => match db.insert_document(depth_collection, depth_history).await {
-                Ok(_) => success_count += 1,
-                Err(e) => {
-                    error_count += 1;
-                    eprintln!("Error inserting document: {}", e);
+            Ok(depth_history) => {
+                let filter = doc! {
+                    "start_time": depth_history.start_time,
+                    "end_time": depth_history.end_time,
+                };
+                match db
+                    .upsert_document(depth_collection, filter, depth_history)
+                    .await
+                {
+                    Ok(_) => success_count += 1,
+                    Err(e) => {
+                        error_count += 1;
+                        eprintln!("Error upserting document: {}", e);
+                    }
                 }
-            },
+            }
             Err(e) => {
                 error_count += 1;
                 eprintln!("Error converting interval: {}", e);
@@ -68,70 +80,61 @@ pub async fn store_to_db(
     }
 
     println!(
-        "Batch complete: {} documents inserted successfully, {} failed",
+        "Batch complete: {} documents upserted successfully, {} failed",
         success_count, error_count
     );
+    if error_count > 0 {
+        return Err(ServiceError::StoreFailed {
+            series: Series::RunepoolMembers.as_str(),
+            failed: error_count,
+            total: success_count + error_count,
+        });
+    }
     Ok(())
 }
 
-pub async fn fetch_runepool_members_units_history(
-    interval: &str,
-    start_time: i64,
-    mongo_client: &MongoClient,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut current_time = start_time;
-
-    loop {
-        let url = format!(
-            "https://midgard.ninerealms.com/v2/history/runepool/?interval={}&from={}&count=400",
-            interval, current_time
-        );
-
-        println!("Fetching URL: {}", url);
+/// RUNEPool member count, units and depth history.
+pub struct RunepoolMembersSeries;
 
-        let response = reqwest::get(&url).await?;
-        println!("Response status: {}", response.status());
+impl MidgardSeries for RunepoolMembersSeries {
+    type Response = ApiResponse;
 
-        if !response.status().is_success() {
-            return Err(format!("Request failed with status: {}", response.status()).into());
-        }
-
-        let text = response.text().await?;
-
-        match serde_json::from_str::<ApiResponse>(&text) {
-            Ok(runepool_history) => {
-                if runepool_history.intervals.is_empty() {
-                    println!("No more intervals to process");
-                    break;
-                }
-
-                println!(
-                    "Number of intervals to process: {}",
-                    runepool_history.intervals.len()
-                );
-
-                let end_time = runepool_history.meta.end_time.parse::<i64>()?;
+    fn series(&self) -> Series {
+        Series::RunepoolMembers
+    }
 
-                // Store data in MongoDB one by one
-                store_to_db(mongo_client, runepool_history.intervals).await?;
+    fn pool(&self) -> Option<&str> {
+        None
+    }
 
-                let current_utc: DateTime<Utc> = Utc::now();
-                let current_timestamp = current_utc.timestamp();
+    fn endpoint(&self) -> String {
+        "/v2/history/runepool/".to_string()
+    }
 
-                if end_time >= current_timestamp {
-                    break;
-                }
+    fn page(response: &ApiResponse) -> Result<Page, ServiceError> {
+        Page::parse(
+            response.intervals.len(),
+            &response.meta.end_time,
+            response
+                .intervals
+                .last()
+                .map(|last| last.start_time.as_str()),
+        )
+    }
 
-                current_time = end_time;
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-            }
-            Err(e) => {
-                println!("Failed to parse JSON: {}", e);
-                println!("Response text: {}", text);
-                return Err(Box::new(e));
-            }
-        }
+    async fn store(
+        &self,
+        mongo_client: &MongoClient,
+        response: ApiResponse,
+    ) -> Result<(), ServiceError> {
+        store_to_db(mongo_client, response.intervals).await
     }
+}
 
-    Ok(())
+pub async fn fetch_runepool_members_units_history(
+    interval: &str,
+    start_time: i64,
+    mongo_client: &MongoClient,
+) -> Result<(), ServiceError> {
+    fetch_series(&RunepoolMembersSeries, interval, start_time, mongo_client).await
 }
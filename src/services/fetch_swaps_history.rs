@@ -1,6 +1,10 @@
 use crate::database::db::Mongodb;
+use crate::error::ServiceError;
+use crate::models::ingestion_checkpoint::Series;
 use crate::models::swaps_history::SwapsHistory;
-use chrono::{DateTime, Utc};
+use crate::services::fetch_pools::fetch_active_pools;
+use crate::services::series_fetcher::{fetch_series, MidgardSeries, Page};
+use mongodb::bson::doc;
 use mongodb::Client as MongoClient;
 use serde::{Deserialize, Serialize};
 
@@ -112,25 +116,35 @@ pub struct SwapHistory {
     pub meta: Meta,
 }
 
-#[allow(unused_variables)]
 pub async fn store_to_db(
     client: &MongoClient,
     intervals: Vec<Interval>,
-) -> Result<(), Box<dyn std::error::Error>> {
+    pool: Option<&str>,
+) -> Result<(), ServiceError> {
     let db = Mongodb::new(client.clone());
     let swaps_collection = &db.swaps_history;
     let mut success_count = 0;
     let mut error_count = 0;
 
     for interval in intervals {
-        match SwapsHistory::try_from(interval) {
-            Ok(depth_history) => match db.insert_document(swaps_collection, depth_history).await {
-                Ok(_) => success_count += 1,
-                Err(e) => {
-                    error_count += 1;
-                    eprintln!("Error inserting document: {}", e);
+        match SwapsHistory::try_from((pool, interval)) {
+            Ok(swaps_history) => {
+                let filter = doc! {
+                    "pool": swaps_history.pool.as_deref(),
+                    "start_time": swaps_history.start_time,
+                    "end_time": swaps_history.end_time,
+                };
+                match db
+                    .upsert_document(swaps_collection, filter, swaps_history)
+                    .await
+                {
+                    Ok(_) => success_count += 1,
+                    Err(e) => {
+                        error_count += 1;
+                        eprintln!("Error upserting document: {}", e);
+                    }
                 }
-            },
+            }
             Err(e) => {
                 error_count += 1;
                 eprintln!("Error converting interval: {}", e);
@@ -139,69 +153,125 @@ pub async fn store_to_db(
     }
 
     println!(
-        "Batch complete: {} documents inserted successfully, {} failed",
+        "Batch complete: {} documents upserted successfully, {} failed",
         success_count, error_count
     );
+    if error_count > 0 {
+        return Err(ServiceError::StoreFailed {
+            series: Series::Swaps.as_str(),
+            failed: error_count,
+            total: success_count + error_count,
+        });
+    }
     Ok(())
 }
 
+/// Fetches the network-wide swaps series followed by the series of every
+/// pool discovered from Midgard. A failing series, the network-wide one
+/// included, is logged and skipped so the remaining pools are still ingested.
 pub async fn fetch_swaps_history(
     interval: &str,
     start_time: i64,
     mongo_client: &MongoClient,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut current_time = start_time;
-
-    loop {
-        let url = format!(
-            "https://midgard.ninerealms.com/v2/history/swaps?interval={}&from={}&count=400",
-            interval, current_time
-        );
-
-        println!("Fetching URL: {}", url);
+) -> Result<(), ServiceError> {
+    let mut failed_pools = Vec::new();
+    if let Err(e) = fetch_swaps_history_for_pool(None, interval, start_time, mongo_client).await {
+        eprintln!("Error fetching network-wide swaps history: {}", e);
+        failed_pools.push("(all)".to_string());
+    }
 
-        let response = reqwest::get(&url).await?;
-        println!("Response status: {}", response.status());
+    let pools = fetch_active_pools().await?;
 
-        if !response.status().is_success() {
-            return Err(format!("Request failed with status: {}", response.status()).into());
+    for pool in &pools {
+        if let Err(e) =
+            fetch_swaps_history_for_pool(Some(pool), interval, start_time, mongo_client).await
+        {
+            eprintln!("Error fetching swaps history for {}: {}", pool, e);
+            failed_pools.push(pool.clone());
         }
+    }
 
-        let text = response.text().await?;
-
-        match serde_json::from_str::<SwapHistory>(&text) {
-            Ok(price_history) => {
-                if price_history.intervals.is_empty() {
-                    println!("No more intervals to process");
-                    break;
-                }
+    if !failed_pools.is_empty() {
+        return Err(ServiceError::PoolsFailed {
+            series: Series::Swaps.as_str(),
+            pools: failed_pools,
+        });
+    }
 
-                println!(
-                    "Number of intervals to process: {}",
-                    price_history.intervals.len()
-                );
+    Ok(())
+}
 
-                let end_time = price_history.meta.end_time.parse::<i64>()?;
+/// Swaps history of a single pool, or the network-wide series when `pool` is
+/// `None`.
+pub struct SwapsSeries<'a> {
+    pub pool: Option<&'a str>,
+}
 
-                store_to_db(mongo_client, price_history.intervals).await?;
+impl MidgardSeries for SwapsSeries<'_> {
+    type Response = SwapHistory;
 
-                let current_utc: DateTime<Utc> = Utc::now();
-                let current_timestamp = current_utc.timestamp();
+    fn series(&self) -> Series {
+        Series::Swaps
+    }
 
-                if end_time >= current_timestamp {
-                    break;
-                }
+    fn pool(&self) -> Option<&str> {
+        self.pool
+    }
 
-                current_time = end_time;
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-            }
-            Err(e) => {
-                println!("Failed to parse JSON: {}", e);
-                println!("Response text: {}", text);
-                return Err(Box::new(e));
-            }
+    fn endpoint(&self) -> String {
+        match self.pool {
+            Some(pool) => format!(
+                "/v2/history/swaps?{}",
+                serde_urlencoded::to_string([("pool", pool)]).unwrap_or_default()
+            ),
+            None => "/v2/history/swaps".to_string(),
         }
     }
 
-    Ok(())
+    fn page(response: &SwapHistory) -> Result<Page, ServiceError> {
+        Page::parse(
+            response.intervals.len(),
+            &response.meta.end_time,
+            response
+                .intervals
+                .last()
+                .map(|last| last.start_time.as_str()),
+        )
+    }
+
+    async fn store(
+        &self,
+        mongo_client: &MongoClient,
+        response: SwapHistory,
+    ) -> Result<(), ServiceError> {
+        store_to_db(mongo_client, response.intervals, self.pool).await
+    }
+}
+
+pub async fn fetch_swaps_history_for_pool(
+    pool: Option<&str>,
+    interval: &str,
+    start_time: i64,
+    mongo_client: &MongoClient,
+) -> Result<(), ServiceError> {
+    fetch_series(&SwapsSeries { pool }, interval, start_time, mongo_client).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoint_encodes_the_pool() {
+        let endpoint = |pool| SwapsSeries { pool }.endpoint();
+        assert_eq!(endpoint(None), "/v2/history/swaps");
+        assert_eq!(
+            endpoint(Some("ETH.USDC-0XA0B86991C6218B36C1D19D4A2E9EB0CE3606EB48")),
+            "/v2/history/swaps?pool=ETH.USDC-0XA0B86991C6218B36C1D19D4A2E9EB0CE3606EB48"
+        );
+        assert_eq!(
+            endpoint(Some("BTC/BTC")),
+            "/v2/history/swaps?pool=BTC%2FBTC"
+        );
+    }
 }
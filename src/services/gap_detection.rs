@@ -0,0 +1,297 @@
+use crate::database::db::Mongodb;
+use crate::error::ServiceError;
+use crate::models::ingestion_checkpoint::Series;
+use crate::models::ingestion_gap::{GapScanCheckpoint, GapStatus, IngestionGap};
+use crate::services::fetch_depth_price_history::DepthSeries;
+use crate::services::fetch_earnings_history::EarningsSeries;
+use crate::services::fetch_runepool_members_units_history::RunepoolMembersSeries;
+use crate::services::fetch_swaps_history::SwapsSeries;
+use crate::services::ingestion_jobs::lock_series;
+use crate::services::series_fetcher::fetch_series_range;
+use crate::utils::get_seconds_per_interval;
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use log::{info, warn};
+use mongodb::bson::{doc, Document};
+use mongodb::Client as MongoClient;
+
+/// Selects the stored intervals of `series` gap detection has not read yet:
+/// for each pool with a scan checkpoint, the intervals ending at or after it,
+/// which includes the last interval read so a gap right after it is found;
+/// for any other pool, all of them.
+fn unscanned_filter(checkpoints: &[GapScanCheckpoint]) -> Document {
+    if checkpoints.is_empty() {
+        return doc! {};
+    }
+    let mut clauses: Vec<Document> = checkpoints
+        .iter()
+        .map(|checkpoint| {
+            doc! {
+                "pool": &checkpoint.pool,
+                "end_time": { "$gte": checkpoint.scanned_until },
+            }
+        })
+        .collect();
+    let scanned_pools: Vec<Option<String>> = checkpoints
+        .iter()
+        .map(|checkpoint| checkpoint.pool.clone())
+        .collect();
+    clauses.push(doc! { "pool": { "$nin": scanned_pools } });
+    doc! { "$or": clauses }
+}
+
+/// Finds the runs of missing intervals among the stored intervals of
+/// `series` selected by `filter`, per pool. A gap is reported wherever an
+/// interval starts later than the previous interval of the same pool ended.
+pub async fn detect_gaps(
+    db: &Mongodb,
+    series: Series,
+    interval: &str,
+    filter: Document,
+) -> Result<Vec<IngestionGap>, ServiceError> {
+    let seconds_per_interval = get_seconds_per_interval(interval)
+        .ok_or_else(|| ServiceError::InvalidInterval(interval.to_string()))?;
+    let detected_at = Utc::now().timestamp();
+
+    let pipeline = vec![
+        doc! { "$match": filter },
+        doc! {
+            "$setWindowFields": {
+                "partitionBy": "$pool",
+                "sortBy": { "start_time": 1 },
+                "output": {
+                    "previous_end_time": { "$shift": { "output": "$end_time", "by": -1 } }
+                }
+            }
+        },
+        doc! {
+            "$match": {
+                "previous_end_time": { "$ne": null },
+                "$expr": { "$gt": ["$start_time", "$previous_end_time"] }
+            }
+        },
+        doc! { "$project": {
+            "_id": 0,
+            "pool": 1,
+            "from": "$previous_end_time",
+            "to": "$start_time"
+        }},
+        doc! { "$sort": { "pool": 1, "from": 1 } },
+    ];
+
+    let mut cursor = db
+        .series_collection(series)
+        .aggregate(pipeline, None)
+        .await?;
+
+    let mut gaps = Vec::new();
+    while let Some(gap) = cursor.try_next().await? {
+        let from = gap.get_i64("from").unwrap_or_default();
+        let to = gap.get_i64("to").unwrap_or_default();
+
+        gaps.push(IngestionGap {
+            series,
+            pool: gap.get_str("pool").ok().map(str::to_string),
+            interval: interval.to_string(),
+            from,
+            to,
+            missing_intervals: ((to - from) / seconds_per_interval).max(1),
+            status: GapStatus::Open,
+            backfill_error: None,
+            detected_at,
+            attempts: 0,
+        });
+    }
+
+    Ok(gaps)
+}
+
+/// End of the newest stored interval of each pool among those selected by
+/// `filter`: where the next scan of the pool starts.
+async fn scanned_until(
+    db: &Mongodb,
+    series: Series,
+    filter: Document,
+) -> Result<Vec<(Option<String>, i64)>, ServiceError> {
+    let pipeline = vec![
+        doc! { "$match": filter },
+        doc! { "$group": { "_id": "$pool", "scanned_until": { "$max": "$end_time" } } },
+    ];
+    let mut cursor = db
+        .series_collection(series)
+        .aggregate(pipeline, None)
+        .await?;
+
+    let mut pools = Vec::new();
+    while let Some(pool) = cursor.try_next().await? {
+        if let Ok(until) = pool.get_i64("scanned_until") {
+            pools.push((pool.get_str("_id").ok().map(str::to_string), until));
+        }
+    }
+    Ok(pools)
+}
+
+/// Re-fetches the intervals of `series` between `from` and `to` without
+/// touching its checkpoint. Depth history needs a `pool`; for swaps `None`
+/// is the network-wide series.
+pub async fn fetch_range(
+    series: Series,
+    pool: Option<&str>,
+    interval: &str,
+    from: i64,
+    to: i64,
+    mongo_client: &MongoClient,
+) -> Result<(), ServiceError> {
+    match series {
+        Series::Depth => {
+            let pool = pool.ok_or(ServiceError::MissingPool("Depth"))?;
+            fetch_series_range(&DepthSeries { pool }, interval, from, to, mongo_client).await
+        }
+        Series::Swaps => {
+            fetch_series_range(&SwapsSeries { pool }, interval, from, to, mongo_client).await
+        }
+        Series::Earnings => {
+            fetch_series_range(&EarningsSeries, interval, from, to, mongo_client).await
+        }
+        Series::RunepoolMembers => {
+            fetch_series_range(&RunepoolMembersSeries, interval, from, to, mongo_client).await
+        }
+    }
+}
+
+/// Re-fetches the missing range of `gap` from Midgard.
+pub async fn backfill_gap(
+    gap: &IngestionGap,
+    mongo_client: &MongoClient,
+) -> Result<(), ServiceError> {
+    fetch_range(
+        gap.series,
+        gap.pool.as_deref(),
+        &gap.interval,
+        gap.from,
+        gap.to,
+        mongo_client,
+    )
+    .await
+}
+
+/// Scans the intervals of every series stored since the last scan for gaps,
+/// and backfills the new gaps along with earlier ones that were not filled
+/// yet and have attempts left. Outcomes are stored in `ingestion_gaps`.
+/// Returns the number of gaps backfilled.
+pub async fn run_gap_backfill(
+    mongo_client: &MongoClient,
+    interval: &str,
+) -> Result<usize, ServiceError> {
+    let db = Mongodb::new(mongo_client.clone());
+    let mut total_gaps = 0;
+
+    for series in Series::ALL {
+        let _series = lock_series(series).await;
+        let checkpoints = db.gap_scan_checkpoints(series, interval).await?;
+        let filter = unscanned_filter(&checkpoints);
+        let scanned = scanned_until(&db, series, filter.clone()).await?;
+        let detected = detect_gaps(&db, series, interval, filter).await?;
+        if !detected.is_empty() {
+            info!(
+                "Found {} new gaps in {} history",
+                detected.len(),
+                series.as_str()
+            );
+        }
+
+        db.clear_backfilled_gaps(series, interval).await?;
+        let stored = db.list_gaps(series, interval).await?;
+        // A scan interrupted before its checkpoint was saved finds some
+        // gaps again; they keep their stored attempts.
+        let new_gaps: Vec<IngestionGap> = detected
+            .into_iter()
+            .filter(|gap| {
+                !stored
+                    .iter()
+                    .any(|known| known.pool == gap.pool && known.from == gap.from)
+            })
+            .collect();
+        let mut gaps: Vec<IngestionGap> = stored
+            .into_iter()
+            .filter(IngestionGap::should_retry)
+            .chain(new_gaps)
+            .collect();
+
+        for gap in &mut gaps {
+            gap.attempts += 1;
+            match backfill_gap(gap, mongo_client).await {
+                // Midgard answers a range it has no data for with no
+                // intervals, so a fetch that succeeded may still leave the
+                // gap open.
+                Ok(()) => {
+                    let stored = db
+                        .stored_seconds(series, gap.pool.as_deref(), gap.from, gap.to)
+                        .await?;
+                    if stored >= gap.to - gap.from {
+                        gap.status = GapStatus::Backfilled;
+                        gap.backfill_error = None;
+                    } else {
+                        gap.status = GapStatus::Unfillable;
+                        gap.backfill_error = Some(format!(
+                            "Midgard returned no intervals for {}s of the gap",
+                            gap.to - gap.from - stored
+                        ));
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to backfill {} gap {}-{} for pool {}: {}",
+                        series.as_str(),
+                        gap.from,
+                        gap.to,
+                        gap.pool.as_deref().unwrap_or("(all)"),
+                        e
+                    );
+                    gap.status = GapStatus::Failed;
+                    gap.backfill_error = Some(e.to_string());
+                }
+            }
+            db.save_gap(gap).await?;
+        }
+
+        for (pool, until) in scanned {
+            db.save_gap_scan_checkpoint(series, pool.as_deref(), interval, until)
+                .await?;
+        }
+        total_gaps += gaps.len();
+    }
+
+    Ok(total_gaps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint(pool: Option<&str>, scanned_until: i64) -> GapScanCheckpoint {
+        GapScanCheckpoint {
+            series: Series::Swaps,
+            pool: pool.map(str::to_string),
+            interval: "hour".to_string(),
+            scanned_until,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn unscanned_filter_starts_each_pool_at_its_checkpoint() {
+        assert_eq!(unscanned_filter(&[]), doc! {});
+
+        let filter =
+            unscanned_filter(&[checkpoint(None, 7_200), checkpoint(Some("BTC.BTC"), 3_600)]);
+        assert_eq!(
+            filter,
+            doc! { "$or": [
+                { "pool": null, "end_time": { "$gte": 7_200_i64 } },
+                { "pool": "BTC.BTC", "end_time": { "$gte": 3_600_i64 } },
+                { "pool": { "$nin": [null, "BTC.BTC"] } },
+            ]}
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use crate::database::db::Mongodb;
+use crate::error::ServiceError;
+use crate::models::ingestion_checkpoint::Series;
+use crate::services::gap_detection::fetch_range;
+use crate::services::response_cache::ResponseCache;
+use crate::services::scheduler::INGESTION_INTERVAL;
+use chrono::Utc;
+use log::{error, info};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use utoipa::ToSchema;
+
+/// Finished jobs kept for `GET /api/admin/jobs`; older ones are forgotten.
+const MAX_FINISHED_JOBS: usize = 100;
+
+static JOBS: OnceLock<JobRegistry> = OnceLock::new();
+
+/// One lock per series, in `Series::ALL` order. See `lock_series`.
+static SERIES_LOCKS: [tokio::sync::Mutex<()>; 4] = [
+    tokio::sync::Mutex::const_new(()),
+    tokio::sync::Mutex::const_new(()),
+    tokio::sync::Mutex::const_new(()),
+    tokio::sync::Mutex::const_new(()),
+];
+
+tokio::task_local! {
+    static CURRENT_JOB: u64;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    /// Fetch a range from Midgard, upserting over stored intervals.
+    Fetch,
+    /// Delete the stored intervals of a range, then fetch it again.
+    Reingest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// What a job ingests: a range of one series, and of one pool for depth and
+/// pool swaps history.
+#[derive(Debug, Clone)]
+pub struct JobRange {
+    pub series: Series,
+    pub pool: Option<String>,
+    pub from: i64,
+    pub to: i64,
+}
+
+/// A background ingestion job started from the admin API.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct IngestionJob {
+    pub id: u64,
+    pub kind: JobKind,
+    pub series: Series,
+    pub pool: Option<String>,
+    pub from: i64,
+    pub to: i64,
+    pub status: JobStatus,
+    /// Stored intervals removed before fetching, for re-ingest jobs
+    pub deleted_intervals: Option<u64>,
+    pub pages_fetched: u64,
+    pub intervals_stored: u64,
+    /// End of the last page stored
+    pub fetched_until: Option<i64>,
+    /// Share of the range fetched so far, from 0 to 1
+    pub progress: f64,
+    pub error: Option<String>,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+pub struct JobRegistry {
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<u64, IngestionJob>>,
+}
+
+impl JobRegistry {
+    pub fn global() -> &'static JobRegistry {
+        JOBS.get_or_init(Self::default)
+    }
+
+    /// Every known job, newest first.
+    pub fn list(&self) -> Vec<IngestionJob> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.values().rev().cloned().collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<IngestionJob> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    fn create(&self, kind: JobKind, range: &JobRange) -> IngestionJob {
+        let job = IngestionJob {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            kind,
+            series: range.series,
+            pool: range.pool.clone(),
+            from: range.from,
+            to: range.to,
+            status: JobStatus::Running,
+            deleted_intervals: None,
+            pages_fetched: 0,
+            intervals_stored: 0,
+            fetched_until: None,
+            progress: 0.0,
+            error: None,
+            started_at: Utc::now().timestamp(),
+            finished_at: None,
+        };
+
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.insert(job.id, job.clone());
+        let finished: Vec<u64> = jobs
+            .values()
+            .filter(|job| job.status != JobStatus::Running)
+            .map(|job| job.id)
+            .collect();
+        for id in finished
+            .iter()
+            .take(finished.len().saturating_sub(MAX_FINISHED_JOBS))
+        {
+            jobs.remove(id);
+        }
+        job
+    }
+
+    fn update(&self, id: u64, change: impl FnOnce(&mut IngestionJob)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            change(job);
+        }
+    }
+}
+
+/// Waits until nothing else writes to `series` and holds it until the guard
+/// is dropped. The scheduled fetch, the gap backfill and admin jobs all take
+/// it, so a re-ingest cannot delete a range while another writer is storing
+/// intervals into it.
+pub async fn lock_series(series: Series) -> tokio::sync::MutexGuard<'static, ()> {
+    let index = Series::ALL
+        .iter()
+        .position(|&other| other == series)
+        .unwrap();
+    SERIES_LOCKS[index].lock().await
+}
+
+/// Records a page stored by the job running on the current task, if any.
+/// Called by the series fetcher after every page.
+pub fn record_page(intervals: usize, end_time: i64) {
+    let Ok(id) = CURRENT_JOB.try_with(|id| *id) else {
+        return;
+    };
+    JobRegistry::global().update(id, |job| {
+        job.pages_fetched += 1;
+        job.intervals_stored += intervals as u64;
+        job.fetched_until = Some(end_time);
+        let span = (job.to - job.from).max(1) as f64;
+        job.progress = ((end_time - job.from) as f64 / span).clamp(0.0, 1.0);
+    });
+}
+
+/// Starts `kind` over `range` in the background and returns the job as it
+/// was registered. Its progress is read back with `JobRegistry::get`.
+pub fn start_job(db: Mongodb, kind: JobKind, range: JobRange) -> IngestionJob {
+    let registry = JobRegistry::global();
+    let job = registry.create(kind, &range);
+    let id = job.id;
+
+    actix_web::rt::spawn(CURRENT_JOB.scope(id, async move {
+        let result = run_job(&db, id, kind, &range).await;
+        registry.update(id, |job| {
+            job.finished_at = Some(Utc::now().timestamp());
+            match result {
+                Ok(()) => {
+                    job.status = JobStatus::Succeeded;
+                    job.progress = 1.0;
+                    info!("Ingestion job {} finished", id);
+                }
+                Err(e) => {
+                    error!("Ingestion job {} failed: {}", id, e);
+                    job.status = JobStatus::Failed;
+                    job.error = Some(e.to_string());
+                }
+            }
+        });
+    }));
+
+    job
+}
+
+async fn run_job(
+    db: &Mongodb,
+    id: u64,
+    kind: JobKind,
+    range: &JobRange,
+) -> Result<(), ServiceError> {
+    let pool = range.pool.as_deref();
+    let _series = lock_series(range.series).await;
+
+    if kind == JobKind::Reingest {
+        let deleted = db
+            .delete_range(range.series, pool, range.from, range.to)
+            .await?;
+        JobRegistry::global().update(id, |job| job.deleted_intervals = Some(deleted));
+        ResponseCache::global().invalidate(range.series);
+    }
+
+    fetch_range(
+        range.series,
+        pool,
+        INGESTION_INTERVAL,
+        range.from,
+        range.to,
+        &db.client,
+    )
+    .await
+}
@@ -0,0 +1,452 @@
+use crate::error::ServiceError;
+use chrono::{DateTime, Utc};
+use dotenv::dotenv;
+use log::{info, warn};
+use rand::Rng;
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+const DEFAULT_MIDGARD_URL: &str = "https://midgard.ninerealms.com";
+/// Consecutive failures after which an endpoint is only tried as a last resort.
+const UNHEALTHY_AFTER_FAILURES: u32 = 3;
+/// How long an unhealthy endpoint is deprioritised before it is retried normally.
+const UNHEALTHY_COOLDOWN: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_RETRIES: u32 = 5;
+const DEFAULT_REQUESTS_PER_SECOND: f64 = 1.0;
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// Longest gap the request budget puts between two requests, however low the
+/// configured rate.
+const MAX_REQUEST_SPACING: Duration = Duration::from_secs(3600);
+
+static MIDGARD_CLIENT: OnceLock<MidgardClient> = OnceLock::new();
+
+/// Outbound request budget shared by every fetcher. Requests are spaced
+/// evenly at the configured rate, and a `Retry-After` from Midgard pushes the
+/// next slot back for everyone.
+#[derive(Debug)]
+struct RequestBudget {
+    spacing: Duration,
+    next_slot: tokio::sync::Mutex<Instant>,
+}
+
+impl RequestBudget {
+    /// Spaces requests `1 / requests_per_second` apart, at most
+    /// `MAX_REQUEST_SPACING`; a rate that is not positive and finite gets the
+    /// longest spacing.
+    fn new(requests_per_second: f64) -> Self {
+        let spacing = Duration::try_from_secs_f64(1.0 / requests_per_second)
+            .ok()
+            .filter(|_| requests_per_second > 0.0)
+            .map_or(MAX_REQUEST_SPACING, |spacing| {
+                spacing.min(MAX_REQUEST_SPACING)
+            });
+        Self {
+            spacing,
+            next_slot: tokio::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn acquire(&self) {
+        let wait = {
+            let mut next_slot = self.next_slot.lock().await;
+            let now = Instant::now();
+            let slot = (*next_slot).max(now);
+            *next_slot = slot + self.spacing;
+            slot - now
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    async fn pause_for(&self, delay: Duration) {
+        let mut next_slot = self.next_slot.lock().await;
+        *next_slot = (*next_slot).max(Instant::now() + delay);
+    }
+}
+
+/// A positive whole number of seconds read from the environment variable `name`.
+fn timeout_from_env(name: &str) -> Option<Duration> {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// Why a pass over the endpoints failed.
+enum RequestFailure {
+    /// Worth retrying: connection errors, 5xx and 429 responses.
+    Retryable(String),
+    /// Any other non-success status; retrying would not help.
+    Fatal(String),
+}
+
+/// Exponential backoff for the given retry attempt with equal jitter: the
+/// delay is a random value between half and all of the capped exponential.
+fn backoff_delay(attempt: u32) -> Duration {
+    let exponential = BACKOFF_BASE.saturating_mul(2u32.saturating_pow(attempt));
+    let capped = exponential.min(BACKOFF_MAX);
+    let half = capped / 2;
+    half + half.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+/// Parses a `Retry-After` header given either in seconds or as an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let retry_at = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    let delay = retry_at.with_timezone(&Utc) - Utc::now();
+    Some(delay.to_std().unwrap_or_default())
+}
+
+#[derive(Debug, Default)]
+struct EndpointHealth {
+    consecutive_failures: u32,
+    total_failures: u64,
+    unhealthy_until: Option<Instant>,
+    last_error: Option<String>,
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    base_url: String,
+    health: Mutex<EndpointHealth>,
+}
+
+impl Endpoint {
+    fn is_healthy(&self) -> bool {
+        let health = self.health.lock().unwrap();
+        match health.unhealthy_until {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+
+    fn record_success(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_failures = 0;
+        health.unhealthy_until = None;
+        health.last_error = None;
+    }
+
+    fn record_failure(&self, error: String) {
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_failures += 1;
+        health.total_failures += 1;
+        health.last_error = Some(error);
+        if health.consecutive_failures >= UNHEALTHY_AFTER_FAILURES {
+            health.unhealthy_until = Some(Instant::now() + UNHEALTHY_COOLDOWN);
+        }
+    }
+}
+
+/// Health snapshot of one configured Midgard endpoint.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointStatus {
+    pub base_url: String,
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    pub total_failures: u64,
+    pub last_error: Option<String>,
+}
+
+/// HTTP client for the Midgard API that rotates through the configured
+/// upstreams when one is unreachable or answers with a 5xx, and retries with
+/// exponential backoff when every upstream fails.
+#[derive(Debug)]
+pub struct MidgardClient {
+    http: reqwest::Client,
+    endpoints: Vec<Endpoint>,
+    preferred: AtomicUsize,
+    max_retries: u32,
+    budget: RequestBudget,
+}
+
+impl MidgardClient {
+    /// `connect_timeout` bounds establishing a connection and `timeout` the
+    /// whole request, body included, so a hung endpoint fails over to the
+    /// next one instead of stalling the fetch.
+    pub fn new(
+        base_urls: Vec<String>,
+        max_retries: u32,
+        requests_per_second: f64,
+        connect_timeout: Duration,
+        timeout: Duration,
+    ) -> Self {
+        let http = reqwest::Client::builder()
+            .connect_timeout(connect_timeout)
+            .timeout(timeout)
+            .build()
+            .expect("Failed to build the Midgard HTTP client");
+        let endpoints = base_urls
+            .into_iter()
+            .map(|base_url| Endpoint {
+                base_url: base_url.trim_end_matches('/').to_string(),
+                health: Mutex::new(EndpointHealth::default()),
+            })
+            .collect();
+
+        Self {
+            http,
+            endpoints,
+            preferred: AtomicUsize::new(0),
+            max_retries,
+            budget: RequestBudget::new(requests_per_second),
+        }
+    }
+
+    /// Reads the comma-separated `MIDGARD_URLS` list, falling back to the
+    /// public Nine Realms endpoint, along with `MIDGARD_MAX_RETRIES`, the
+    /// shared `MIDGARD_REQUESTS_PER_SECOND` budget and the
+    /// `MIDGARD_CONNECT_TIMEOUT_SECS` and `MIDGARD_TIMEOUT_SECS` timeouts.
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        let max_retries = env::var("MIDGARD_MAX_RETRIES")
+            .ok()
+            .and_then(|value| value.parse::<u32>().ok())
+            .unwrap_or(DEFAULT_MAX_RETRIES);
+        let requests_per_second = env::var("MIDGARD_REQUESTS_PER_SECOND")
+            .ok()
+            .and_then(|value| match value.trim().parse::<f64>() {
+                Ok(rate) if rate.is_finite() && rate > 0.0 => Some(rate),
+                _ => {
+                    warn!(
+                        "Ignoring MIDGARD_REQUESTS_PER_SECOND={}: expected a positive number",
+                        value
+                    );
+                    None
+                }
+            })
+            .unwrap_or(DEFAULT_REQUESTS_PER_SECOND);
+        let connect_timeout =
+            timeout_from_env("MIDGARD_CONNECT_TIMEOUT_SECS").unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+        let timeout = timeout_from_env("MIDGARD_TIMEOUT_SECS").unwrap_or(DEFAULT_REQUEST_TIMEOUT);
+
+        let base_urls: Vec<String> = env::var("MIDGARD_URLS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        let base_urls = if base_urls.is_empty() {
+            vec![DEFAULT_MIDGARD_URL.to_string()]
+        } else {
+            info!("Using Midgard endpoints: {}", base_urls.join(", "));
+            base_urls
+        };
+        Self::new(
+            base_urls,
+            max_retries,
+            requests_per_second,
+            connect_timeout,
+            timeout,
+        )
+    }
+
+    pub fn global() -> &'static MidgardClient {
+        MIDGARD_CLIENT.get_or_init(Self::from_env)
+    }
+
+    pub fn endpoint_statuses(&self) -> Vec<EndpointStatus> {
+        self.endpoints
+            .iter()
+            .map(|endpoint| {
+                let healthy = endpoint.is_healthy();
+                let health = endpoint.health.lock().unwrap();
+                EndpointStatus {
+                    base_url: endpoint.base_url.clone(),
+                    healthy,
+                    consecutive_failures: health.consecutive_failures,
+                    total_failures: health.total_failures,
+                    last_error: health.last_error.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Endpoint indexes in the order they should be tried: healthy endpoints
+    /// first, starting from the last one that succeeded, then unhealthy ones.
+    fn attempt_order(&self) -> Vec<usize> {
+        let count = self.endpoints.len();
+        let start = self.preferred.load(Ordering::Relaxed);
+        let rotated: Vec<usize> = (0..count).map(|offset| (start + offset) % count).collect();
+
+        let (mut healthy, unhealthy): (Vec<usize>, Vec<usize>) = rotated
+            .into_iter()
+            .partition(|&index| self.endpoints[index].is_healthy());
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    /// Sends a GET for `path_and_query` (e.g. `/v2/pools`) and returns the
+    /// response body. Each attempt tries every endpoint in turn; when all of
+    /// them fail with a retryable error the request is retried with backoff,
+    /// up to the configured number of retries.
+    pub async fn get_text(&self, path_and_query: &str) -> Result<String, ServiceError> {
+        let mut attempt = 0;
+
+        loop {
+            match self.try_endpoints(path_and_query).await {
+                Ok(text) => return Ok(text),
+                Err(RequestFailure::Fatal(e)) => return Err(ServiceError::Midgard(e)),
+                Err(RequestFailure::Retryable(e)) if attempt >= self.max_retries => {
+                    return Err(ServiceError::Midgard(format!(
+                        "{} (gave up after {} retries)",
+                        e, attempt
+                    )));
+                }
+                Err(RequestFailure::Retryable(e)) => {
+                    let delay = backoff_delay(attempt);
+                    warn!(
+                        "Request for {} failed: {}. Retrying in {:?}",
+                        path_and_query, e, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Makes one pass over the endpoints. Connection errors, 5xx and 429
+    /// responses move on to the next endpoint; a 429 also pauses the shared
+    /// budget for its `Retry-After`.
+    async fn try_endpoints(&self, path_and_query: &str) -> Result<String, RequestFailure> {
+        let mut last_error = String::from("No Midgard endpoints configured");
+
+        for index in self.attempt_order() {
+            let endpoint = &self.endpoints[index];
+            let url = format!("{}{}", endpoint.base_url, path_and_query);
+
+            self.budget.acquire().await;
+            println!("Fetching URL: {}", url);
+
+            let response = match self.http.get(&url).send().await {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!("Midgard endpoint {} unreachable: {}", endpoint.base_url, e);
+                    endpoint.record_failure(e.to_string());
+                    last_error = format!("Request to {} failed: {}", endpoint.base_url, e);
+                    continue;
+                }
+            };
+
+            let status = response.status();
+            println!("Response status: {}", status);
+
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                let retry_after = response
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(parse_retry_after);
+                if let Some(delay) = retry_after {
+                    warn!(
+                        "Midgard endpoint {} rate limited us, pausing requests for {:?}",
+                        endpoint.base_url, delay
+                    );
+                    self.budget.pause_for(delay).await;
+                }
+                last_error = format!("Request failed with status: {}", status);
+                continue;
+            }
+
+            if status.is_server_error() {
+                warn!("Midgard endpoint {} returned {}", endpoint.base_url, status);
+                endpoint.record_failure(format!("Status {}", status));
+                last_error = format!("Request failed with status: {}", status);
+                continue;
+            }
+
+            if !status.is_success() {
+                return Err(RequestFailure::Fatal(format!(
+                    "Request failed with status: {}",
+                    status
+                )));
+            }
+
+            match response.text().await {
+                Ok(text) => {
+                    endpoint.record_success();
+                    self.preferred.store(index, Ordering::Relaxed);
+                    return Ok(text);
+                }
+                Err(e) => {
+                    endpoint.record_failure(e.to_string());
+                    last_error =
+                        format!("Failed to read response from {}: {}", endpoint.base_url, e);
+                }
+            }
+        }
+
+        Err(RequestFailure::Retryable(last_error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_with_jitter_up_to_the_cap() {
+        for attempt in 0..8 {
+            let full = BACKOFF_BASE * 2u32.pow(attempt);
+            let full = full.min(BACKOFF_MAX);
+            let delay = backoff_delay(attempt);
+            assert!(
+                delay >= full / 2 && delay <= full,
+                "attempt {attempt}: {delay:?}"
+            );
+        }
+        assert!(backoff_delay(u32::MAX) <= BACKOFF_MAX);
+    }
+
+    #[test]
+    fn budget_spacing_is_clamped() {
+        assert_eq!(RequestBudget::new(4.0).spacing, Duration::from_millis(250));
+        assert_eq!(RequestBudget::new(f64::INFINITY).spacing, Duration::ZERO);
+        for rate in [1e-300, f64::MIN_POSITIVE, 0.0, -1.0, f64::NAN] {
+            assert_eq!(RequestBudget::new(rate).spacing, MAX_REQUEST_SPACING);
+        }
+    }
+
+    #[test]
+    fn retry_after_takes_seconds_or_a_date() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+
+        let in_a_minute = (Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+        let delay = parse_retry_after(&in_a_minute).unwrap();
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60));
+
+        // A date in the past means retrying right away.
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn retry_after_rejects_garbage() {
+        assert_eq!(parse_retry_after(""), None);
+        assert_eq!(parse_retry_after("-5"), None);
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+}
@@ -1,5 +1,14 @@
+pub mod api_keys;
 pub mod fetch_depth_price_history;
 pub mod fetch_earnings_history;
+pub mod fetch_pools;
 pub mod fetch_runepool_members_units_history;
 pub mod fetch_swaps_history;
+pub mod gap_detection;
+pub mod ingestion_jobs;
+pub mod midgard_client;
+pub mod parquet_export;
+pub mod rate_limiter;
+pub mod response_cache;
 pub mod scheduler;
+pub mod series_fetcher;
@@ -0,0 +1,490 @@
+use crate::database::db::Mongodb;
+use crate::error::ServiceError;
+use crate::models::depth_price_history::DepthPriceHistory;
+use crate::models::earnings_history::EarningsHistory;
+use crate::models::earnings_history_pools::EarningsHistoryPools;
+use crate::models::runepool_members_units_history::RunePoolTotalMembersHistory;
+use crate::models::swaps_history::SwapsHistory;
+use futures_util::TryStreamExt;
+use log::info;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::FindOptions;
+use mongodb::Collection;
+use parquet::basic::{Compression, LogicalType, Repetition, Type as PhysicalType};
+use parquet::data_type::{
+    ByteArray, ByteArrayType, DoubleType, FixedLenByteArray, FixedLenByteArrayType, Int64Type,
+};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedColumnWriter, SerializedFileWriter};
+use parquet::schema::types::Type;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+/// Rows buffered per Parquet row group.
+const ROWS_PER_GROUP: usize = 50_000;
+
+/// A stored history collection that can be exported to Parquet.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum ExportCollection {
+    DepthHistory,
+    SwapsHistory,
+    EarningsHistory,
+    EarningsHistoryPools,
+    RunepoolMembersHistory,
+}
+
+/// Parquet type of a stored field, following its type in the model struct.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnKind {
+    /// `i64` fields.
+    Int64,
+    /// `f64` fields.
+    Double,
+    /// `Amount` fields, written as DECIMAL(38, 0).
+    Amount,
+    /// `String` fields.
+    Text,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ParquetColumn {
+    name: &'static str,
+    kind: ColumnKind,
+    /// `Option` fields in the model.
+    optional: bool,
+}
+
+/// Columns of a model's collection: `pool` when it has one, then the model's
+/// `fields` in declaration order. `amount_fields` are written as decimals;
+/// the other columns take the type the field has in `sample`, a row with its
+/// optional fields set. `Option` fields, which default to null, are optional.
+fn model_columns<T: Default + Serialize>(
+    sample: &T,
+    pool: bool,
+    fields: &[&'static str],
+    amount_fields: &[&str],
+) -> Result<Vec<ParquetColumn>, ServiceError> {
+    let defaults = mongodb::bson::to_document(&T::default())?;
+    let sample = mongodb::bson::to_document(sample)?;
+    let names = pool
+        .then_some("pool")
+        .into_iter()
+        .chain(fields.iter().copied());
+    names
+        .map(|name| {
+            let default = defaults.get(name);
+            let kind = if amount_fields.contains(&name) {
+                ColumnKind::Amount
+            } else {
+                match sample.get(name) {
+                    Some(Bson::Int64(_) | Bson::Int32(_)) => ColumnKind::Int64,
+                    Some(Bson::Double(_)) => ColumnKind::Double,
+                    // `pool` is a `String`, or an `Option<String>` where a
+                    // row may have none.
+                    Some(Bson::String(_)) => ColumnKind::Text,
+                    Some(Bson::Null) if name == "pool" => ColumnKind::Text,
+                    _ => {
+                        return Err(ServiceError::Export(format!(
+                            "no Parquet type for {}",
+                            name
+                        )))
+                    }
+                }
+            };
+            Ok(ParquetColumn {
+                name,
+                kind,
+                optional: matches!(default, Some(Bson::Null)),
+            })
+        })
+        .collect()
+}
+
+impl ExportCollection {
+    pub fn collection_name(&self) -> &'static str {
+        match self {
+            ExportCollection::DepthHistory => "depth_history",
+            ExportCollection::SwapsHistory => "swaps_history",
+            ExportCollection::EarningsHistory => "earnings_history",
+            ExportCollection::EarningsHistoryPools => "earnings_history_pools",
+            ExportCollection::RunepoolMembersHistory => "runepool_members_history",
+        }
+    }
+
+    /// Whether the collection's rows belong to a pool.
+    pub fn has_pool(&self) -> bool {
+        matches!(
+            self,
+            ExportCollection::DepthHistory
+                | ExportCollection::SwapsHistory
+                | ExportCollection::EarningsHistoryPools
+        )
+    }
+
+    fn columns(&self) -> Result<Vec<ParquetColumn>, ServiceError> {
+        let pool = self.has_pool();
+        match self {
+            ExportCollection::DepthHistory => model_columns(
+                &DepthPriceHistory::default(),
+                pool,
+                &DepthPriceHistory::FIELDS,
+                &DepthPriceHistory::AMOUNT_FIELDS,
+            ),
+            ExportCollection::SwapsHistory => model_columns(
+                &SwapsHistory::default(),
+                pool,
+                &SwapsHistory::FIELDS,
+                &SwapsHistory::AMOUNT_FIELDS,
+            ),
+            ExportCollection::EarningsHistory => model_columns(
+                &EarningsHistory {
+                    rune_price_usd: Some(0.0),
+                    ..Default::default()
+                },
+                pool,
+                &EarningsHistory::FIELDS,
+                &EarningsHistory::AMOUNT_FIELDS,
+            ),
+            ExportCollection::EarningsHistoryPools => model_columns(
+                &EarningsHistoryPools::default(),
+                pool,
+                &EarningsHistoryPools::FIELDS,
+                &EarningsHistoryPools::AMOUNT_FIELDS,
+            ),
+            ExportCollection::RunepoolMembersHistory => model_columns(
+                &RunePoolTotalMembersHistory::default(),
+                pool,
+                &RunePoolTotalMembersHistory::FIELDS,
+                &RunePoolTotalMembersHistory::AMOUNT_FIELDS,
+            ),
+        }
+    }
+}
+
+/// Rows to export: the stored intervals inside `[from, to]`, optionally of
+/// one pool.
+#[derive(Debug, Default)]
+pub struct ExportFilter {
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub pool: Option<String>,
+}
+
+impl ExportFilter {
+    fn to_document(&self) -> Document {
+        let mut filter = doc! {};
+        if let Some(from) = self.from {
+            filter.insert("start_time", doc! { "$gte": from });
+        }
+        if let Some(to) = self.to {
+            filter.insert("end_time", doc! { "$lte": to });
+        }
+        if let Some(pool) = &self.pool {
+            filter.insert("pool", pool);
+        }
+        filter
+    }
+}
+
+fn schema(columns: &[ParquetColumn]) -> Result<Type, ServiceError> {
+    let mut fields = Vec::with_capacity(columns.len());
+    for column in columns {
+        let repetition = if column.optional {
+            Repetition::OPTIONAL
+        } else {
+            Repetition::REQUIRED
+        };
+        let field = match column.kind {
+            ColumnKind::Int64 => Type::primitive_type_builder(column.name, PhysicalType::INT64),
+            ColumnKind::Double => Type::primitive_type_builder(column.name, PhysicalType::DOUBLE),
+            ColumnKind::Amount => {
+                Type::primitive_type_builder(column.name, PhysicalType::FIXED_LEN_BYTE_ARRAY)
+                    .with_length(16)
+                    .with_logical_type(Some(LogicalType::Decimal {
+                        scale: 0,
+                        precision: 38,
+                    }))
+                    .with_precision(38)
+                    .with_scale(0)
+            }
+            ColumnKind::Text => Type::primitive_type_builder(column.name, PhysicalType::BYTE_ARRAY)
+                .with_logical_type(Some(LogicalType::String)),
+        }
+        .with_repetition(repetition)
+        .build()?;
+        fields.push(Arc::new(field));
+    }
+    Ok(Type::group_type_builder("schema")
+        .with_fields(fields)
+        .build()?)
+}
+
+enum ColumnValues {
+    Int64(Vec<i64>),
+    Double(Vec<f64>),
+    Amount(Vec<FixedLenByteArray>),
+    Text(Vec<ByteArray>),
+}
+
+/// One row group's worth of values of a column.
+struct ColumnBuffer {
+    column: ParquetColumn,
+    values: ColumnValues,
+    definition_levels: Vec<i16>,
+}
+
+impl ColumnBuffer {
+    fn new(column: ParquetColumn) -> Self {
+        let values = match column.kind {
+            ColumnKind::Int64 => ColumnValues::Int64(Vec::new()),
+            ColumnKind::Double => ColumnValues::Double(Vec::new()),
+            ColumnKind::Amount => ColumnValues::Amount(Vec::new()),
+            ColumnKind::Text => ColumnValues::Text(Vec::new()),
+        };
+        Self {
+            column,
+            values,
+            definition_levels: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, row: &Document) -> Result<(), ServiceError> {
+        let name = self.column.name;
+        let value = match row.get(name) {
+            None | Some(Bson::Null) if self.column.optional => {
+                self.definition_levels.push(0);
+                return Ok(());
+            }
+            None | Some(Bson::Null) => {
+                return Err(ServiceError::Export(format!("missing value for {}", name)))
+            }
+            Some(value) => value,
+        };
+        self.definition_levels.push(1);
+
+        match (&mut self.values, value) {
+            (ColumnValues::Int64(values), Bson::Int64(value)) => values.push(*value),
+            (ColumnValues::Int64(values), Bson::Int32(value)) => values.push(*value as i64),
+            (ColumnValues::Double(values), Bson::Double(value)) => values.push(*value),
+            (ColumnValues::Amount(values), Bson::Decimal128(value)) => {
+                let amount = value.to_string().parse::<i128>().map_err(|_| {
+                    ServiceError::Export(format!("{} is not an integer amount: {}", name, value))
+                })?;
+                values.push(FixedLenByteArray::from(amount.to_be_bytes().to_vec()));
+            }
+            (ColumnValues::Text(values), Bson::String(value)) => {
+                values.push(ByteArray::from(value.as_str()))
+            }
+            (_, value) => {
+                return Err(ServiceError::Export(format!(
+                    "unexpected value for {}: {}",
+                    name, value
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, writer: &mut SerializedColumnWriter<'_>) -> Result<(), ServiceError> {
+        let levels = if self.column.optional {
+            Some(self.definition_levels.as_slice())
+        } else {
+            None
+        };
+        match &mut self.values {
+            ColumnValues::Int64(values) => {
+                writer
+                    .typed::<Int64Type>()
+                    .write_batch(values, levels, None)?;
+                values.clear();
+            }
+            ColumnValues::Double(values) => {
+                writer
+                    .typed::<DoubleType>()
+                    .write_batch(values, levels, None)?;
+                values.clear();
+            }
+            ColumnValues::Amount(values) => {
+                writer
+                    .typed::<FixedLenByteArrayType>()
+                    .write_batch(values, levels, None)?;
+                values.clear();
+            }
+            ColumnValues::Text(values) => {
+                writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(values, levels, None)?;
+                values.clear();
+            }
+        }
+        self.definition_levels.clear();
+        Ok(())
+    }
+}
+
+fn write_row_group<W: Write + Send>(
+    writer: &mut SerializedFileWriter<W>,
+    buffers: &mut [ColumnBuffer],
+) -> Result<(), ServiceError> {
+    let mut row_group = writer.next_row_group()?;
+    for buffer in buffers.iter_mut() {
+        let mut column = row_group.next_column()?.ok_or_else(|| {
+            ServiceError::Export("row group has fewer columns than the schema".to_string())
+        })?;
+        buffer.write(&mut column)?;
+        column.close()?;
+    }
+    row_group.close()?;
+    Ok(())
+}
+
+async fn write_rows<T, W>(
+    collection: &Collection<T>,
+    export: ExportCollection,
+    filter: &ExportFilter,
+    out: W,
+) -> Result<usize, ServiceError>
+where
+    T: DeserializeOwned + Serialize + Unpin + Send + Sync,
+    W: Write + Send,
+{
+    let columns = export.columns()?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer =
+        SerializedFileWriter::new(out, Arc::new(schema(&columns)?), Arc::new(properties))?;
+    let mut buffers: Vec<ColumnBuffer> = columns.into_iter().map(ColumnBuffer::new).collect();
+
+    let sort = if export.has_pool() {
+        doc! { "pool": 1, "start_time": 1 }
+    } else {
+        doc! { "start_time": 1 }
+    };
+    let options = FindOptions::builder().sort(sort).build();
+    let mut cursor = collection.find(filter.to_document(), options).await?;
+
+    let mut rows = 0;
+    let mut buffered = 0;
+    // Rows are read through the model struct so every column has the
+    // model's type, including defaults for fields missing on older rows.
+    while let Some(model) = cursor.try_next().await? {
+        let row = mongodb::bson::to_document(&model)?;
+        for buffer in buffers.iter_mut() {
+            buffer.push(&row)?;
+        }
+        rows += 1;
+        buffered += 1;
+        if buffered == ROWS_PER_GROUP {
+            write_row_group(&mut writer, &mut buffers)?;
+            buffered = 0;
+        }
+    }
+    if buffered > 0 {
+        write_row_group(&mut writer, &mut buffers)?;
+    }
+    writer.close()?;
+
+    Ok(rows)
+}
+
+/// Writes the rows of `collection` selected by `filter` to `out` as a
+/// Parquet file, typed after the collection's model struct. Returns the
+/// number of rows written.
+pub async fn export_parquet<W: Write + Send>(
+    db: &Mongodb,
+    collection: ExportCollection,
+    filter: &ExportFilter,
+    out: W,
+) -> Result<usize, ServiceError> {
+    if filter.pool.is_some() && !collection.has_pool() {
+        return Err(ServiceError::Export(format!(
+            "{} has no pool field",
+            collection.collection_name()
+        )));
+    }
+
+    let rows = match collection {
+        ExportCollection::DepthHistory => {
+            write_rows(&db.depth_history, collection, filter, out).await?
+        }
+        ExportCollection::SwapsHistory => {
+            write_rows(&db.swaps_history, collection, filter, out).await?
+        }
+        ExportCollection::EarningsHistory => {
+            write_rows(&db.earnings_history, collection, filter, out).await?
+        }
+        ExportCollection::EarningsHistoryPools => {
+            write_rows(&db.earnings_history_pools, collection, filter, out).await?
+        }
+        ExportCollection::RunepoolMembersHistory => {
+            write_rows(&db.runepool_members_history, collection, filter, out).await?
+        }
+    };
+
+    info!(
+        "Exported {} rows of {} to Parquet",
+        rows,
+        collection.collection_name()
+    );
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [ExportCollection; 5] = [
+        ExportCollection::DepthHistory,
+        ExportCollection::SwapsHistory,
+        ExportCollection::EarningsHistory,
+        ExportCollection::EarningsHistoryPools,
+        ExportCollection::RunepoolMembersHistory,
+    ];
+
+    fn column<'a>(columns: &'a [ParquetColumn], name: &str) -> &'a ParquetColumn {
+        columns.iter().find(|column| column.name == name).unwrap()
+    }
+
+    #[test]
+    fn columns_follow_the_models() {
+        for collection in ALL {
+            let columns = collection.columns().unwrap();
+            assert!(schema(&columns).is_ok());
+            assert_eq!(
+                columns.first().map(|column| column.name) == Some("pool"),
+                collection.has_pool()
+            );
+            assert_eq!(column(&columns, "start_time").kind, ColumnKind::Int64);
+        }
+
+        let swaps = ExportCollection::SwapsHistory.columns().unwrap();
+        assert_eq!(swaps.len(), SwapsHistory::FIELDS.len() + 1);
+        assert!(column(&swaps, "pool").optional);
+        assert_eq!(column(&swaps, "total_volume").kind, ColumnKind::Amount);
+        assert_eq!(column(&swaps, "average_slip").kind, ColumnKind::Double);
+        assert_eq!(column(&swaps, "total_count").kind, ColumnKind::Int64);
+
+        let depth = ExportCollection::DepthHistory.columns().unwrap();
+        assert!(!column(&depth, "pool").optional);
+        assert_eq!(column(&depth, "pool").kind, ColumnKind::Text);
+
+        let runepool = ExportCollection::RunepoolMembersHistory.columns().unwrap();
+        let depth_column = column(&runepool, "depth");
+        assert!(depth_column.optional);
+        assert_eq!(depth_column.kind, ColumnKind::Amount);
+        assert!(!column(&runepool, "units").optional);
+
+        let earnings = ExportCollection::EarningsHistory.columns().unwrap();
+        let price = column(&earnings, "rune_price_usd");
+        assert!(price.optional);
+        assert_eq!(price.kind, ColumnKind::Double);
+        assert!(column(&earnings, "bonding_earnings").optional);
+        assert!(!column(&earnings, "block_rewards").optional);
+    }
+}
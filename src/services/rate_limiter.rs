@@ -0,0 +1,334 @@
+use crate::database::db::Mongodb;
+use crate::models::api_key::RateLimitTier;
+use chrono::Utc;
+use dotenv::dotenv;
+use mongodb::bson::DateTime;
+use mongodb::error::Error as MongoError;
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+const DEFAULT_ANONYMOUS_REQUESTS_PER_MINUTE: u32 = 60;
+const DEFAULT_ANONYMOUS_BURST: u32 = 20;
+const DEFAULT_ANONYMOUS_DAILY_QUOTA: u64 = 5_000;
+/// How often `prune` runs.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+const SECONDS_PER_DAY: i64 = 86_400;
+
+static RATE_LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    /// When the bucket will be full again if the client makes no requests.
+    full_at: Instant,
+}
+
+/// Why a request was rejected, with the seconds until it can be retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    RateLimited { retry_after: u64 },
+    QuotaExceeded { retry_after: u64 },
+}
+
+/// Daily quota of a client after a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaStatus {
+    pub limit: u64,
+    pub remaining: u64,
+    /// Seconds until the next UTC day, when the quota resets.
+    pub reset: u64,
+}
+
+/// State of a client's limits after a request, for the `X-RateLimit-*`
+/// response headers.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitStatus {
+    /// Bucket size of the tier.
+    pub limit: u32,
+    /// Requests that can be made right now.
+    pub remaining: u32,
+    /// Seconds until the bucket is full again.
+    pub reset: u64,
+    /// The daily quota, for requests that got as far as counting it.
+    pub quota: Option<QuotaStatus>,
+    pub rejection: Option<Rejection>,
+}
+
+/// Token buckets of every client, held in memory so bursts are rejected
+/// without a database round trip; each instance of the service refills its
+/// own. Daily quotas are counted in MongoDB instead, so they hold across
+/// restarts and instances.
+#[derive(Debug)]
+pub struct RateLimiter {
+    anonymous: RateLimitTier,
+    trust_proxy_headers: bool,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(anonymous: RateLimitTier, trust_proxy_headers: bool) -> Self {
+        Self {
+            anonymous,
+            trust_proxy_headers,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reads the anonymous tier from `ANONYMOUS_REQUESTS_PER_MINUTE`,
+    /// `ANONYMOUS_BURST` and `ANONYMOUS_DAILY_QUOTA` (`0` for no quota), and
+    /// whether clients are identified by proxy headers from
+    /// `TRUST_PROXY_HEADERS`.
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        let requests_per_minute = env::var("ANONYMOUS_REQUESTS_PER_MINUTE")
+            .ok()
+            .and_then(|value| value.parse::<u32>().ok())
+            .unwrap_or(DEFAULT_ANONYMOUS_REQUESTS_PER_MINUTE);
+        let burst = env::var("ANONYMOUS_BURST")
+            .ok()
+            .and_then(|value| value.parse::<u32>().ok())
+            .unwrap_or(DEFAULT_ANONYMOUS_BURST);
+        let daily_quota = env::var("ANONYMOUS_DAILY_QUOTA")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_ANONYMOUS_DAILY_QUOTA);
+        let trust_proxy_headers = env::var("TRUST_PROXY_HEADERS")
+            .ok()
+            .and_then(|value| value.parse::<bool>().ok())
+            .unwrap_or(false);
+
+        Self::new(
+            RateLimitTier {
+                requests_per_minute,
+                burst,
+                daily_quota: (daily_quota > 0).then_some(daily_quota),
+            },
+            trust_proxy_headers,
+        )
+    }
+
+    pub fn global() -> &'static RateLimiter {
+        RATE_LIMITER.get_or_init(Self::from_env)
+    }
+
+    pub fn anonymous_tier(&self) -> RateLimitTier {
+        self.anonymous
+    }
+
+    /// Whether anonymous clients are identified by the `Forwarded` and
+    /// `X-Forwarded-For` headers rather than by the connection's address.
+    /// Only safe behind a proxy that overwrites those headers, since clients
+    /// could otherwise pick a fresh address for every request.
+    pub fn trust_proxy_headers(&self) -> bool {
+        self.trust_proxy_headers
+    }
+
+    /// Takes one request from `client`'s bucket and, when `tier` has one,
+    /// its daily quota in `db`. A rejected request consumes neither, except
+    /// that requests over the quota are still counted.
+    pub async fn check(
+        &self,
+        db: &Mongodb,
+        client: &str,
+        tier: RateLimitTier,
+    ) -> Result<RateLimitStatus, MongoError> {
+        let status = self.take_token(client, tier);
+        let Some(limit) = tier.daily_quota else {
+            return Ok(status);
+        };
+        if status.rejection.is_some() {
+            return Ok(status);
+        }
+
+        let now = Utc::now();
+        let timestamp = now.timestamp();
+        let tomorrow = (timestamp.div_euclid(SECONDS_PER_DAY) + 1) * SECONDS_PER_DAY;
+        let day = now.format("%Y-%m-%d").to_string();
+        let expires_at = DateTime::from_millis((tomorrow + SECONDS_PER_DAY) * 1000);
+        let used = match db.increment_quota_usage(client, &day, expires_at).await {
+            Ok(used) => used,
+            Err(e) => {
+                self.return_token(client, tier);
+                return Err(e);
+            }
+        };
+
+        let (quota, rejection) = quota_status(limit, used, (tomorrow - timestamp) as u64);
+        let mut status = match rejection {
+            Some(_) => self.return_token(client, tier),
+            None => status,
+        };
+        status.quota = Some(quota);
+        status.rejection = rejection;
+        Ok(status)
+    }
+
+    /// Takes one token from `client`'s bucket, if it has one.
+    fn take_token(&self, client: &str, tier: RateLimitTier) -> RateLimitStatus {
+        self.update_bucket(client, tier, |bucket, per_second| {
+            if bucket.tokens < 1.0 {
+                Some(Rejection::RateLimited {
+                    retry_after: ((1.0 - bucket.tokens) / per_second).ceil() as u64,
+                })
+            } else {
+                bucket.tokens -= 1.0;
+                None
+            }
+        })
+    }
+
+    /// Gives back the token of a request rejected after `take_token`.
+    fn return_token(&self, client: &str, tier: RateLimitTier) -> RateLimitStatus {
+        let burst = f64::from(tier.burst.max(1));
+        self.update_bucket(client, tier, |bucket, _| {
+            bucket.tokens = (bucket.tokens + 1.0).min(burst);
+            None
+        })
+    }
+
+    /// Refills `client`'s bucket for the time since it was last used, then
+    /// applies `update` to it.
+    fn update_bucket(
+        &self,
+        client: &str,
+        tier: RateLimitTier,
+        update: impl FnOnce(&mut Bucket, f64) -> Option<Rejection>,
+    ) -> RateLimitStatus {
+        let burst = tier.burst.max(1);
+        let per_second = f64::from(tier.requests_per_minute.max(1)) / 60.0;
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: f64::from(burst),
+            refilled_at: now,
+            full_at: now,
+        });
+
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(f64::from(burst));
+        bucket.refilled_at = now;
+        let rejection = update(bucket, per_second);
+
+        let reset = ((f64::from(burst) - bucket.tokens) / per_second).ceil() as u64;
+        bucket.full_at = now + Duration::from_secs(reset);
+
+        RateLimitStatus {
+            limit: burst,
+            remaining: bucket.tokens.floor() as u32,
+            reset,
+            quota: None,
+            rejection,
+        }
+    }
+
+    /// Forgets clients whose bucket is full: they would start over in the
+    /// same state. Run every `PRUNE_INTERVAL` rather than on requests, so
+    /// requests never wait on a scan of every client.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|_, bucket| now < bucket.full_at);
+    }
+}
+
+/// Quota left after the `used`th request of the day under a `limit`, and
+/// the rejection of that request when it is over the limit.
+fn quota_status(limit: u64, used: u64, until_tomorrow: u64) -> (QuotaStatus, Option<Rejection>) {
+    let quota = QuotaStatus {
+        limit,
+        remaining: limit.saturating_sub(used),
+        reset: until_tomorrow,
+    };
+    let rejection = (used > limit).then_some(Rejection::QuotaExceeded {
+        retry_after: until_tomorrow,
+    });
+    (quota, rejection)
+}
+
+/// Prunes the global limiter every `PRUNE_INTERVAL`, for as long as the
+/// server runs.
+pub async fn prune_periodically() {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        RateLimiter::global().prune();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIER: RateLimitTier = RateLimitTier {
+        requests_per_minute: 60,
+        burst: 2,
+        daily_quota: None,
+    };
+
+    /// Moves `client`'s last refill back by `seconds`, as if that much time
+    /// had passed.
+    fn wait(limiter: &RateLimiter, client: &str, seconds: u64) {
+        let mut buckets = limiter.buckets.lock().unwrap();
+        let bucket = buckets.get_mut(client).unwrap();
+        bucket.refilled_at -= Duration::from_secs(seconds);
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_refills() {
+        let limiter = RateLimiter::new(TIER, false);
+        assert_eq!(limiter.take_token("a", TIER).remaining, 1);
+        assert_eq!(limiter.take_token("a", TIER).remaining, 0);
+        let status = limiter.take_token("a", TIER);
+        assert_eq!(
+            status.rejection,
+            Some(Rejection::RateLimited { retry_after: 1 })
+        );
+        assert_eq!(status.reset, 2);
+
+        // Other clients have their own bucket.
+        assert_eq!(limiter.take_token("b", TIER).rejection, None);
+
+        wait(&limiter, "a", 1);
+        assert_eq!(limiter.take_token("a", TIER).rejection, None);
+        assert!(limiter.take_token("a", TIER).rejection.is_some());
+
+        // Refilling stops at the burst size.
+        wait(&limiter, "a", 10);
+        assert_eq!(limiter.take_token("a", TIER).remaining, 1);
+    }
+
+    #[test]
+    fn quota_rejects_requests_past_the_limit() {
+        let (quota, rejection) = quota_status(2, 1, 3_600);
+        assert_eq!(
+            quota,
+            QuotaStatus {
+                limit: 2,
+                remaining: 1,
+                reset: 3_600
+            }
+        );
+        assert_eq!(rejection, None);
+
+        assert_eq!(quota_status(2, 2, 3_600).1, None);
+        let (quota, rejection) = quota_status(2, 3, 3_600);
+        assert_eq!(quota.remaining, 0);
+        assert_eq!(
+            rejection,
+            Some(Rejection::QuotaExceeded { retry_after: 3_600 })
+        );
+    }
+
+    #[test]
+    fn returned_tokens_stop_at_the_burst_size() {
+        let limiter = RateLimiter::new(TIER, false);
+        assert_eq!(limiter.take_token("a", TIER).remaining, 1);
+        assert_eq!(limiter.return_token("a", TIER).remaining, 2);
+        assert_eq!(limiter.return_token("a", TIER).remaining, 2);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::models::ingestion_checkpoint::Series;
+use actix_web::web::Bytes;
+use dotenv::dotenv;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+const DEFAULT_CAPACITY: usize = 1000;
+const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+static RESPONSE_CACHE: OnceLock<ResponseCache> = OnceLock::new();
+
+/// A cached response body, with the content type it was served with.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub body: Bytes,
+    pub content_type: String,
+}
+
+#[derive(Debug)]
+struct Entry {
+    response: CachedResponse,
+    series: Series,
+    stored_at: Instant,
+    /// Position in `State::recency`.
+    used: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counters {
+    hits: u64,
+    misses: u64,
+    invalidations: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    /// Keys by last use, oldest first.
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+    /// Bumped on every invalidation of a series, so a response computed
+    /// before new intervals were stored is not cached after them.
+    generations: HashMap<Series, u64>,
+    counters: HashMap<Series, Counters>,
+    evictions: u64,
+}
+
+impl State {
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recency.remove(&entry.used);
+            entry.used = clock;
+            self.recency.insert(clock, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.used);
+        }
+    }
+}
+
+/// In-process LRU cache of history responses. Entries expire after a TTL
+/// and are dropped as soon as ingestion stores new intervals for their series.
+#[derive(Debug)]
+pub struct ResponseCache {
+    capacity: usize,
+    ttl: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SeriesCacheStats {
+    pub series: Series,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Times ingestion dropped the series' entries after storing new intervals
+    pub invalidations: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    pub enabled: bool,
+    pub capacity: usize,
+    pub ttl_seconds: u64,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped to stay within capacity
+    pub evictions: u64,
+    pub series: Vec<SeriesCacheStats>,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Reads `RESPONSE_CACHE_CAPACITY` and `RESPONSE_CACHE_TTL_SECS`. A
+    /// capacity or TTL of `0` disables the cache.
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        let capacity = env::var("RESPONSE_CACHE_CAPACITY")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_CAPACITY);
+        let ttl = env::var("RESPONSE_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TTL);
+
+        Self::new(capacity, ttl)
+    }
+
+    pub fn global() -> &'static ResponseCache {
+        RESPONSE_CACHE.get_or_init(Self::from_env)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0 && !self.ttl.is_zero()
+    }
+
+    /// Looks up `key` and counts a hit or a miss for `series`.
+    pub fn get(&self, series: Series, key: &str) -> Option<CachedResponse> {
+        let mut state = self.state.lock().unwrap();
+
+        let fresh = match state.entries.get(key) {
+            Some(entry) => entry.stored_at.elapsed() < self.ttl,
+            None => false,
+        };
+        if !fresh {
+            state.remove(key);
+            state.counters.entry(series).or_default().misses += 1;
+            return None;
+        }
+
+        state.touch(key);
+        state.counters.entry(series).or_default().hits += 1;
+        state.entries.get(key).map(|entry| entry.response.clone())
+    }
+
+    /// Current generation of `series`, to pass back to `insert` once the
+    /// response has been computed.
+    pub fn generation(&self, series: Series) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.generations.get(&series).copied().unwrap_or_default()
+    }
+
+    /// Stores a response unless `series` was invalidated since `generation`
+    /// was read, evicting the least recently used entries past capacity.
+    pub fn insert(&self, series: Series, key: String, generation: u64, response: CachedResponse) {
+        if !self.enabled() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.generations.get(&series).copied().unwrap_or_default() != generation {
+            return;
+        }
+
+        state.remove(&key);
+        state.clock += 1;
+        let used = state.clock;
+        state.recency.insert(used, key.clone());
+        state.entries.insert(
+            key,
+            Entry {
+                response,
+                series,
+                stored_at: Instant::now(),
+                used,
+            },
+        );
+
+        while state.entries.len() > self.capacity {
+            let Some((_, oldest)) = state.recency.pop_first() else {
+                break;
+            };
+            state.entries.remove(&oldest);
+            state.evictions += 1;
+        }
+    }
+
+    /// Drops every entry of `series`. Called by ingestion after it stores
+    /// new intervals.
+    pub fn invalidate(&self, series: Series) {
+        let mut state = self.state.lock().unwrap();
+        *state.generations.entry(series).or_default() += 1;
+        state.counters.entry(series).or_default().invalidations += 1;
+
+        let keys: Vec<String> = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.series == series)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            state.remove(&key);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        let series: Vec<SeriesCacheStats> = Series::ALL
+            .iter()
+            .map(|&series| {
+                let counters = state.counters.get(&series).copied().unwrap_or_default();
+                SeriesCacheStats {
+                    series,
+                    entries: state
+                        .entries
+                        .values()
+                        .filter(|entry| entry.series == series)
+                        .count(),
+                    hits: counters.hits,
+                    misses: counters.misses,
+                    invalidations: counters.invalidations,
+                }
+            })
+            .collect();
+
+        CacheStats {
+            enabled: self.enabled(),
+            capacity: self.capacity,
+            ttl_seconds: self.ttl.as_secs(),
+            entries: state.entries.len(),
+            hits: series.iter().map(|stats| stats.hits).sum(),
+            misses: series.iter().map(|stats| stats.misses).sum(),
+            evictions: state.evictions,
+            series,
+        }
+    }
+}
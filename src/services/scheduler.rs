@@ -1,20 +1,103 @@
-#[allow(unused_imports)]
-use crate::utils::RUNEPOOL_START_TIME;
+use crate::database::db::Mongodb;
+use crate::models::ingestion_checkpoint::Series;
+use crate::services::ingestion_jobs::lock_series;
 use chrono::Utc;
 use cron::Schedule;
-use log::{error, info};
+use dotenv::dotenv;
+use log::{error, info, warn};
+use mongodb::error::Error as MongoError;
 use mongodb::Client;
+use serde::Serialize;
+use std::env;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicI64, Ordering};
-#[allow(unused_imports)]
-use tokio::time::{interval, Duration};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use utoipa::ToSchema;
 
-// const INITIAL_START_TIME: i64 = 1707350400;
-const INITIAL_START_TIME: i64 = 1739487600;
-// static LAST_EXECUTION_TIME: AtomicI64 = AtomicI64::new(RUNEPOOL_START_TIME);
-static LAST_EXECUTION_TIME: AtomicI64 = AtomicI64::new(INITIAL_START_TIME);
+/// Interval every series is ingested at.
+pub const INGESTION_INTERVAL: &str = "hour";
+
+/// Default of `SCHEDULER_ENABLED`.
+const DEFAULT_SCHEDULER_ENABLED: bool = true;
+
+static SCHEDULER: SchedulerState = SchedulerState {
+    paused: AtomicBool::new(false),
+    runs: Mutex::new(SchedulerRuns {
+        running: false,
+        last_started_at: None,
+        last_finished_at: None,
+    }),
+};
+
+struct SchedulerRuns {
+    running: bool,
+    last_started_at: Option<i64>,
+    last_finished_at: Option<i64>,
+}
+
+struct SchedulerState {
+    paused: AtomicBool,
+    runs: Mutex<SchedulerRuns>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SchedulerStatus {
+    /// Scheduled runs are skipped while paused
+    pub paused: bool,
+    /// Whether a run is in progress
+    pub running: bool,
+    pub last_run_started_at: Option<i64>,
+    pub last_run_finished_at: Option<i64>,
+}
+
+/// Skips scheduled runs until `resume`. A run already in progress finishes.
+/// The state is stored, so the scheduler stays paused across restarts.
+pub async fn pause(db: &Mongodb) -> Result<(), MongoError> {
+    db.save_scheduler_paused(true).await?;
+    SCHEDULER.paused.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+pub async fn resume(db: &Mongodb) -> Result<(), MongoError> {
+    db.save_scheduler_paused(false).await?;
+    SCHEDULER.paused.store(false, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn status() -> SchedulerStatus {
+    let runs = SCHEDULER.runs.lock().unwrap();
+    SchedulerStatus {
+        paused: SCHEDULER.paused.load(Ordering::Relaxed),
+        running: runs.running,
+        last_run_started_at: runs.last_started_at,
+        last_run_finished_at: runs.last_finished_at,
+    }
+}
+
+/// Ingests every series once at startup and then at the top of every hour,
+/// unless paused. It starts paused when it was paused from the admin routes
+/// before the restart, or when `SCHEDULER_ENABLED` is `false`.
+pub async fn start_hourly_data_fetch(db: Mongodb) {
+    dotenv().ok();
+    let enabled = env::var("SCHEDULER_ENABLED")
+        .ok()
+        .and_then(|value| value.parse::<bool>().ok())
+        .unwrap_or(DEFAULT_SCHEDULER_ENABLED);
+    // Ingesting while an operator meant it to be paused is worse than
+    // missing a run, so an unreadable state counts as paused.
+    let paused_by_admin = db.scheduler_paused().await.unwrap_or_else(|e| {
+        error!("Failed to read the scheduler state, starting paused: {}", e);
+        true
+    });
+    if paused_by_admin {
+        info!("Scheduler was paused from the admin routes, starting paused");
+    }
+    SCHEDULER
+        .paused
+        .store(!enabled || paused_by_admin, Ordering::Relaxed);
+    let mongo_client = db.client.as_ref();
 
-pub async fn start_hourly_data_fetch(mongo_client: Client) {
     // "0 0 * * * *" -> sec min hour day month weekday
     let schedule = match Schedule::from_str("0 0 * * * *") {
         Ok(schedule) => schedule,
@@ -23,40 +106,108 @@ pub async fn start_hourly_data_fetch(mongo_client: Client) {
             return;
         }
     };
-    let last_execution = LAST_EXECUTION_TIME.load(Ordering::SeqCst);
-    let mut upcoming = schedule.upcoming(Utc);
 
+    let mut due = Utc::now();
     loop {
-        if let Some(datetime) = upcoming.next() {
-            let current_time = Utc::now().timestamp();
-            info!("Starting hourly data fetch at {}", current_time);
-
-            let client1 = mongo_client.clone();
-            let client2 = mongo_client.clone();
-            let client3 = mongo_client.clone();
-            let client4 = mongo_client.clone();
-            let last_exec = last_execution;
-
-            let fetch_tasks = tokio::join!(
-                super::fetch_earnings_history::fetch_earnings_history("hour", last_exec, &client1),
-                super::fetch_runepool_members_units_history::fetch_runepool_members_units_history(
-                    "hour", last_exec, &client2
-                ),
-                super::fetch_depth_price_history::fetch_depth_price_history(
-                    "BTC.BTC", "hour", last_exec, &client3
-                ),
-                super::fetch_swaps_history::fetch_swaps_history("hour", last_exec, &client4)
-            );
-
-            match fetch_tasks {
-                (Ok(_), Ok(_), Ok(_), Ok(_)) => info!("All fetches completed successfully"),
-                _ => error!("One or more fetches failed"),
-            }
-
-            LAST_EXECUTION_TIME.store(current_time, Ordering::SeqCst);
-            info!("Completed hourly data fetch at {}", datetime);
-
-            tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+        if SCHEDULER.paused.load(Ordering::Relaxed) {
+            info!("Scheduler paused, skipping the data fetch due at {}", due);
+        } else {
+            run_data_fetch(mongo_client).await;
         }
+
+        // Taken after the run, so a run longer than an hour skips the slots
+        // it overran instead of starting again straight away.
+        let Some(next) = schedule.upcoming(Utc).next() else {
+            return;
+        };
+        due = next;
+        let wait = (due - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Where `series` starts when it has no checkpoint in `ingestion_checkpoints`
+/// yet: `INGESTION_START_TIME` when set, otherwise the start of the series'
+/// history on Midgard. Once a checkpoint exists the fetchers resume from it
+/// instead.
+fn initial_start_time(series: Series) -> i64 {
+    let Ok(value) = env::var("INGESTION_START_TIME") else {
+        return series.history_start();
+    };
+    value.trim().parse().unwrap_or_else(|_| {
+        warn!(
+            "Ignoring INGESTION_START_TIME={:?}, which is not a Unix timestamp",
+            value
+        );
+        series.history_start()
+    })
+}
+
+async fn run_data_fetch(mongo_client: &Client) {
+    let current_time = Utc::now().timestamp();
+    info!("Starting hourly data fetch at {}", current_time);
+    {
+        let mut runs = SCHEDULER.runs.lock().unwrap();
+        runs.running = true;
+        runs.last_started_at = Some(current_time);
+    }
+
+    // Each series waits for admin jobs running on it, see `lock_series`.
+    let fetch_tasks = tokio::join!(
+        async {
+            let _series = lock_series(Series::Earnings).await;
+            super::fetch_earnings_history::fetch_earnings_history(
+                INGESTION_INTERVAL,
+                initial_start_time(Series::Earnings),
+                mongo_client,
+            )
+            .await
+        },
+        async {
+            let _series = lock_series(Series::RunepoolMembers).await;
+            super::fetch_runepool_members_units_history::fetch_runepool_members_units_history(
+                INGESTION_INTERVAL,
+                initial_start_time(Series::RunepoolMembers),
+                mongo_client,
+            )
+            .await
+        },
+        async {
+            let _series = lock_series(Series::Depth).await;
+            super::fetch_depth_price_history::fetch_depth_price_history_for_all_pools(
+                INGESTION_INTERVAL,
+                initial_start_time(Series::Depth),
+                mongo_client,
+            )
+            .await
+        },
+        async {
+            let _series = lock_series(Series::Swaps).await;
+            super::fetch_swaps_history::fetch_swaps_history(
+                INGESTION_INTERVAL,
+                initial_start_time(Series::Swaps),
+                mongo_client,
+            )
+            .await
+        }
+    );
+
+    match fetch_tasks {
+        (Ok(_), Ok(_), Ok(_), Ok(_)) => info!("All fetches completed successfully"),
+        _ => error!("One or more fetches failed"),
+    }
+
+    match super::gap_detection::run_gap_backfill(mongo_client, INGESTION_INTERVAL).await {
+        Ok(0) => info!("No gaps found in stored history"),
+        Ok(gaps) => info!("Gap backfill processed {} gaps", gaps),
+        Err(e) => error!("Gap detection failed: {}", e),
+    }
+
+    let finished = Utc::now().timestamp();
+    {
+        let mut runs = SCHEDULER.runs.lock().unwrap();
+        runs.running = false;
+        runs.last_finished_at = Some(finished);
     }
+    info!("Completed hourly data fetch at {}", finished);
 }
@@ -0,0 +1,195 @@
+use crate::database::db::Mongodb;
+use crate::error::ServiceError;
+use crate::models::ingestion_checkpoint::Series;
+use crate::services::ingestion_jobs;
+use crate::services::midgard_client::MidgardClient;
+use crate::services::response_cache::ResponseCache;
+use chrono::Utc;
+use log::info;
+use mongodb::Client as MongoClient;
+use serde::de::DeserializeOwned;
+use std::time::Instant;
+
+/// Midgard returns at most this many intervals per request.
+const PAGE_SIZE: u32 = 400;
+
+/// Pagination details of one Midgard history response.
+pub struct Page {
+    pub interval_count: usize,
+    /// `meta.endTime` of the response, where the next page starts.
+    pub end_time: i64,
+    /// Start of the newest interval in the response, if there is one.
+    pub last_start_time: Option<i64>,
+}
+
+impl Page {
+    pub fn parse(
+        interval_count: usize,
+        end_time: &str,
+        last_start_time: Option<&str>,
+    ) -> Result<Self, ServiceError> {
+        Ok(Self {
+            interval_count,
+            end_time: end_time.trim().parse::<i64>()?,
+            last_start_time: last_start_time
+                .map(|start_time| start_time.trim().parse::<i64>())
+                .transpose()?,
+        })
+    }
+}
+
+/// A paginated Midgard history series that `fetch_series` can ingest.
+#[allow(async_fn_in_trait)]
+pub trait MidgardSeries {
+    /// One page of the series as returned by Midgard.
+    type Response: DeserializeOwned;
+
+    fn series(&self) -> Series;
+
+    fn pool(&self) -> Option<&str>;
+
+    /// Endpoint path, optionally with a query string, e.g.
+    /// `/v2/history/depths/BTC.BTC`. `interval`, `from` and `count` are
+    /// appended by the fetcher.
+    fn endpoint(&self) -> String;
+
+    fn page(response: &Self::Response) -> Result<Page, ServiceError>;
+
+    /// Converts the page to stored models and writes them to MongoDB.
+    async fn store(
+        &self,
+        mongo_client: &MongoClient,
+        response: Self::Response,
+    ) -> Result<(), ServiceError>;
+}
+
+/// Pages through `source` from its checkpoint (or `start_time` when there is
+/// none, or it is older) until Midgard reports the current interval, storing
+/// each page and advancing the checkpoint after it commits.
+pub async fn fetch_series<S: MidgardSeries>(
+    source: &S,
+    interval: &str,
+    start_time: i64,
+    mongo_client: &MongoClient,
+) -> Result<(), ServiceError> {
+    let db = Mongodb::new(mongo_client.clone());
+    let from = match db
+        .get_checkpoint(source.series(), source.pool(), interval)
+        .await?
+    {
+        Some(checkpoint) if checkpoint.last_end_time > start_time => {
+            println!("Resuming from checkpoint: {}", checkpoint.last_end_time);
+            checkpoint.last_end_time
+        }
+        _ => start_time,
+    };
+
+    fetch_pages(source, interval, from, None, &db, mongo_client).await
+}
+
+/// Re-fetches the intervals of `source` between `from` and `to` without
+/// touching its checkpoint. Used to backfill gaps in stored history.
+pub async fn fetch_series_range<S: MidgardSeries>(
+    source: &S,
+    interval: &str,
+    from: i64,
+    to: i64,
+    mongo_client: &MongoClient,
+) -> Result<(), ServiceError> {
+    let db = Mongodb::new(mongo_client.clone());
+    fetch_pages(source, interval, from, Some(to), &db, mongo_client).await
+}
+
+/// Fetches and stores pages starting at `from`. With no `until` the
+/// checkpoint advances after every page and paging stops at the current
+/// interval; otherwise paging stops once `until` is covered.
+async fn fetch_pages<S: MidgardSeries>(
+    source: &S,
+    interval: &str,
+    from: i64,
+    until: Option<i64>,
+    db: &Mongodb,
+    mongo_client: &MongoClient,
+) -> Result<(), ServiceError> {
+    let series = source.series();
+    let pool = source.pool();
+    let endpoint = source.endpoint();
+    let separator = if endpoint.contains('?') { '&' } else { '?' };
+
+    let mut current_time = from;
+    let started = Instant::now();
+    let mut pages = 0;
+    let mut intervals = 0;
+
+    loop {
+        let path = format!(
+            "{}{}interval={}&from={}&count={}",
+            endpoint, separator, interval, current_time, PAGE_SIZE
+        );
+
+        let text = MidgardClient::global().get_text(&path).await?;
+
+        let response = match serde_json::from_str::<S::Response>(&text) {
+            Ok(response) => response,
+            Err(e) => {
+                println!("Failed to parse JSON: {}", e);
+                println!("Response text: {}", text);
+                return Err(e.into());
+            }
+        };
+
+        let page = S::page(&response)?;
+        if page.interval_count == 0 {
+            println!("No more intervals to process");
+            break;
+        }
+
+        println!("Number of intervals to process: {}", page.interval_count);
+
+        let stored = source.store(mongo_client, response).await;
+        // A store that failed part-way may still have written intervals, so
+        // cached responses of the series are dropped and its checkpoint marked
+        // updated before an error ends the fetch. Live fetches that succeed
+        // mark it when the checkpoint moves below.
+        ResponseCache::global().invalidate(series);
+        if until.is_some() || stored.is_err() {
+            db.touch_checkpoint(series, pool, interval).await?;
+        }
+        stored?;
+        pages += 1;
+        intervals += page.interval_count;
+        ingestion_jobs::record_page(page.interval_count, page.end_time);
+
+        let current_timestamp = Utc::now().timestamp();
+
+        if until.is_none() {
+            // The newest interval is still open until its end time passes, so
+            // checkpoint at its start and fetch it again on the next run.
+            let checkpoint_time = if page.end_time >= current_timestamp {
+                page.last_start_time.unwrap_or(current_time)
+            } else {
+                page.end_time
+            };
+            db.save_checkpoint(series, pool, interval, checkpoint_time)
+                .await?;
+        }
+
+        let stop_time = until.unwrap_or(current_timestamp).min(current_timestamp);
+        if page.end_time >= stop_time {
+            break;
+        }
+
+        current_time = page.end_time;
+    }
+
+    info!(
+        "Fetched {} {} intervals in {} pages for pool {} in {:?}",
+        intervals,
+        series.as_str(),
+        pages,
+        pool.unwrap_or("(all)"),
+        started.elapsed()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,228 @@
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, Utc};
+use mongodb::bson::{doc, Document};
+
+use crate::utils::{get_seconds_per_interval, ONE_HOUR_SECS};
+
+/// `$dateTrunc` unit and bin size for an interval name. Interval names are
+/// validated where they enter the API, see `HistoryQueryParams::interval`.
+fn date_unit(interval: &str) -> (&'static str, i64) {
+    match interval {
+        "5min" => ("minute", 5),
+        "day" => ("day", 1),
+        "week" => ("week", 1),
+        "month" => ("month", 1),
+        "quarter" => ("quarter", 1),
+        "year" => ("year", 1),
+        _ => ("hour", 1),
+    }
+}
+
+/// Months in a calendar interval, or `None` for fixed-length intervals.
+fn calendar_months(interval: &str) -> Option<u32> {
+    match interval {
+        "month" => Some(1),
+        "quarter" => Some(3),
+        "year" => Some(12),
+        _ => None,
+    }
+}
+
+/// Length of a fixed-length interval. Like `date_unit`, this expects a
+/// validated interval name.
+fn fixed_seconds(interval: &str) -> i64 {
+    get_seconds_per_interval(interval).unwrap_or(ONE_HOUR_SECS as i64)
+}
+
+fn seconds_to_date(field: &str) -> Document {
+    doc! { "$toDate": { "$multiply": [field, 1000] } }
+}
+
+fn date_to_seconds(date: Document) -> Document {
+    doc! { "$toLong": { "$divide": [{ "$toLong": date }, 1000] } }
+}
+
+/// Aggregation expression for the start, in Unix seconds, of the `interval`
+/// bucket containing a stored row's `start_time`. Weeks start on Monday and
+/// months, quarters and years follow the UTC calendar, as in Midgard.
+pub fn bucket_start_expr(interval: &str) -> Document {
+    let (unit, bin_size) = date_unit(interval);
+    date_to_seconds(doc! {
+        "$dateTrunc": {
+            "date": seconds_to_date("$start_time"),
+            "unit": unit,
+            "binSize": bin_size,
+            "timezone": "UTC",
+            "startOfWeek": "monday"
+        }
+    })
+}
+
+/// Aggregation expression for the end, in Unix seconds, of the `interval`
+/// bucket starting at `start_field`.
+pub fn bucket_end_expr(interval: &str, start_field: &str) -> Document {
+    let (unit, bin_size) = date_unit(interval);
+    date_to_seconds(doc! {
+        "$dateAdd": {
+            "startDate": seconds_to_date(start_field),
+            "unit": unit,
+            "amount": bin_size,
+            "timezone": "UTC"
+        }
+    })
+}
+
+fn to_date(timestamp: i64) -> Option<NaiveDate> {
+    Some(DateTime::from_timestamp(timestamp, 0)?.date_naive())
+}
+
+fn to_timestamp(date: NaiveDate) -> i64 {
+    date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp()
+}
+
+/// Start of the `interval` bucket containing `timestamp`, or `None` when
+/// `timestamp` is outside the dates chrono can represent.
+pub fn bucket_start(interval: &str, timestamp: i64) -> Option<i64> {
+    let date = to_date(timestamp)?;
+    match interval {
+        "week" => Some(to_timestamp(date.checked_sub_days(Days::new(
+            date.weekday().num_days_from_monday() as u64,
+        ))?)),
+        "month" | "quarter" | "year" => {
+            let months = calendar_months(interval).unwrap();
+            let month0 = date.month0() / months * months;
+            Some(to_timestamp(NaiveDate::from_ymd_opt(
+                date.year(),
+                month0 + 1,
+                1,
+            )?))
+        }
+        _ => {
+            let seconds = fixed_seconds(interval);
+            Some(timestamp - timestamp.rem_euclid(seconds))
+        }
+    }
+}
+
+/// Span of `count` buckets of a calendar interval, for `Months` arithmetic.
+fn calendar_span(months: u32, count: i64) -> Option<Months> {
+    let count = u32::try_from(count.max(0)).ok()?;
+    Some(Months::new(months.checked_mul(count)?))
+}
+
+/// Moves a bucket start back by `count` buckets of `interval`, or `None`
+/// when the result is out of range.
+pub fn buckets_before(interval: &str, start: i64, count: i64) -> Option<i64> {
+    match calendar_months(interval) {
+        Some(months) => {
+            let date = DateTime::from_timestamp(start, 0)?;
+            date.checked_sub_months(calendar_span(months, count)?)
+                .map(|date| date.timestamp())
+        }
+        None => start.checked_sub(count.checked_mul(fixed_seconds(interval))?),
+    }
+}
+
+/// Moves a bucket start forward by `count` buckets of `interval`, or `None`
+/// when the result is out of range.
+pub fn buckets_after(interval: &str, start: i64, count: i64) -> Option<i64> {
+    match calendar_months(interval) {
+        Some(months) => {
+            let date = DateTime::from_timestamp(start, 0)?;
+            date.checked_add_months(calendar_span(months, count)?)
+                .map(|date| date.timestamp())
+        }
+        None => start.checked_add(count.checked_mul(fixed_seconds(interval))?),
+    }
+}
+
+/// Default `from` for a query without one: the start of the bucket `count`
+/// buckets back, counting the last one, or the epoch when that is earlier.
+/// The last bucket is the one ending at `to`, or the current one when there
+/// is no `to`, so `to` and `count` select the `count` buckets before `to`.
+pub fn default_from(interval: &str, count: i64, to: Option<i64>) -> i64 {
+    // `to` bounds the end of the last interval, so the last bucket is the
+    // one holding the second before it.
+    let last = to.map_or_else(|| Utc::now().timestamp(), |to| to.saturating_sub(1));
+    bucket_start(interval, last)
+        .and_then(|last| buckets_before(interval, last, count.saturating_sub(1)))
+        .map_or(0, |from| from.max(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JAN_1_2024: i64 = 1_704_067_200;
+    const FEB_1_2024: i64 = 1_706_745_600;
+    const DEC_1_2023: i64 = 1_701_388_800;
+    const OCT_1_2023: i64 = 1_696_118_400;
+
+    #[test]
+    fn bucket_start_follows_the_calendar() {
+        // 2024-01-31T12:00:00Z
+        assert_eq!(bucket_start("month", 1_706_702_400), Some(JAN_1_2024));
+        // 2024-06-15T09:30:00Z
+        assert_eq!(bucket_start("quarter", 1_718_443_800), Some(1_711_929_600));
+        assert_eq!(bucket_start("year", 1_718_443_800), Some(JAN_1_2024));
+        assert_eq!(bucket_start("day", 1_718_443_800), Some(1_718_409_600));
+        assert_eq!(bucket_start("hour", 1_718_443_800), Some(1_718_442_000));
+        assert_eq!(bucket_start("5min", 1_718_443_899), Some(1_718_443_800));
+    }
+
+    #[test]
+    fn weeks_start_on_monday_across_years() {
+        // Wednesday 2024-01-03 belongs to the week of Monday 2024-01-01.
+        assert_eq!(bucket_start("week", 1_704_268_800), Some(JAN_1_2024));
+        // Sunday 2023-01-01 belongs to the week of Monday 2022-12-26.
+        assert_eq!(bucket_start("week", 1_672_567_200), Some(1_672_012_800));
+    }
+
+    #[test]
+    fn calendar_buckets_cross_month_and_year_boundaries() {
+        assert_eq!(buckets_after("month", DEC_1_2023, 1), Some(JAN_1_2024));
+        assert_eq!(buckets_after("month", JAN_1_2024, 1), Some(FEB_1_2024));
+        assert_eq!(buckets_before("month", FEB_1_2024, 2), Some(DEC_1_2023));
+        assert_eq!(buckets_before("quarter", JAN_1_2024, 1), Some(OCT_1_2023));
+        assert_eq!(buckets_after("quarter", OCT_1_2023, 1), Some(JAN_1_2024));
+        assert_eq!(buckets_after("year", JAN_1_2024, 1), Some(1_735_689_600));
+        assert_eq!(buckets_before("year", 1_735_689_600, 1), Some(JAN_1_2024));
+    }
+
+    #[test]
+    fn fixed_buckets_move_by_their_length() {
+        assert_eq!(buckets_after("day", JAN_1_2024, 31), Some(FEB_1_2024));
+        assert_eq!(
+            buckets_before("hour", JAN_1_2024, 24),
+            Some(JAN_1_2024 - 86_400)
+        );
+        assert_eq!(buckets_before("week", JAN_1_2024, 0), Some(JAN_1_2024));
+    }
+
+    #[test]
+    fn out_of_range_times_are_none() {
+        assert_eq!(bucket_start("week", 99_999_999_999_999), None);
+        assert_eq!(bucket_start("month", i64::MIN), None);
+        assert_eq!(buckets_after("hour", i64::MAX - 10, 1), None);
+        assert_eq!(buckets_before("day", JAN_1_2024, i64::MAX), None);
+        assert_eq!(buckets_after("month", JAN_1_2024, i64::MAX), None);
+        assert_eq!(buckets_after("year", JAN_1_2024, 1_000_000), None);
+    }
+
+    #[test]
+    fn default_from_counts_back_from_to() {
+        assert_eq!(
+            default_from("day", 3, Some(FEB_1_2024)),
+            FEB_1_2024 - 3 * 86_400
+        );
+        assert_eq!(default_from("month", 2, Some(FEB_1_2024)), DEC_1_2023);
+        // A `to` inside a bucket keeps that bucket.
+        assert_eq!(default_from("month", 2, Some(FEB_1_2024 + 1)), JAN_1_2024);
+    }
+
+    #[test]
+    fn default_from_never_precedes_the_epoch() {
+        assert_eq!(default_from("year", i64::MAX, None), 0);
+        assert_eq!(default_from("day", 5, Some(86_400)), 0);
+        assert!(default_from("hour", 1, None) > 0);
+    }
+}
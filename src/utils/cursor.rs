@@ -0,0 +1,423 @@
+use actix_web::HttpRequest;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::FindOneOptions;
+use mongodb::Collection;
+
+use crate::error::ApiError;
+use crate::routes::queries::HistoryQueryParams;
+use crate::utils::buckets::{bucket_start, bucket_start_expr, buckets_after, buckets_before};
+use crate::utils::handle_pagination_and_sorting;
+
+/// How far past the current time a cursor may point. Cursors are built from
+/// stored intervals, so anything much later than now was not issued by us.
+const MAX_CURSOR_AHEAD_SECS: i64 = 366 * 86_400;
+
+/// Position in a history series, handed to clients as an opaque token.
+/// Cursors point at bucket boundaries and a cursor page only reads the rows
+/// of its own buckets, so it costs the same however deep into history it is,
+/// and intervals ingested while a client walks the series cannot shift the
+/// pages it has not read yet.
+///
+/// What a cursor page queries, and why none of it grows with depth:
+/// - the intervals and the `meta` summary are aggregations whose `$match`
+///   is limited to the cursor's window by `cursor_match_stage`;
+/// - `total_count` is skipped;
+/// - the `next`/`prev` lookups in `pagination_document` are single-row
+///   `find_one`s sorted on `start_time` from the window's edges, which seek
+///   the unique `(pool, start_time, end_time)` or `(start_time, end_time)`
+///   index of the series rather than scan it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageCursor {
+    /// Intervals starting at or after this time.
+    After(i64),
+    /// Intervals starting before this time.
+    Before(i64),
+}
+
+impl PageCursor {
+    pub fn encode(&self) -> String {
+        let position = match self {
+            PageCursor::After(time) => format!("after:{}", time),
+            PageCursor::Before(time) => format!("before:{}", time),
+        };
+        URL_SAFE_NO_PAD.encode(position)
+    }
+
+    /// The cursor encoded in `token`, or `None` for a malformed token or one
+    /// pointing before the epoch or too far into the future.
+    pub fn decode(token: &str) -> Option<Self> {
+        let position = String::from_utf8(URL_SAFE_NO_PAD.decode(token).ok()?).ok()?;
+        let (direction, time) = position.split_once(':')?;
+        let time: i64 = time.parse().ok()?;
+        if !(0..=Utc::now().timestamp() + MAX_CURSOR_AHEAD_SECS).contains(&time) {
+            return None;
+        }
+        match direction {
+            "after" => Some(PageCursor::After(time)),
+            "before" => Some(PageCursor::Before(time)),
+            _ => None,
+        }
+    }
+
+    /// The cursor requested by `query`. Cursors walk the series by start
+    /// time, so they cannot be combined with another `sort_by` field.
+    pub fn from_query(query: &HistoryQueryParams) -> Result<Option<Self>, ApiError> {
+        let Some(token) = query.cursor.as_deref() else {
+            return Ok(None);
+        };
+        let cursor =
+            Self::decode(token).ok_or_else(|| ApiError::invalid("cursor", "Invalid cursor"))?;
+        if !sorts_by_start_time(query) {
+            return Err(ApiError::invalid(
+                "cursor",
+                "cursor can only be used when sorting by startTime",
+            ));
+        }
+        Ok(Some(cursor))
+    }
+
+    /// Start time window `[start, end)` of the stored rows making up the
+    /// `limit` buckets next to the cursor. Both ends are bucket starts, so
+    /// every bucket in the window is complete.
+    fn window(&self, interval: &str, limit: i64) -> Result<(i64, i64), ApiError> {
+        let window = match *self {
+            PageCursor::After(time) => bucket_start(interval, time)
+                .and_then(|start| Some((start, buckets_after(interval, start, limit)?))),
+            PageCursor::Before(time) => bucket_start(interval, time)
+                .and_then(|end| Some((buckets_before(interval, end, limit)?, end))),
+        };
+        window.ok_or_else(|| ApiError::invalid("cursor", "Cursor is out of range"))
+    }
+}
+
+fn sorts_by_start_time(query: &HistoryQueryParams) -> bool {
+    matches!(query.sort_by.as_deref(), None | Some("startTime"))
+}
+
+/// `match_stage` restricted to the stored rows of the page `cursor` points
+/// at, when there is one.
+pub fn cursor_match_stage(
+    match_stage: Document,
+    query: &HistoryQueryParams,
+    cursor: Option<PageCursor>,
+) -> Result<Document, ApiError> {
+    let Some(cursor) = cursor else {
+        return Ok(match_stage);
+    };
+    let interval = query.interval()?;
+    let (_page, _skip, limit, _sort_field, _sort_order) = handle_pagination_and_sorting(query);
+    let (start, end) = cursor.window(interval, limit)?;
+    Ok(doc! {
+        "$and": [
+            match_stage,
+            { "start_time": { "$gte": start, "$lt": end } }
+        ]
+    })
+}
+
+/// Number of `interval` buckets holding rows matching `match_stage`, for the
+/// page counts of a request without a cursor. Cursor pages skip the count,
+/// which gets slow deep into history.
+pub async fn total_count<T: Send + Sync>(
+    collection: &Collection<T>,
+    match_stage: &Document,
+    interval: &str,
+    cursor: Option<PageCursor>,
+) -> Result<Option<i64>, ApiError> {
+    if cursor.is_some() {
+        return Ok(None);
+    }
+    let pipeline = vec![
+        doc! { "$match": match_stage.clone() },
+        doc! { "$group": { "_id": bucket_start_expr(interval) } },
+        doc! { "$count": "count" },
+    ];
+    let counted = collection
+        .aggregate(pipeline, None)
+        .await?
+        .try_next()
+        .await?;
+    // `$count` gives an int or a long depending on the size; no document at
+    // all when nothing matches.
+    let count = match counted.as_ref().and_then(|counted| counted.get("count")) {
+        Some(Bson::Int32(count)) => i64::from(*count),
+        Some(Bson::Int64(count)) => *count,
+        _ => 0,
+    };
+    Ok(Some(count))
+}
+
+/// Stages that order and page the bucketed intervals. With a cursor, the
+/// `limit` intervals nearest to it are taken and then put in the requested
+/// order; without one, `page` selects the page. Unpaginated pipelines, such
+/// as exports, are only sorted.
+pub fn page_stages(
+    query: &HistoryQueryParams,
+    cursor: Option<PageCursor>,
+    paginate: bool,
+) -> Vec<Document> {
+    let (_page, skip, limit, sort_field, sort_order) = handle_pagination_and_sorting(query);
+    if !paginate {
+        return vec![doc! { "$sort": { sort_field: sort_order } }];
+    }
+    match cursor {
+        Some(cursor) => {
+            let nearest_first = match cursor {
+                PageCursor::After(_) => 1,
+                PageCursor::Before(_) => -1,
+            };
+            vec![
+                doc! { "$sort": { "startTime": nearest_first } },
+                doc! { "$limit": limit },
+                doc! { "$sort": { "startTime": sort_order } },
+            ]
+        }
+        None => vec![
+            doc! { "$sort": { sort_field: sort_order } },
+            doc! { "$skip": skip },
+            doc! { "$limit": limit },
+        ],
+    }
+}
+
+/// Link to the current request with its page replaced by `cursor`.
+fn cursor_link(req: &HttpRequest, cursor: PageCursor) -> String {
+    let mut params: Vec<(String, String)> =
+        serde_urlencoded::from_str(req.query_string()).unwrap_or_default();
+    params.retain(|(name, _)| name != "cursor" && name != "page");
+    params.push(("cursor".to_string(), cursor.encode()));
+    format!(
+        "{}?{}",
+        req.path(),
+        serde_urlencoded::to_string(&params).unwrap_or_default()
+    )
+}
+
+/// Cursor of the page holding the nearest stored row on one side of
+/// `boundary`, or `None` when the series ends there. Looking the row up
+/// skips over ingestion gaps, so a link never leads to an empty page.
+async fn neighbour_cursor<T: Send + Sync>(
+    collection: &Collection<T>,
+    filter: &Document,
+    interval: &str,
+    boundary: i64,
+    later: bool,
+) -> Result<Option<PageCursor>, ApiError> {
+    let (side, direction) = if later {
+        (doc! { "$gte": boundary }, 1)
+    } else {
+        (doc! { "$lt": boundary }, -1)
+    };
+    let options = FindOneOptions::builder()
+        .sort(doc! { "start_time": direction })
+        .projection(doc! { "start_time": 1 })
+        .build();
+    let row = collection
+        .clone_with_type::<Document>()
+        .find_one(
+            doc! { "$and": [filter.clone(), { "start_time": side }] },
+            options,
+        )
+        .await?;
+
+    Ok(row
+        .and_then(|row| row.get_i64("start_time").ok())
+        .and_then(|start_time| bucket_start(interval, start_time))
+        .and_then(|bucket| {
+            if later {
+                Some(PageCursor::After(bucket))
+            } else {
+                buckets_after(interval, bucket, 1).map(PageCursor::Before)
+            }
+        }))
+}
+
+/// Pagination block of a history response. It always carries the cursors
+/// and links of the neighbouring pages, which are `null` where the series
+/// ends, and page counts when `total_count` was computed, which is skipped
+/// for cursor requests. `match_stage` is the route's filter without the
+/// cursor applied.
+pub async fn pagination_document<T: Send + Sync>(
+    req: &HttpRequest,
+    query: &HistoryQueryParams,
+    collection: &Collection<T>,
+    match_stage: &Document,
+    cursor: Option<PageCursor>,
+    intervals: &[Document],
+    total_count: Option<i64>,
+) -> Result<Document, ApiError> {
+    let interval = query.interval()?;
+    let (page, _skip, limit, sort_field, sort_order) = handle_pagination_and_sorting(query);
+
+    let mut pagination = Document::new();
+    if let Some(total_count) = total_count {
+        pagination.insert("currentPage", Bson::Int64(page));
+        pagination.insert(
+            "totalPages",
+            Bson::Int64((total_count as f64 / limit as f64).ceil() as i64),
+        );
+        pagination.insert("totalRecords", Bson::Int64(total_count));
+    }
+    pagination.insert("limit", Bson::Int64(limit));
+    pagination.insert("sortBy", sort_field);
+    pagination.insert("order", if sort_order == 1 { "asc" } else { "desc" });
+
+    // Boundaries of what this page covers: the cursor's window, or the
+    // returned intervals for page-number requests.
+    let bounds = match cursor {
+        Some(cursor) => Some(cursor.window(interval, limit)?),
+        None => {
+            let starts = intervals
+                .iter()
+                .filter_map(|interval| interval.get_i64("startTime").ok());
+            let ends = intervals
+                .iter()
+                .filter_map(|interval| interval.get_i64("endTime").ok());
+            starts.min().zip(ends.max())
+        }
+    };
+
+    let (mut later, mut earlier) = (None, None);
+    if let (Some((start, end)), true) = (bounds, sorts_by_start_time(query)) {
+        // Without an explicit `from`, the default range only limits the
+        // first page; the links can walk back through the whole history.
+        let mut filter = match_stage.clone();
+        if query.from.is_none() {
+            filter.remove("start_time");
+        }
+        later = neighbour_cursor(collection, &filter, interval, end, true).await?;
+        earlier = neighbour_cursor(collection, &filter, interval, start, false).await?;
+    }
+    let (next, prev) = if sort_order == 1 {
+        (later, earlier)
+    } else {
+        (earlier, later)
+    };
+
+    for (name, cursor) in [("next", next), ("prev", prev)] {
+        pagination.insert(
+            format!("{}Cursor", name),
+            cursor.map(|cursor| Bson::String(cursor.encode())),
+        );
+        pagination.insert(
+            name,
+            cursor.map(|cursor| Bson::String(cursor_link(req, cursor))),
+        );
+    }
+
+    Ok(pagination)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(position: &str) -> String {
+        URL_SAFE_NO_PAD.encode(position)
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        for cursor in [
+            PageCursor::After(0),
+            PageCursor::After(1_704_067_200),
+            PageCursor::Before(1_704_067_200),
+        ] {
+            assert_eq!(PageCursor::decode(&cursor.encode()), Some(cursor));
+        }
+    }
+
+    #[test]
+    fn rejects_garbage_tokens() {
+        assert_eq!(PageCursor::decode(""), None);
+        assert_eq!(PageCursor::decode("not a cursor!"), None);
+        assert_eq!(PageCursor::decode(&token("after")), None);
+        assert_eq!(PageCursor::decode(&token("after:")), None);
+        assert_eq!(PageCursor::decode(&token("after:soon")), None);
+        assert_eq!(PageCursor::decode(&token("sideways:1704067200")), None);
+        assert_eq!(
+            PageCursor::decode(&URL_SAFE_NO_PAD.encode([0xff, 0xfe])),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_times() {
+        assert_eq!(PageCursor::decode(&token("after:-1")), None);
+        assert_eq!(
+            PageCursor::decode(&token("before:9223372036854775807")),
+            None
+        );
+        let far_future = Utc::now().timestamp() + 2 * MAX_CURSOR_AHEAD_SECS;
+        assert_eq!(
+            PageCursor::decode(&token(&format!("after:{}", far_future))),
+            None
+        );
+    }
+
+    /// Whether `stage` is a `$match` with both ends of `start_time` bound.
+    fn bounded_match(stage: &Document) -> bool {
+        let Ok(filter) = stage.get_document("$match") else {
+            return true;
+        };
+        let window = filter.get_array("$and").ok().and_then(|conditions| {
+            conditions
+                .iter()
+                .filter_map(Bson::as_document)
+                .find_map(|condition| condition.get_document("start_time").ok())
+        });
+        window.is_some_and(|window| window.contains_key("$gte") && window.contains_key("$lt"))
+    }
+
+    #[test]
+    fn cursor_pages_never_aggregate_an_open_range() {
+        use crate::routes::depth_history_routes::depth_intervals_pipeline;
+        use crate::routes::earning_history_route::earnings_intervals_pipeline;
+        use crate::routes::rune_pool_history_route::runepool_intervals_pipeline;
+        use crate::routes::swaps_history_routes::{
+            build_swaps_match_stage, swaps_intervals_pipeline, SWAPS_ROLLUP,
+        };
+        use crate::utils::build_match_stage;
+        use crate::utils::rollup::{summary_pipeline, RollupField};
+
+        // No `from`: the match itself leaves `start_time` open.
+        let query = HistoryQueryParams {
+            interval: Some("day".to_string()),
+            cursor: Some(PageCursor::Before(1_705_276_800).encode()),
+            ..Default::default()
+        };
+        let match_stage = build_match_stage(Some("BTC.BTC"), &query, "day").unwrap();
+        assert!(!match_stage.contains_key("start_time"));
+
+        let fields: Vec<&RollupField> = SWAPS_ROLLUP.iter().collect();
+        let pipelines = [
+            depth_intervals_pipeline(match_stage.clone(), &query, true).unwrap(),
+            earnings_intervals_pipeline(match_stage.clone(), &query, true).unwrap(),
+            runepool_intervals_pipeline(match_stage.clone(), &query, true).unwrap(),
+            swaps_intervals_pipeline(build_swaps_match_stage(&query).unwrap(), &query, true)
+                .unwrap(),
+            summary_pipeline(match_stage, &query, &fields, &[]).unwrap(),
+        ];
+        for pipeline in pipelines {
+            assert!(pipeline.iter().all(bounded_match), "{:?}", pipeline[0]);
+        }
+    }
+
+    #[test]
+    fn windows_cover_whole_buckets() {
+        // 2024-01-15T00:00:00Z
+        let mid_january = 1_705_276_800;
+        assert_eq!(
+            PageCursor::After(mid_january).window("month", 1).unwrap(),
+            (1_704_067_200, 1_706_745_600)
+        );
+        assert_eq!(
+            PageCursor::Before(mid_january).window("day", 2).unwrap(),
+            (mid_january - 2 * 86_400, mid_january)
+        );
+    }
+}
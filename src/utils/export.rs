@@ -0,0 +1,161 @@
+use actix_web::http::header::ACCEPT;
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use log::error;
+use mongodb::bson::{Bson, Document};
+use mongodb::Collection;
+
+use crate::error::ApiError;
+use crate::utils::rollup::RollupField;
+use crate::utils::time::render_iso_times;
+
+/// Row-oriented formats the history routes can stream instead of JSON.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+}
+
+impl ExportFormat {
+    /// Format requested by the `format` query parameter or, without one, by
+    /// the `Accept` header. `None` means the regular JSON response.
+    pub fn negotiate(req: &HttpRequest, format: Option<&str>) -> Result<Option<Self>, ApiError> {
+        match format {
+            Some("json") => return Ok(None),
+            Some("csv") => return Ok(Some(Self::Csv)),
+            Some("ndjson") => return Ok(Some(Self::Ndjson)),
+            Some(other) => {
+                return Err(ApiError::invalid(
+                    "format",
+                    format!(
+                        "Unsupported format '{}', expected json, csv or ndjson",
+                        other
+                    ),
+                ))
+            }
+            None => {}
+        }
+
+        let accept = req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if accept.contains("text/csv") {
+            Ok(Some(Self::Csv))
+        } else if accept.contains("application/x-ndjson") || accept.contains("application/ndjson") {
+            Ok(Some(Self::Ndjson))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn header(self, columns: &[&str]) -> Option<Bytes> {
+        match self {
+            Self::Csv => Some(Bytes::from(csv_line(
+                columns.iter().map(|column| column.to_string()),
+            ))),
+            Self::Ndjson => None,
+        }
+    }
+
+    fn row(self, columns: &[&str], interval: &Document) -> Bytes {
+        match self {
+            Self::Csv => Bytes::from(csv_line(
+                columns
+                    .iter()
+                    .map(|column| csv_value(interval.get(*column))),
+            )),
+            Self::Ndjson => {
+                let mut row = Document::new();
+                for column in columns {
+                    row.insert(
+                        *column,
+                        interval.get(*column).cloned().unwrap_or(Bson::Null),
+                    );
+                }
+                let mut line = serde_json::to_string(&row).unwrap_or_default();
+                line.push('\n');
+                Bytes::from(line)
+            }
+        }
+    }
+}
+
+/// Export columns for a series: the response names of the stored fields in
+/// `model_fields`, in the model's declaration order. Stored fields without a
+/// response field are left out.
+pub fn export_columns(model_fields: &[&str], rollup: &[RollupField]) -> Vec<&'static str> {
+    model_fields
+        .iter()
+        .filter_map(|field| match *field {
+            "start_time" => Some("startTime"),
+            "end_time" => Some("endTime"),
+            field => rollup
+                .iter()
+                .find(|rollup_field| rollup_field.source == field)
+                .map(|rollup_field| rollup_field.output),
+        })
+        .collect()
+}
+
+fn csv_value(value: Option<&Bson>) -> String {
+    match value {
+        None | Some(Bson::Null) => String::new(),
+        Some(Bson::String(value)) => value.clone(),
+        Some(Bson::Int32(value)) => value.to_string(),
+        Some(Bson::Int64(value)) => value.to_string(),
+        Some(Bson::Double(value)) => value.to_string(),
+        Some(value) => value.clone().into_relaxed_extjson().to_string(),
+    }
+}
+
+fn csv_line(values: impl Iterator<Item = String>) -> String {
+    let mut line = values
+        .map(|value| {
+            if value.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", value.replace('"', "\"\""))
+            } else {
+                value
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push_str("\r\n");
+    line
+}
+
+/// Runs `pipeline` on `collection` and streams the resulting intervals as
+/// `format`, one row per document as the cursor yields it. `iso_times`
+/// renders `startTime` and `endTime` as ISO-8601 strings.
+pub async fn export_intervals<T>(
+    collection: &Collection<T>,
+    pipeline: Vec<Document>,
+    format: ExportFormat,
+    columns: Vec<&'static str>,
+    iso_times: bool,
+) -> Result<HttpResponse, ApiError> {
+    let cursor = collection.aggregate(pipeline, None).await?;
+
+    let header = format.header(&columns).map(Ok);
+    let rows = cursor
+        .map_ok(move |mut interval| {
+            if iso_times {
+                render_iso_times(&mut interval);
+            }
+            format.row(&columns, &interval)
+        })
+        .inspect_err(|e| error!("Cursor error during export: {}", e));
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .streaming(stream::iter(header).chain(rows)))
+}
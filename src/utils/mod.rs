@@ -1,61 +1,83 @@
+pub mod buckets;
+pub mod cursor;
+pub mod export;
+pub mod rollup;
+pub mod time;
+
 use chrono::{TimeZone, Utc};
 
+use crate::error::{ApiError, ServiceError};
 use crate::routes::queries::HistoryQueryParams;
-use mongodb::bson::doc;
+use mongodb::bson::{doc, Document};
 
+/// ISO-8601 form of Unix seconds, e.g. `2024-06-01T00:00:00Z`.
 pub fn format_timestamp(timestamp: i64) -> String {
     let datetime = Utc.timestamp_opt(timestamp, 0).unwrap();
-    datetime.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+    datetime.format("%Y-%m-%dT%H:%M:%SZ").to_string()
 }
 
-pub fn get_seconds_per_interval(interval: &str) -> i64 {
+/// Nominal length of an interval, or `None` for an unknown interval name.
+pub fn get_seconds_per_interval(interval: &str) -> Option<i64> {
     match interval {
-        "5min" => 300,
-        "hour" => 3600,
-        "day" => 86400,
-        "week" => 604800,
-        "month" => 2592000,
-        "quarter" => 7776000,
-        "year" => 31536000,
-        _ => 3600,
+        "5min" => Some(300),
+        "hour" => Some(3600),
+        "day" => Some(86400),
+        "week" => Some(604800),
+        "month" => Some(2592000),
+        "quarter" => Some(7776000),
+        "year" => Some(31536000),
+        _ => None,
     }
 }
 
 pub fn build_match_stage(
     pool: Option<&str>,
     query: &HistoryQueryParams,
-    seconds_per_interval: i64,
-) -> Result<mongodb::bson::Document, actix_web::Error> {
+    interval: &str,
+) -> Result<mongodb::bson::Document, ApiError> {
     let mut match_stage = doc! {};
     if let Some(pool_name) = pool {
         match_stage.insert("pool", pool_name);
     }
 
-    if let Some(from) = query.from {
+    let (from, to) = query.time_range(interval);
+    if let Some(from) = from {
         match_stage.insert("start_time", doc! { "$gte": from });
-    } else {
-        let current_time = Utc::now().timestamp();
-        let count = query.count.unwrap_or(400) as i64;
-        match_stage.insert(
-            "start_time",
-            doc! {
-                "$gte": current_time - (count * seconds_per_interval)
-            },
-        );
     }
-
-    if let Some(to) = query.to {
+    if let Some(to) = to {
         match_stage.insert("end_time", doc! { "$lte": to });
     }
 
     Ok(match_stage)
 }
 
+/// Builds a `$set` stage that renders the given Decimal128 amount fields as
+/// exact decimal strings. Append it after sorting so amounts sort numerically.
+pub fn amounts_to_strings_stage(fields: &[&str]) -> Document {
+    let mut set = Document::new();
+    for field in fields {
+        set.insert(*field, doc! { "$toString": format!("${}", field) });
+    }
+    doc! { "$set": set }
+}
+
+/// Parses an amount rendered by `amounts_to_strings_stage`. A value that is
+/// not a whole number is an error rather than a zero, so a bad row cannot
+/// silently skew a total.
+pub fn parse_amount(field: &str, value: &str) -> Result<i128, ServiceError> {
+    value.parse().map_err(|_| ServiceError::InvalidAmount {
+        field: field.to_string(),
+        value: value.to_string(),
+    })
+}
+
 /// Helper function to handle pagination and sorting
 pub fn handle_pagination_and_sorting(query: &HistoryQueryParams) -> (i64, i64, i64, String, i32) {
     let page = query.page.unwrap_or(1);
     let limit = query.limit.unwrap_or(50).min(400);
-    let skip = (page - 1) * limit;
+    // Saturating, since this also runs on queries built internally rather
+    // than checked by `HistoryQueryParams::validate`.
+    let skip = page.saturating_sub(1).max(0).saturating_mul(limit);
     let sort_field = query.sort_by.as_deref().unwrap_or("startTime").to_string();
     let sort_order = match query.order.as_deref().unwrap_or("asc") {
         "desc" => -1,
@@ -69,3 +91,6 @@ pub fn handle_pagination_and_sorting(query: &HistoryQueryParams) -> (i64, i64, i
 pub const ONE_HOUR_SECS: u64 = 3_600;
 // pub const RUNEPOOL_START_TIME : i64= 1721865600;
 pub const RUNEPOOL_START_TIME: i64 = 1648771200;
+/// Start of Midgard's history: the launch of THORChain's multichain
+/// chaosnet, 2021-04-13T00:00:00Z.
+pub const MIDGARD_HISTORY_START: i64 = 1_618_272_000;
@@ -0,0 +1,288 @@
+use mongodb::bson::{doc, Bson, Document};
+
+use crate::error::ApiError;
+use crate::routes::queries::HistoryQueryParams;
+use crate::utils::amounts_to_strings_stage;
+use crate::utils::buckets::{bucket_end_expr, bucket_start_expr};
+use crate::utils::cursor::{cursor_match_stage, PageCursor};
+
+/// How a stored field is combined when several stored intervals fall into
+/// one requested interval, e.g. hourly rows queried with `interval=day`.
+/// Every rule skips stored intervals where the field is null or missing, e.g.
+/// earnings intervals predating `bondingEarnings`.
+pub enum Rollup {
+    /// The value at the start of the interval, e.g. a range's opening depth.
+    First,
+    /// Stock metrics such as depths, units and prices: the value at the end
+    /// of the interval.
+    Last,
+    /// Flow metrics such as counts, volumes, fees and earnings.
+    Sum,
+    /// Plain average over the stored intervals.
+    Avg,
+    /// Average weighted by another stored field, e.g. slip by volume.
+    WeightedAvg(&'static str),
+}
+
+/// Roll-up rule for one response field.
+pub struct RollupField {
+    /// Field name in the response.
+    pub output: &'static str,
+    /// Field name in the stored document.
+    pub source: &'static str,
+    pub rollup: Rollup,
+}
+
+impl RollupField {
+    pub const fn new(output: &'static str, source: &'static str, rollup: Rollup) -> Self {
+        Self {
+            output,
+            source,
+            rollup,
+        }
+    }
+}
+
+fn weighted_sum_field(output: &str) -> String {
+    format!("{}_weighted_sum", output)
+}
+
+fn weight_field(output: &str) -> String {
+    format!("{}_weight", output)
+}
+
+/// Response names of the `fields` stored as Decimal128 amounts, i.e. whose
+/// `source` is one of the model's `amount_fields`.
+pub fn amount_outputs<'a>(
+    fields: impl IntoIterator<Item = &'a RollupField>,
+    amount_fields: &[&str],
+) -> Vec<&'static str> {
+    fields
+        .into_iter()
+        .filter(|field| amount_fields.contains(&field.source))
+        .map(|field| field.output)
+        .collect()
+}
+
+fn has_value(source: &str) -> Document {
+    doc! { "$ne": [{ "$ifNull": [source, Bson::Null] }, Bson::Null] }
+}
+
+/// A stored interval's value paired with its `start_time`, or null when it
+/// has none. `$min` and `$max` skip nulls and compare the pairs by time
+/// first, so they pick the oldest and newest intervals with a value, where
+/// `$first` and `$last` would pick a null.
+fn timed_value(source: &str) -> Document {
+    doc! {
+        "$cond": [
+            has_value(source),
+            { "t": "$start_time", "v": source },
+            Bson::Null
+        ]
+    }
+}
+
+/// `$group` accumulators for `fields`.
+pub fn group_accumulators<'a>(fields: impl IntoIterator<Item = &'a RollupField>) -> Document {
+    let mut accumulators = Document::new();
+    for field in fields {
+        let source = format!("${}", field.source);
+        match field.rollup {
+            Rollup::First => {
+                accumulators.insert(field.output, doc! { "$min": timed_value(&source) });
+            }
+            Rollup::Last => {
+                accumulators.insert(field.output, doc! { "$max": timed_value(&source) });
+            }
+            Rollup::Sum => {
+                accumulators.insert(field.output, doc! { "$sum": source });
+            }
+            Rollup::Avg => {
+                accumulators.insert(field.output, doc! { "$avg": source });
+            }
+            Rollup::WeightedAvg(weight) => {
+                let weight = format!("${}", weight);
+                accumulators.insert(
+                    weighted_sum_field(field.output),
+                    doc! { "$sum": { "$multiply": [&source, &weight] } },
+                );
+                accumulators.insert(
+                    weight_field(field.output),
+                    doc! { "$sum": { "$cond": [has_value(&source), weight, 0] } },
+                );
+            }
+        }
+    }
+    accumulators
+}
+
+/// `$project` entries that keep the fields produced by `group_accumulators`,
+/// unwrap first and last values, which are null when no interval had one,
+/// and resolve weighted averages, which are 0 when the total weight is 0.
+pub fn project_fields<'a>(fields: impl IntoIterator<Item = &'a RollupField>) -> Document {
+    let mut projection = Document::new();
+    for field in fields {
+        match field.rollup {
+            Rollup::WeightedAvg(_) => {
+                let weighted_sum = format!("${}", weighted_sum_field(field.output));
+                let weight = format!("${}", weight_field(field.output));
+                projection.insert(
+                    field.output,
+                    doc! {
+                        "$cond": [
+                            { "$eq": [&weight, 0] },
+                            0.0,
+                            { "$toDouble": { "$divide": [weighted_sum, weight] } }
+                        ]
+                    },
+                );
+            }
+            Rollup::First | Rollup::Last => {
+                let value = format!("${}.v", field.output);
+                projection.insert(field.output, doc! { "$ifNull": [value, Bson::Null] });
+            }
+            Rollup::Sum | Rollup::Avg => {
+                projection.insert(field.output, Bson::Int32(1));
+            }
+        }
+    }
+    projection
+}
+
+/// Aggregation that rolls every stored interval matching `match_stage` up
+/// into one document with `fields`, the way the `query`'s interval buckets
+/// are rolled up. `startTime` is the first bucket's start and `endTime` the
+/// last bucket's end, so a `meta` built from it covers the requested range
+/// rather than one page. A cursor request has no range of its own and covers
+/// the cursor's page window instead, so deep pages cost the same as the
+/// first. Nothing comes out when nothing matches.
+pub fn summary_pipeline(
+    match_stage: Document,
+    query: &HistoryQueryParams,
+    fields: &[&RollupField],
+    amount_fields: &[&str],
+) -> Result<Vec<Document>, ApiError> {
+    let interval = query.interval()?;
+    let cursor = PageCursor::from_query(query)?;
+    let mut group = doc! {
+        "_id": Bson::Null,
+        "startTime": { "$first": bucket_start_expr(interval) },
+        "lastStart": { "$last": bucket_start_expr(interval) }
+    };
+    group.extend(group_accumulators(fields.iter().copied()));
+
+    let mut projection = doc! {
+        "_id": 0,
+        "startTime": 1,
+        "endTime": bucket_end_expr(interval, "$lastStart")
+    };
+    projection.extend(project_fields(fields.iter().copied()));
+
+    Ok(vec![
+        doc! { "$match": cursor_match_stage(match_stage, query, cursor)? },
+        doc! { "$sort": { "start_time": 1 } },
+        doc! { "$group": group },
+        doc! { "$project": projection },
+        amounts_to_strings_stage(&amount_outputs(fields.iter().copied(), amount_fields)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNITS_META: [RollupField; 2] = [
+        RollupField::new("startUnits", "units", Rollup::First),
+        RollupField::new("endUnits", "units", Rollup::Last),
+    ];
+
+    fn day_query(cursor: Option<PageCursor>) -> HistoryQueryParams {
+        HistoryQueryParams {
+            interval: Some("day".to_string()),
+            limit: Some(2),
+            cursor: cursor.map(|cursor| cursor.encode()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn summary_pipeline_rolls_up_every_matched_interval() {
+        let fields: Vec<&RollupField> = UNITS_META.iter().collect();
+        let pipeline = summary_pipeline(
+            doc! { "pool": "BTC.BTC" },
+            &day_query(None),
+            &fields,
+            &["units"],
+        )
+        .unwrap();
+
+        assert_eq!(pipeline[0], doc! { "$match": { "pool": "BTC.BTC" } });
+        let group = pipeline[2].get_document("$group").unwrap();
+        assert_eq!(group.get("_id"), Some(&Bson::Null));
+        assert_eq!(
+            group.get_document("startUnits").unwrap(),
+            &doc! { "$min": timed_value("$units") }
+        );
+        assert_eq!(
+            group.get_document("endUnits").unwrap(),
+            &doc! { "$max": timed_value("$units") }
+        );
+        let projection = pipeline[3].get_document("$project").unwrap();
+        assert_eq!(
+            projection.get_document("endUnits").unwrap(),
+            &doc! { "$ifNull": ["$endUnits.v", Bson::Null] }
+        );
+        let set = pipeline[4].get_document("$set").unwrap();
+        assert!(set.contains_key("startUnits") && set.contains_key("endUnits"));
+    }
+
+    #[test]
+    fn summary_pipeline_keeps_to_the_cursor_window() {
+        // 2024-01-15T00:00:00Z
+        let mid_january: i64 = 1_705_276_800;
+        let fields: Vec<&RollupField> = UNITS_META.iter().collect();
+        let pipeline = summary_pipeline(
+            doc! { "pool": "BTC.BTC" },
+            &day_query(Some(PageCursor::After(mid_january))),
+            &fields,
+            &["units"],
+        )
+        .unwrap();
+
+        assert_eq!(
+            pipeline[0],
+            doc! {
+                "$match": {
+                    "$and": [
+                        { "pool": "BTC.BTC" },
+                        { "start_time": { "$gte": mid_january, "$lt": mid_january + 2 * 86_400 } }
+                    ]
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn rollups_skip_intervals_without_a_value() {
+        assert_eq!(
+            timed_value("$rune_price_usd"),
+            doc! {
+                "$cond": [
+                    { "$ne": [{ "$ifNull": ["$rune_price_usd", Bson::Null] }, Bson::Null] },
+                    { "t": "$start_time", "v": "$rune_price_usd" },
+                    Bson::Null
+                ]
+            }
+        );
+        let slip = [RollupField::new(
+            "averageSlip",
+            "average_slip",
+            Rollup::WeightedAvg("volume"),
+        )];
+        let group = group_accumulators(&slip);
+        assert_eq!(
+            group.get_document("averageSlip_weight").unwrap(),
+            &doc! { "$sum": { "$cond": [has_value("$average_slip"), "$volume", 0] } }
+        );
+    }
+}
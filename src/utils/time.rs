@@ -0,0 +1,138 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use mongodb::bson::Document;
+use serde::{de, Deserialize, Deserializer};
+
+use crate::utils::format_timestamp;
+
+/// Parses a time given as Unix seconds, an ISO-8601 timestamp, a date such
+/// as `2024-06-01` (midnight UTC), `now`, or an offset from now such as
+/// `-7d` or `now-12h`. Offsets take `s`, `m`, `h`, `d` or `w` units.
+/// An unencoded `+` in a query string decodes to a space, so spaces are read
+/// as `+`: `now 1h` is `now+1h` and `...T06:30:00 02:00` has a `+02:00`
+/// offset.
+pub fn parse_time(input: &str, now: i64) -> Option<i64> {
+    let input = input.trim_end().replace(' ', "+");
+    let input = input.as_str();
+    if let Ok(timestamp) = input.parse::<i64>() {
+        return Some(timestamp);
+    }
+    if input == "now" {
+        return Some(now);
+    }
+    if let Some(offset) = parse_offset(input.strip_prefix("now").unwrap_or(input)) {
+        return now.checked_add(offset);
+    }
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(input) {
+        return Some(datetime.timestamp());
+    }
+    if let Ok(datetime) = NaiveDateTime::parse_from_str(input, "%Y-%m-%dT%H:%M:%S") {
+        return Some(datetime.and_utc().timestamp());
+    }
+    NaiveDate::parse_from_str(input, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|datetime| datetime.and_utc().timestamp())
+}
+
+/// Seconds of an offset such as `-7d` or `+1h`; the sign is required.
+fn parse_offset(offset: &str) -> Option<i64> {
+    let (sign, rest) = match offset.chars().next()? {
+        '-' => (-1, &offset[1..]),
+        '+' => (1, &offset[1..]),
+        _ => return None,
+    };
+    let unit = rest.chars().last()?;
+    let digits = &rest[..rest.len() - unit.len_utf8()];
+    if digits.is_empty() || !digits.bytes().all(|digit| digit.is_ascii_digit()) {
+        return None;
+    }
+    let amount = digits.parse::<i64>().ok()?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3_600,
+        'd' => 86_400,
+        'w' => 604_800,
+        _ => return None,
+    };
+    Some(sign * amount.checked_mul(seconds)?)
+}
+
+/// Deserializes an optional time parameter accepted by `parse_time` into
+/// Unix seconds.
+pub fn deserialize_time<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(input) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    parse_time(&input, Utc::now().timestamp())
+        .map(Some)
+        .ok_or_else(|| {
+            de::Error::custom(format!(
+                "invalid time '{}', expected Unix seconds, an ISO-8601 timestamp, a date, now or an offset such as -7d",
+                input
+            ))
+        })
+}
+
+/// Replaces the Unix `startTime` and `endTime` of `doc` with ISO-8601 strings.
+pub fn render_iso_times(doc: &mut Document) {
+    for field in ["startTime", "endTime"] {
+        if let Ok(timestamp) = doc.get_i64(field) {
+            doc.insert(field, format_timestamp(timestamp));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_718_443_800;
+
+    #[test]
+    fn parses_absolute_times() {
+        assert_eq!(parse_time("1704067200", NOW), Some(1_704_067_200));
+        assert_eq!(parse_time("2024-01-01", NOW), Some(1_704_067_200));
+        assert_eq!(parse_time("2024-01-01T06:30:00", NOW), Some(1_704_090_600));
+        assert_eq!(parse_time("2024-01-01T06:30:00Z", NOW), Some(1_704_090_600));
+        assert_eq!(
+            parse_time("2024-01-01T06:30:00+02:00", NOW),
+            Some(1_704_083_400)
+        );
+    }
+
+    #[test]
+    fn parses_offsets_from_now() {
+        assert_eq!(parse_time("now", NOW), Some(NOW));
+        assert_eq!(parse_time("-7d", NOW), Some(NOW - 7 * 86_400));
+        assert_eq!(parse_time("now-12h", NOW), Some(NOW - 12 * 3_600));
+        assert_eq!(parse_time("+30m", NOW), Some(NOW + 1_800));
+        assert_eq!(parse_time("-2w", NOW), Some(NOW - 2 * 604_800));
+    }
+
+    #[test]
+    fn reads_spaces_as_plus() {
+        assert_eq!(parse_time("now 1h", NOW), Some(NOW + 3_600));
+        assert_eq!(parse_time(" 1d", NOW), Some(NOW + 86_400));
+        assert_eq!(
+            parse_time("2024-01-01T06:30:00 02:00", NOW),
+            Some(1_704_083_400)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_and_overflowing_times() {
+        assert_eq!(parse_time("", NOW), None);
+        assert_eq!(parse_time("yesterday", NOW), None);
+        assert_eq!(parse_time("-d", NOW), None);
+        assert_eq!(parse_time("7d", NOW), None);
+        assert_eq!(parse_time("-7y", NOW), None);
+        assert_eq!(parse_time("-+7d", NOW), None);
+        assert_eq!(parse_time("-7é", NOW), None);
+        assert_eq!(parse_time("+9223372036854775807s", NOW), None);
+        assert_eq!(parse_time("-9999999999999999w", NOW), None);
+    }
+}